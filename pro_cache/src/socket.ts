@@ -18,6 +18,12 @@ export type WSMessage =
     | { type: 'ws-cache-enabled', enabled: boolean, explicitlyClosed?: boolean }
     | { type: 'ws-debug-enabled', enabled: boolean };
 
+// A route's entry in invalidate/invalidate-delta data: a timestamp, or { timestamp, etag } when the server has a version
+export type RouteVersion = number | { timestamp: number, etag: string };
+
+const versionTimestamp = (version: RouteVersion): number =>
+    typeof version === 'number' ? version : version.timestamp;
+
 export interface WebSocketContext {
     db: IndexedDBCache;
     cache: CacheManager;
@@ -871,15 +877,24 @@ export class WebSocketClient {
     }
 
     private async defaultMessageHandler(msg: any) {
-        
+        // Compact connections (?compact=1) send numeric paths as [[id, timestamp], ...]
+        if (Array.isArray(msg.numeric)) {
+            msg.data = { ...(msg.data ?? {}) };
+            for (const [id, timestamp] of msg.numeric) {
+                msg.data[String(id)] = timestamp;
+            }
+        }
+
         // 1. Handle Full Sync: { type: 'invalidate', data: { [key]: timestamp, ... } }
         if (msg.type === 'invalidate' && typeof msg.data === 'object' && !Array.isArray(msg.data)) {
             this.log('[WS Leader] Received Full Sync "invalidate" message.');
-            const data = msg.data as Record<string, number>;
+            const data = msg.data as Record<string, RouteVersion>;
             const serverKeys = new Set(Object.keys(data));
+            // Partial syncs (connected with ?since_ts=) only list what changed; the rest is still valid
+            const partial = msg.partial === true;
 
             // Case A: Empty Data -> Clear All
-            if (serverKeys.size === 0) {
+            if (serverKeys.size === 0 && !partial) {
                  this.log('[WS Leader] Full Sync: Server state is empty. Clearing all local cache.');
                  this.cacheManager.clear();
                  await this.db.clearAll();
@@ -895,7 +910,8 @@ export class WebSocketClient {
 
             // Case B: Non-Empty Data -> Sync
             // 1. Update items from Server (if newer)
-            for (const [keyOrBucket, timestamp] of Object.entries(data)) {
+            for (const [keyOrBucket, version] of Object.entries(data)) {
+                const timestamp = versionTimestamp(version);
                 let shouldUpdate = true;
                 if (this.config.shouldInvalidate) {
                      shouldUpdate = await this.config.shouldInvalidate(keyOrBucket, timestamp, this.db);
@@ -913,7 +929,7 @@ export class WebSocketClient {
             }
 
             // 2. Delete items NOT in Server list (Sync)
-            const allLocalBuckets = await this.db.getAllBucketKeys();
+            const allLocalBuckets = partial ? null : await this.db.getAllBucketKeys();
             if (allLocalBuckets) {
                 for (const localBucket of allLocalBuckets) {
                     if (!serverKeys.has(localBucket)) {
@@ -931,13 +947,27 @@ export class WebSocketClient {
             return;
         }
 
-        // 2. Handle Delta Update: { type: 'invalidate-delta', data: { [key]: timestamp } }
+        // 2. Handle Project-wide Flush: { type: 'invalidate-all', data: { timestamp } },
+        // or a reset of the project to its baseline: { type: 'flush' }
+        if (msg.type === 'invalidate-all' || msg.type === 'flush') {
+             this.log(`[WS Leader] Received "${msg.type}". Clearing all local cache.`);
+             this.cacheManager.clear();
+             await this.db.clearAll();
+             this.channel?.postMessage({ type: 'ws-invalidate-all', timestamp: msg.data?.timestamp ?? Date.now() } as WSMessage);
+             this.globalInvalidationCallbacks.forEach(cb => cb());
+             this.echoLatency(msg);
+             return;
+        }
+
+        // 3. Handle Delta Update: { type: 'invalidate-delta', data: { [key]: timestamp } }
         if (msg.type === 'invalidate-delta' && typeof msg.data === 'object') {
-             const data = msg.data as Record<string, number>;
-             for (const [keyOrBucket, timestamp] of Object.entries(data)) {
+             const data = msg.data as Record<string, RouteVersion>;
+             for (const [keyOrBucket, version] of Object.entries(data)) {
+                 const timestamp = versionTimestamp(version);
                  this.log(`[WS Leader] Delta update for: ${keyOrBucket} at ${timestamp}`);
                  await this.invalidateAndNotify(keyOrBucket, timestamp);
              }
+             this.echoLatency(msg);
              return;
         }
         
@@ -954,6 +984,13 @@ export class WebSocketClient {
         }
     }
 
+    // Measured broadcasts carry `sent_at`; echoing it lets the server record delivery latency
+    private echoLatency(msg: any) {
+        if (typeof msg.sent_at === 'number') {
+            this.send({ type: 'latency', sent_at: msg.sent_at });
+        }
+    }
+
     // Helper to invalidate a bucket/key and notify all subscribers
     private async invalidateAndNotify(keyOrBucket: string, timestamp: number) {
          // 1. Invalidate Cache
@@ -0,0 +1,190 @@
+// Fan-out benchmark: invalidate HTTP latency with many connected sessions, queued vs wait_for_delivery.
+// Uses only Node built-ins (no `ws` dependency) so thousands of idle sockets stay cheap.
+// The sessions live in a forked child so reading their frames doesn't delay the timed HTTP calls.
+//
+//   node bench_fanout.js                      # SESSIONS=2000 ROUNDS=200
+//   SESSIONS=5000 ROUNDS=100 node bench_fanout.js
+//   PAUSE_MS=0 node bench_fanout.js           # back-to-back requests (measures throughput, not latency)
+//
+// Against a server without the broadcaster queue, `wait_for_delivery` is ignored and both
+// modes measure the synchronous fan-out.
+const http = require('http');
+const { fork } = require('child_process');
+const crypto = require('crypto');
+
+const INTERNAL_API = process.env.INTERNAL_API || "http://127.0.0.1:8081/internal";
+const WS_HOST = process.env.WS_HOST || "127.0.0.1";
+const WS_PORT = Number(process.env.WS_PORT || 8080);
+const INTERNAL_KEY = process.env.INTERNAL_API_KEY;
+
+const SESSIONS = Number(process.env.SESSIONS || 2000);
+const ROUNDS = Number(process.env.ROUNDS || 200);
+const PROJECT_ID = process.env.PROJECT_ID || "project-bench";
+// Gap after each invalidate, so the previous fan-out isn't still running when the next request is timed
+const PAUSE_MS = Number(process.env.PAUSE_MS ?? 50);
+
+async function internal(method, path, body) {
+    const headers = { 'Content-Type': 'application/json' };
+    if (INTERNAL_KEY) headers['X-Internal-Key'] = INTERNAL_KEY;
+    const response = await fetch(`${INTERNAL_API}${path}`, { method, headers, body: JSON.stringify(body) });
+    if (!response.ok) throw new Error(`${path}: ${response.status} ${await response.text()}`);
+    return response.json();
+}
+
+async function registerTokens() {
+    const tokens = [];
+    for (let start = 0; start < SESSIONS; start += 500) {
+        const batch = [];
+        for (let i = start; i < Math.min(start + 500, SESSIONS); i++) {
+            const token = `bench-${process.pid}-${i}`;
+            batch.push({ token, user_id: `bench-user-${i}`, project_id: PROJECT_ID, ttl: 3600 });
+            tokens.push(token);
+        }
+        await internal('POST', '/auth/register_batch', batch);
+    }
+    return tokens;
+}
+
+// Minimal client: completes the upgrade, answers pings and counts text frames mentioning /bench/
+function connect(token, counters) {
+    return new Promise((resolve, reject) => {
+        const req = http.request({
+            host: WS_HOST,
+            port: WS_PORT,
+            path: `/ws?token=${encodeURIComponent(token)}`,
+            headers: {
+                'Connection': 'Upgrade',
+                'Upgrade': 'websocket',
+                'Sec-WebSocket-Version': '13',
+                'Sec-WebSocket-Key': crypto.randomBytes(16).toString('base64'),
+            },
+        });
+        req.on('upgrade', (_res, socket, head) => {
+            let buffer = head;
+            socket.on('data', (chunk) => {
+                buffer = Buffer.concat([buffer, chunk]);
+                buffer = readFrames(buffer, socket, counters);
+            });
+            socket.on('error', () => {});
+            resolve(socket);
+        });
+        req.on('response', (res) => reject(new Error(`upgrade refused: ${res.statusCode}`)));
+        req.on('error', reject);
+        req.end();
+    });
+}
+
+function readFrames(buffer, socket, counters) {
+    while (buffer.length >= 2) {
+        const opcode = buffer[0] & 0x0f;
+        let length = buffer[1] & 0x7f;
+        let offset = 2;
+        if (length === 126) {
+            if (buffer.length < 4) break;
+            length = buffer.readUInt16BE(2);
+            offset = 4;
+        } else if (length === 127) {
+            if (buffer.length < 10) break;
+            length = Number(buffer.readBigUInt64BE(2));
+            offset = 10;
+        }
+        if (buffer.length < offset + length) break;
+
+        const payload = buffer.subarray(offset, offset + length);
+        if (opcode === 0x1 && payload.includes('/bench/')) {
+            counters.received++;
+            counters.lastAt = process.hrtime.bigint();
+        } else if (opcode === 0x9) {
+            socket.write(maskedFrame(0xa, payload));
+        }
+        buffer = buffer.subarray(offset + length);
+    }
+    return buffer;
+}
+
+function maskedFrame(opcode, payload) {
+    // Control frames only: payload < 126 bytes
+    const mask = crypto.randomBytes(4);
+    const masked = Buffer.from(payload.map((b, i) => b ^ mask[i % 4]));
+    return Buffer.concat([Buffer.from([0x80 | opcode, 0x80 | payload.length]), mask, masked]);
+}
+
+function percentile(sorted, p) {
+    return sorted[Math.min(sorted.length - 1, Math.floor(sorted.length * p))];
+}
+
+// Child: holds the sessions and reports how many /bench/ frames they've received
+async function holdSessions() {
+    const tokens = await registerTokens();
+    const counters = { received: 0, lastAt: 0n };
+    const sockets = [];
+    for (let start = 0; start < tokens.length; start += 200) {
+        sockets.push(...await Promise.all(tokens.slice(start, start + 200).map(t => connect(t, counters))));
+    }
+    process.on('message', () => process.send({ received: counters.received, lastAt: counters.lastAt.toString() }));
+    process.send({ ready: sockets.length });
+}
+
+function progress(child) {
+    return new Promise(resolve => {
+        child.once('message', (m) => resolve({ received: m.received, lastAt: BigInt(m.lastAt) }));
+        child.send('progress');
+    });
+}
+
+async function runMode(label, waitForDelivery, child) {
+    const latencies = [];
+    const expected = (await progress(child)).received + SESSIONS * ROUNDS;
+    const started = process.hrtime.bigint();
+
+    for (let i = 0; i < ROUNDS; i++) {
+        const t0 = process.hrtime.bigint();
+        await internal('POST', '/invalidate', {
+            project_id: PROJECT_ID,
+            path: `/bench/${label}/${i}`,
+            wait_for_delivery: waitForDelivery,
+        });
+        latencies.push(Number(process.hrtime.bigint() - t0) / 1e6);
+        if (PAUSE_MS > 0) await new Promise(r => setTimeout(r, PAUSE_MS));
+    }
+
+    // Wait for the last deliveries so the next mode starts from an idle server
+    const deadline = Date.now() + 60000;
+    let counts = await progress(child);
+    while (counts.received < expected && Date.now() < deadline) {
+        await new Promise(r => setTimeout(r, 20));
+        counts = await progress(child);
+    }
+
+    latencies.sort((a, b) => a - b);
+    const mean = latencies.reduce((a, b) => a + b, 0) / latencies.length;
+    // hrtime is per-machine monotonic, so the child's timestamps compare with ours
+    const delivered = Number(counts.lastAt - started) / 1e6;
+    const frames = counts.received - (expected - SESSIONS * ROUNDS);
+    console.log(
+        `${label.padEnd(18)} p50 ${percentile(latencies, 0.5).toFixed(2)}ms  p95 ${percentile(latencies, 0.95).toFixed(2)}ms  ` +
+        `p99 ${percentile(latencies, 0.99).toFixed(2)}ms  mean ${mean.toFixed(2)}ms  ` +
+        `all delivered after ${delivered.toFixed(0)}ms ` +
+        `(${frames}/${SESSIONS * ROUNDS} frames)`
+    );
+}
+
+async function run() {
+    const child = fork(__filename, ['--hold-sessions']);
+    const ready = await new Promise(resolve => child.once('message', (m) => resolve(m.ready)));
+    console.log(`${ready} sessions connected to ${PROJECT_ID}, ${ROUNDS} invalidations per mode`);
+    // Let the initial syncs drain
+    await new Promise(r => setTimeout(r, 1000));
+
+    await runMode('queued', false, child);
+    await runMode('wait_for_delivery', true, child);
+
+    child.kill();
+    process.exit(0);
+}
+
+const main = process.argv.includes('--hold-sessions') ? holdSessions : run;
+main().catch((e) => {
+    console.log(`[Bench] Error: ${e}`);
+    process.exit(1);
+});
@@ -0,0 +1,37 @@
+// Injects the git SHA and build time consumed by GET /internal/version
+use std::process::Command;
+
+fn main() {
+    let git_sha = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|out| out.status.success())
+        .and_then(|out| String::from_utf8(out.stdout).ok())
+        .map(|sha| sha.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    let built_at = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    println!("cargo:rustc-env=GIT_SHA={}", git_sha);
+    println!("cargo:rustc-env=BUILD_UNIX_TIME={}", built_at);
+
+    // Rerun when the sources change (so built_at stays current) or HEAD moves (checkout or commit)
+    println!("cargo:rerun-if-changed=src");
+    let git_dir = Command::new("git")
+        .args(["rev-parse", "--git-dir"])
+        .output()
+        .ok()
+        .and_then(|out| String::from_utf8(out.stdout).ok());
+    if let Some(git_dir) = git_dir {
+        let git_dir = git_dir.trim();
+        println!("cargo:rerun-if-changed={}/HEAD", git_dir);
+        if let Ok(head) = std::fs::read_to_string(format!("{}/HEAD", git_dir)) {
+            if let Some(reference) = head.trim().strip_prefix("ref: ") {
+                println!("cargo:rerun-if-changed={}/{}", git_dir, reference);
+            }
+        }
+    }
+}
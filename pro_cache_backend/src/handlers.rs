@@ -1,176 +1,2097 @@
-use actix_web::{web, HttpResponse, Responder};
-use crate::state::{AppState, RegisterTokenRequest, InvalidateRequest, TokenData};
+use actix_web::{http::StatusCode, web, HttpResponse, Responder};
+use crate::protocol::{Empty, InvalidateAllData, OutgoingMessage};
+use crate::relay::RelayedDelivery;
+use crate::state::{canonical_path, validate_timestamp, AppState, DependencyRequest, DisconnectRequest, EventsQuery, FanoutJob, FlushRequest, HotPathsQuery, Priority, LATENCY_BUCKETS_MS, RegisterTokenRequest, InvalidateRequest, RemoveRoutesRequest, RevokeProjectRequest, RouteQuery, TagPathsRequest, TokenData, TokenQuery, UserTokenQuery};
+use std::collections::HashMap;
 use std::time::Instant;
 
+/// Error body shared by the handlers: `{ "status": "error", "code", "message" }`.
+pub fn error_response(status: StatusCode, code: &str, message: impl Into<String>) -> HttpResponse {
+    HttpResponse::build(status).json(error_body(code, message))
+}
+
+fn error_body(code: &str, message: impl Into<String>) -> serde_json::Value {
+    serde_json::json!({
+        "status": "error",
+        "code": code,
+        "message": message.into()
+    })
+}
+
+/// Compares an `X-Internal-Key` against INTERNAL_API_KEY in constant time, so response timing
+/// doesn't reveal how much of a guessed key was right.
+pub fn internal_key_matches(expected: &str, provided: &str) -> bool {
+    use subtle::ConstantTimeEq;
+    expected.as_bytes().ct_eq(provided.as_bytes()).into()
+}
+
+// Upper bound for token, user_id and project_id lengths
+const MAX_ID_LEN: usize = 256;
+
+fn validate_register(req: &RegisterTokenRequest) -> Result<(), String> {
+    for (field, value) in [("token", &req.token), ("user_id", &req.user_id), ("project_id", &req.project_id)] {
+        if value.is_empty() {
+            return Err(format!("{} must not be empty", field));
+        }
+        if value.len() > MAX_ID_LEN {
+            return Err(format!("{} must be at most {} characters", field, MAX_ID_LEN));
+        }
+    }
+    Ok(())
+}
+
+// "<number><unit>" with unit s, m, h or d (e.g. "90s", "30m", "24h", "7d") -> seconds
+fn parse_human_ttl(value: &str) -> Result<u64, String> {
+    let invalid = || format!("ttl_human '{}' is not a duration like 30m, 24h or 7d", value);
+    let value = value.trim();
+    let (unit_at, unit) = value.char_indices().last().ok_or_else(invalid)?;
+    let multiplier = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+    let amount = &value[..unit_at];
+    amount.parse::<u64>().ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(invalid)
+}
+
 pub async fn register_token(
     data: web::Data<AppState>,
     req: web::Json<RegisterTokenRequest>,
 ) -> impl Responder {
+    match register_one(&data, &req) {
+        Ok(previous_token) => HttpResponse::Ok().json(serde_json::json!({
+            "status": "success",
+            "message": "Token registered",
+            "replaced": previous_token.is_some(),
+            "previous_token": previous_token
+        })),
+        Err(message) => error_response(StatusCode::BAD_REQUEST, "invalid_request", message),
+    }
+}
+
+// Unknown and expired tokens are reported as `valid: false` rather than an error
+pub async fn verify_token(
+    data: web::Data<AppState>,
+    query: web::Query<TokenQuery>,
+) -> impl Responder {
+    match data.tokens.get(&query.token).filter(|t| !t.is_expired()) {
+        Some(token_data) => HttpResponse::Ok().json(serde_json::json!({
+            "valid": true,
+            "user_id": token_data.user_id,
+            "project_id": token_data.project_id,
+            "expires_in": token_data.expires_in()
+        })),
+        None => HttpResponse::Ok().json(serde_json::json!({ "valid": false })),
+    }
+}
+
+// Support tool: the user's live token. It's a credential, so with INTERNAL_API_KEY set the key
+// is required even from loopback (which the internal scope otherwise trusts); every peek is logged.
+pub async fn peek_token(
+    req: actix_web::HttpRequest,
+    data: web::Data<AppState>,
+    query: web::Query<UserTokenQuery>,
+) -> impl Responder {
+    if let Some(expected) = &data.internal_api_key {
+        let provided = req.headers().get("X-Internal-Key").and_then(|v| v.to_str().ok());
+        if !provided.is_some_and(|provided| internal_key_matches(expected, provided)) {
+            return error_response(StatusCode::FORBIDDEN, "key_required", "X-Internal-Key is required to read tokens");
+        }
+    }
+
+    let Some((token, token_data)) = data.tokens.active_token_of(&query.project_id, &query.user_id) else {
+        return error_response(StatusCode::NOT_FOUND, "token_not_found", "No active token for this user");
+    };
+    log::warn!("[Auth] Token of user {} in project {} read via /auth/token by {:?}", query.user_id, query.project_id, req.peer_addr());
+
+    HttpResponse::Ok().json(serde_json::json!({
+        "token": token,
+        "ttl": token_data.ttl,
+        "expires_in": token_data.expires_in()
+    }))
+}
+
+pub async fn revoke_project(
+    data: web::Data<AppState>,
+    req: web::Json<RevokeProjectRequest>,
+) -> impl Responder {
+    let (revoked, disconnected) = data.revoke_project_tokens(&req.project_id);
+    log::warn!("[Auth] Revoked {} tokens of project {} ({} sessions disconnected)", revoked, req.project_id, disconnected);
+
+    HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "revoked": revoked,
+        "disconnected": disconnected
+    }))
+}
+
+// Registers every entry independently; an invalid entry is reported without aborting the rest
+pub async fn register_batch(
+    data: web::Data<AppState>,
+    req: web::Json<Vec<RegisterTokenRequest>>,
+) -> impl Responder {
+    let results: Vec<_> = req.iter().map(|entry| match register_one(&data, entry) {
+        Ok(previous_token) => serde_json::json!({
+            "status": "success",
+            "user_id": entry.user_id,
+            "project_id": entry.project_id,
+            "replaced": previous_token.is_some(),
+            "previous_token": previous_token
+        }),
+        Err(message) => {
+            let mut result = error_body("invalid_request", message);
+            result["user_id"] = serde_json::json!(entry.user_id);
+            result["project_id"] = serde_json::json!(entry.project_id);
+            result
+        }
+    }).collect();
+
+    let registered = results.iter().filter(|r| r["status"] == "success").count();
+    HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "registered": registered,
+        "failed": results.len() - registered,
+        "results": results
+    }))
+}
+
+// Validates and stores one token, retiring the user's previous token for the project.
+// Returns the replaced token, if any.
+fn register_one(data: &AppState, req: &RegisterTokenRequest) -> Result<Option<String>, String> {
+    validate_register(req)?;
+    if let Some(timestamp) = req.timestamp {
+        validate_timestamp(timestamp, data.max_client_skew)?;
+    }
+
+    // Numeric ttl wins over ttl_human
+    let requested_ttl = match (req.ttl, &req.ttl_human) {
+        (Some(ttl), _) => Some(ttl),
+        (None, Some(human)) => Some(parse_human_ttl(human)?),
+        (None, None) => None,
+    };
+
+    // Checked last so a request rejected for another reason doesn't burn its nonce
+    if let Some(nonce) = &req.nonce {
+        data.tokens.use_register_nonce(nonce, req.timestamp)?;
+    }
+
     let token_data = TokenData {
         user_id: req.user_id.clone(),
         project_id: req.project_id.clone(),
         created_at: Instant::now(),
-        ttl: req.ttl.unwrap_or(86400), // Default 24 hours
+        ttl: data.resolve_ttl(&req.project_id, requested_ttl),
+        one_time: req.one_time == Some(true),
     };
 
-    // 1. Check if user already has a token for this project
-    let user_key = (req.project_id.clone(), req.user_id.clone());
-    if let Some(old_token) = data.user_tokens.get(&user_key) {
-        // Remove the old token from pending_tokens (valid_tokens)
-        data.pending_tokens.remove(old_token.value());
+    // Registering retires the user's previous (different) token for this project into its
+    // grace period; sessions still using it are kicked
+    let previous_token = data.tokens.register(&req.token, token_data);
+    if let Some(old_token) = &previous_token {
+        let revoked = data.sessions.revoke_token(&req.project_id, old_token);
+        if revoked > 0 {
+            log::info!("[Auth] Revoked {} sessions of user {} in project {}", revoked, req.user_id, req.project_id);
+        }
     }
 
-    // 2. Register the new token
-    data.pending_tokens.insert(req.token.clone(), token_data);
-    data.user_tokens.insert(user_key, req.token.clone());
-
-    HttpResponse::Ok().json(serde_json::json!({
-        "status": "success",
-        "message": "Token registered"
-    }))
+    Ok(previous_token)
 }
 
-fn normalize_path(v: serde_json::Value) -> String {
-    match v {
+fn normalize_path(data: &AppState, v: serde_json::Value) -> String {
+    let path = match v {
         serde_json::Value::String(s) => s,
         serde_json::Value::Number(n) => n.to_string(),
         _ => v.to_string(),
+    };
+    canonical_path(&path, data.lowercase_paths)
+}
+
+// A path ending in "/*" is a prefix wildcard: it expands to every known route of the project
+// under that prefix. A "*" anywhere else is treated literally.
+fn expand_path(data: &AppState, project_id: &str, path: String) -> Vec<String> {
+    match path.strip_suffix('*').filter(|prefix| prefix.ends_with('/')) {
+        Some(prefix) => data.routes.project_routes(project_id)
+            .into_iter()
+            .filter(|r| r.starts_with(prefix))
+            .collect(),
+        None => vec![path],
+    }
+}
+
+// Concrete paths to invalidate in one project: the requested paths plus the project's tag paths,
+// with wildcards expanded (deduplicated, order preserved)
+fn resolve_target_paths(
+    data: &AppState,
+    project_id: &str,
+    requested_paths: &[String],
+    tags: Option<&[String]>,
+) -> Vec<String> {
+    let mut paths: Vec<String> = requested_paths.to_vec();
+
+    // Resolve tags to their current path set (unknown tags contribute nothing)
+    if let Some(tags) = tags {
+        paths.extend(data.routes.tagged_paths(project_id, tags));
+    }
+
+    let mut target_paths: Vec<String> = Vec::new();
+    for path in paths {
+        for p in expand_path(data, project_id, path) {
+            if !target_paths.contains(&p) {
+                target_paths.push(p);
+            }
+        }
+    }
+    add_dependents(data, project_id, target_paths)
+}
+
+// Extends `paths` with the transitive closure of their declared dependents.
+// Each path is visited once, so dependency cycles terminate.
+fn add_dependents(data: &AppState, project_id: &str, paths: Vec<String>) -> Vec<String> {
+    if !data.routes.has_dependencies() {
+        return paths;
+    }
+
+    let mut visited: std::collections::HashSet<String> = paths.iter().cloned().collect();
+    let mut queue: std::collections::VecDeque<String> = paths.iter().cloned().collect();
+    let mut target_paths = paths;
+
+    while let Some(path) = queue.pop_front() {
+        for dependent in data.routes.dependents_of(&path).into_iter().flat_map(|d| expand_path(data, project_id, d)) {
+            if visited.insert(dependent.clone()) {
+                target_paths.push(dependent.clone());
+                queue.push_back(dependent);
+            }
+        }
+    }
+    target_paths
+}
+
+// Per-request values shared by every project's delta
+struct DeltaContext<'a> {
+    timestamp: i64,
+    current_drift: i64,
+    target_user: Option<&'a String>,
+    exclude_users: &'a [String],
+    ack_id: Option<&'a str>,
+    sent_at: Option<u64>,
+    wait_for_delivery: bool,
+    high_priority: bool, // Sent here on the sessions' priority lanes (implies wait_for_delivery)
+}
+
+// Outcome of broadcasting one project's delta (empty when the fan-out was queued)
+#[derive(Default)]
+struct Delivery {
+    broadcast_count: u64,
+    matched_users: std::collections::HashSet<String>,
+    sessions: Vec<uuid::Uuid>,
+}
+
+// Stores the new timestamps for one project and returns them as a delta map.
+fn store_timestamps(
+    data: &AppState,
+    project_id: &str,
+    target_paths: &[String],
+    timestamp: i64,
+) -> serde_json::Map<String, serde_json::Value> {
+    // Update Invalidation State and Prepare Delta Message (DashMap is thread-safe)
+    data.invalidations.set_timestamps(project_id, target_paths.iter().map(|path| (path.clone(), timestamp)));
+    target_paths
+        .iter()
+        .map(|path| (path.clone(), data.route_value(project_id, path, timestamp)))
+        .collect()
+}
+
+// Stores the new timestamps for one project and broadcasts the delta.
+fn apply_delta(
+    data: &web::Data<AppState>,
+    project_id: &str,
+    target_paths: &[String],
+    seq: u64,
+    ctx: &DeltaContext,
+) -> Result<Delivery, serde_json::Error> {
+    let delta_data = store_timestamps(data, project_id, target_paths, ctx.timestamp);
+    broadcast_delta(data, project_id, delta_data, seq, ctx)
+}
+
+// Sends an `invalidate-delta` to the project's targeted sessions and records it for replay.
+fn broadcast_delta(
+    data: &web::Data<AppState>,
+    project_id: &str,
+    delta_data: serde_json::Map<String, serde_json::Value>,
+    seq: u64,
+    ctx: &DeltaContext,
+) -> Result<Delivery, serde_json::Error> {
+    let target_paths: Vec<String> = delta_data.keys().cloned().collect();
+    let message = OutgoingMessage::InvalidateDelta { data: delta_data, drift_time: ctx.current_drift, seq };
+    deliver(data, project_id, message, seq, ctx, Some(&target_paths))
+}
+
+// Records `message` for replay and sends it to the project's targeted sessions.
+// With `paths`, sessions whose subscription excludes all of them are skipped.
+// All of that is queued on the project's broadcaster task, so the caller doesn't wait on work that
+// grows with the number of sessions (and the delivery is empty), unless `ctx.wait_for_delivery`
+// (or `ctx.high_priority`) asks for it to happen here.
+fn deliver(
+    data: &web::Data<AppState>,
+    project_id: &str,
+    message: OutgoingMessage,
+    seq: u64,
+    ctx: &DeltaContext,
+    paths: Option<&[String]>,
+) -> Result<Delivery, serde_json::Error> {
+    let DeltaContext { target_user, exclude_users, ack_id, sent_at, wait_for_delivery, high_priority, .. } = *ctx;
+    let mut message = message.to_value()?;
+    if let Some(ack_id) = ack_id {
+        message["ack_id"] = serde_json::json!(ack_id);
+    }
+    let replay_frame = serde_json::to_string(&message)?;
+
+    // Other instances get it before `sent_at`, which is only meaningful on this instance's clock
+    data.relay_delivery(project_id, &message, paths, target_user, exclude_users, high_priority);
+
+    // Only live sends are stamped; a replayed copy would report the time spent disconnected
+    let msg_str = match sent_at {
+        Some(sent_at) => {
+            message["sent_at"] = serde_json::json!(sent_at);
+            serde_json::to_string(&message)?
+        }
+        None => replay_frame.clone(),
+    };
+
+    if !wait_for_delivery && !high_priority {
+        enqueue_fanout(data, project_id, FanoutJob {
+            seq,
+            replay_frame,
+            message: msg_str,
+            target_user: target_user.cloned(),
+            exclude_users: exclude_users.to_vec(),
+            paths: paths.map(<[String]>::to_vec),
+            ack_id: ack_id.map(str::to_string),
+        });
+        return Ok(Delivery::default());
+    }
+
+    // Record the message in each targeted user's replay buffer (connected or not)
+    data.replay.record(project_id, seq, &replay_frame, |user_id| is_targeted(user_id, target_user, exclude_users));
+
+    let targets = target_sessions(data, project_id, target_user, exclude_users, paths, high_priority);
+    // Messages never carry tokens, so the full payload is safe to log
+    log::debug!("[Broadcast] Project {} -> {} sessions: {}", project_id, targets.len(), msg_str);
+
+    let mut count = 0;
+    let mut matched_users = std::collections::HashSet::new();
+    let mut sessions = Vec::new();
+
+    // Broadcasting outside of any lock
+    for (session_id, user_id, sender) in targets {
+        // Sending message (slow consumers are dropped rather than queued without bound)
+        if !data.send_or_evict(project_id, session_id, &sender, msg_str.clone()) {
+            continue;
+        }
+        count += 1;
+        sessions.push(session_id);
+        matched_users.insert(user_id);
+    }
+    data.metrics.broadcasts_total.fetch_add(count, std::sync::atomic::Ordering::Relaxed);
+
+    Ok(Delivery { broadcast_count: count, matched_users, sessions })
+}
+
+// Hands a fan-out to the project's broadcaster task, starting the task on first use.
+// One task per project keeps that project's queued messages in order.
+fn enqueue_fanout(data: &web::Data<AppState>, project_id: &str, job: FanoutJob) {
+    let queue = data.broadcasters.queue(project_id, || start_broadcaster(data, project_id));
+    let Err(tokio::sync::mpsc::error::SendError(job)) = queue.send(job) else {
+        return;
+    };
+
+    // The task is gone (it panicked mid fan-out): replace it, or every later fan-out would be dropped too
+    log::error!("[Broadcast] Broadcaster for project {} is gone; restarting it", project_id);
+    data.broadcasters.remove_closed(project_id);
+    let queue = data.broadcasters.queue(project_id, || start_broadcaster(data, project_id));
+    if queue.send(job).is_err() {
+        log::error!("[Broadcast] Broadcaster for project {} is gone; dropping a fan-out", project_id);
+    }
+}
+
+fn start_broadcaster(data: &web::Data<AppState>, project_id: &str) -> tokio::sync::mpsc::UnboundedSender<FanoutJob> {
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<FanoutJob>();
+    let data = data.clone();
+    let project_id = project_id.to_string();
+    actix_rt::spawn(async move {
+        while let Some(job) = rx.recv().await {
+            run_fanout(&data, &project_id, job);
+        }
+    });
+    tx
+}
+
+// Broadcaster side of a queued delivery: records it for replay, then sends it to the sessions
+// targeted at this point and, for a `require_ack` broadcast, adds them to the ack's targets.
+fn run_fanout(data: &AppState, project_id: &str, job: FanoutJob) {
+    let target_user = job.target_user.as_ref();
+    data.replay.record(project_id, job.seq, &job.replay_frame, |user_id| is_targeted(user_id, target_user, &job.exclude_users));
+
+    let targets = target_sessions(data, project_id, target_user, &job.exclude_users, job.paths.as_deref(), false);
+    log::debug!("[Broadcast] Project {} -> {} sessions: {}", project_id, targets.len(), job.message);
+
+    let mut sent = Vec::with_capacity(targets.len());
+    for (session_id, _, sender) in targets {
+        if data.send_or_evict(project_id, session_id, &sender, job.message.clone()) {
+            sent.push(session_id);
+        }
+    }
+    data.metrics.broadcasts_total.fetch_add(sent.len() as u64, std::sync::atomic::Ordering::Relaxed);
+    if let Some(ack_id) = &job.ack_id {
+        data.acks.add_targets(ack_id, sent);
+    }
+}
+
+// Whether `user_id` receives a message: only `target_user` if given, otherwise anyone not excluded
+fn is_targeted(user_id: &str, target_user: Option<&String>, exclude_users: &[String]) -> bool {
+    match target_user {
+        Some(target_user) => user_id == target_user,
+        None => !exclude_users.iter().any(|u| u == user_id),
     }
 }
 
+// Applies a delivery another instance made: mirrors its timestamps into this instance's state
+// (so syncs from here agree), records it for replay and sends it to the matching local sessions.
+pub fn apply_relayed(data: &web::Data<AppState>, mut relayed: RelayedDelivery) {
+    let project_id = relayed.project_id.as_str();
+    // Seqs are per instance: the origin's one means nothing to our clients, so the message is
+    // restamped with ours (allocated together with the state change, like a local delivery)
+    let seq = data.clock.serialized(|| {
+        match relayed.message["type"].as_str() {
+            Some("invalidate-delta") => {
+                if let Some(delta) = relayed.message["data"].as_object() {
+                    // Plain timestamps, or { timestamp, etag } for paths invalidated with an etag
+                    let timestamps = delta.iter().filter_map(|(path, value)| {
+                        value.as_i64().or_else(|| value["timestamp"].as_i64()).map(|ts| (path.clone(), ts))
+                    });
+                    data.invalidations.set_timestamps(project_id, timestamps);
+                }
+            }
+            Some("invalidate-all") => {
+                if let Some(ts) = relayed.message["data"]["timestamp"].as_i64() {
+                    data.invalidations.clear_etags(project_id);
+                    store_timestamps(data, project_id, &invalidate_all_routes(data, project_id), ts);
+                }
+            }
+            Some("flush") => reset_project_state(data, project_id),
+            _ => {}
+        }
+        data.replay.next_seq(project_id)
+    });
+    relayed.message["seq"] = serde_json::json!(seq);
+    let message = relayed.message.to_string();
+
+    if !relayed.priority {
+        // Recorded and sent by the broadcaster, like a queued local delivery
+        enqueue_fanout(data, project_id, FanoutJob {
+            seq,
+            replay_frame: message.clone(),
+            message,
+            target_user: relayed.target_user,
+            exclude_users: relayed.exclude_users,
+            paths: relayed.paths,
+            ack_id: None,
+        });
+        return;
+    }
+
+    // Recorded in the targeted users' replay buffers, like a local delivery
+    data.replay.record(project_id, seq, &message, |user_id| {
+        is_targeted(user_id, relayed.target_user.as_ref(), &relayed.exclude_users)
+    });
+    let targets = target_sessions(data, project_id, relayed.target_user.as_ref(), &relayed.exclude_users, relayed.paths.as_deref(), true);
+    for (session_id, _, sender) in targets {
+        data.send_or_evict(project_id, session_id, &sender, message.clone());
+    }
+}
+
+// The project's sessions a message would be sent to: those of targeted users (see `is_targeted`),
+// and with `paths`, skipping sessions whose subscription excludes all of them.
+// The sender is the session's priority lane with `high_priority`.
+fn target_sessions(
+    data: &AppState,
+    project_id: &str,
+    target_user: Option<&String>,
+    exclude_users: &[String],
+    paths: Option<&[String]>,
+    high_priority: bool,
+) -> Vec<(uuid::Uuid, String, tokio::sync::mpsc::Sender<String>)> {
+    data.sessions.matching(project_id, |session_id, session| {
+        if !is_targeted(&session.user_id, target_user, exclude_users) || paths.is_some_and(|paths| !session.wants_any(paths)) {
+            return None;
+        }
+        let sender = if high_priority { &session.priority_sender } else { &session.sender };
+        Some((session_id, session.user_id.clone(), sender.clone()))
+    })
+}
+
+// Merges a project's delta into the staging map, keeping the max timestamp per path.
+// The first delta of a window spawns the flusher that broadcasts the merged result.
+fn stage_delta(data: &web::Data<AppState>, project_id: &str, target_paths: &[String], timestamp: i64, window: std::time::Duration) {
+    if data.staging.stage(project_id, target_paths, timestamp) {
+        let data = data.clone();
+        let project_id = project_id.to_string();
+        actix_rt::spawn(async move {
+            tokio::time::sleep(window).await;
+            flush_staged(&data, &project_id);
+        });
+    }
+}
+
+fn flush_staged(data: &web::Data<AppState>, project_id: &str) {
+    let Some(merged) = data.staging.take(project_id) else {
+        return;
+    };
+
+    let seq = data.clock.serialized(|| data.replay.next_seq(project_id));
+    // Debounced paths go out now, so the interval restarts from this broadcast
+    if data.path_min_interval.is_some() {
+        data.staging.record_broadcast(project_id, merged.keys().cloned());
+    }
+    let delta_data: serde_json::Map<String, serde_json::Value> = merged
+        .into_iter()
+        .map(|(path, ts)| {
+            let value = data.route_value(project_id, &path, ts);
+            (path, value)
+        })
+        .collect();
+    let ctx = DeltaContext {
+        timestamp: 0, // Unused: each path carries its own merged timestamp
+        current_drift: data.clock.drift_time(),
+        target_user: None,
+        exclude_users: &[],
+        ack_id: None,
+        sent_at: None,
+        wait_for_delivery: false,
+        high_priority: false,
+    };
+
+    if let Err(e) = broadcast_delta(data, project_id, delta_data, seq, &ctx) {
+        log::error!("[Coalesce] Failed to flush merged delta for project {}: {}", project_id, e);
+    }
+}
+
+// Sets every known route of the project to `ctx.timestamp` and broadcasts `invalidate-all`.
+// Returns the delivery and the routes that were touched.
+fn apply_invalidate_all(
+    data: &web::Data<AppState>,
+    project_id: &str,
+    seq: u64,
+    ctx: &DeltaContext,
+) -> Result<(Delivery, Vec<String>), serde_json::Error> {
+    let routes = invalidate_all_routes(data, project_id);
+    data.invalidations.clear_etags(project_id); // A full flush is always a hard purge
+    store_timestamps(data, project_id, &routes, ctx.timestamp);
+
+    let message = OutgoingMessage::InvalidateAll {
+        data: InvalidateAllData { timestamp: ctx.timestamp },
+        drift_time: ctx.current_drift,
+        seq,
+    };
+    let delivery = deliver(data, project_id, message, seq, ctx, None)?;
+    Ok((delivery, routes))
+}
+
+// Every route an invalidate-all touches: the project's known routes plus any it has state for
+fn invalidate_all_routes(data: &AppState, project_id: &str) -> Vec<String> {
+    let mut routes = data.routes.project_routes(project_id);
+    routes.extend(
+        data.invalidations.timestamps(project_id)
+            .into_iter()
+            .map(|(path, _)| path)
+            .filter(|r| !data.routes.contains(project_id, r)),
+    );
+    routes
+}
+
+// Whether `project_id` sits under `parent` in a slash-delimited hierarchy (at any depth)
+fn is_child_project(project_id: &str, parent: &str) -> bool {
+    project_id.strip_prefix(parent).is_some_and(|rest| rest.starts_with('/'))
+}
+
+// Adds `paths` to the response's `matched_paths`, which lists each path once across projects
+fn add_matched_paths(matched_paths: &mut Vec<String>, paths: &[String]) {
+    for path in paths {
+        if !matched_paths.contains(path) {
+            matched_paths.push(path.clone());
+        }
+    }
+}
+
+// Drops the paths whose stored timestamp is already >= their `if_modified_since`, adding them to `skipped`
+fn skip_unmodified(
+    data: &AppState,
+    project_id: &str,
+    paths: &mut Vec<String>,
+    if_modified_since: &HashMap<String, i64>,
+    skipped: &mut Vec<String>,
+) {
+    paths.retain(|path| {
+        let unmodified = if_modified_since
+            .get(path)
+            .zip(data.invalidations.timestamp(project_id, path))
+            .is_some_and(|(since, stored)| stored >= *since);
+        if unmodified && !skipped.contains(path) {
+            skipped.push(path.clone());
+        }
+        !unmodified
+    });
+}
+
+// Accumulates a user's per_user counts (a user may have deltas in several projects)
+fn add_user_counts(per_user: &mut serde_json::Map<String, serde_json::Value>, user_id: &str, broadcast_count: u64, affected_paths: usize) {
+    let entry = per_user
+        .entry(user_id.to_string())
+        .or_insert_with(|| serde_json::json!({ "broadcast_count": 0, "affected_paths": 0 }));
+    entry["broadcast_count"] = serde_json::json!(entry["broadcast_count"].as_u64().unwrap_or(0) + broadcast_count);
+    entry["affected_paths"] = serde_json::json!(entry["affected_paths"].as_u64().unwrap_or(0) + affected_paths as u64);
+}
+
+// A broadcast message that couldn't be serialized. State stored before it stays (the next
+// sync picks it up); the caller sees a 500 and can retry.
+fn delivery_failed(project_id: &str, e: serde_json::Error) -> HttpResponse {
+    log::error!("[Invalidate] Failed to serialize the broadcast for project {}: {}", project_id, e);
+    error_response(StatusCode::INTERNAL_SERVER_ERROR, "internal_error", e.to_string())
+}
+
+// Dry run: the response `invalidate` would produce for `targets`, plus each project's
+// would-be delta, computed without touching any state or sending anything.
+fn dry_run_response(
+    data: &AppState,
+    req: &InvalidateRequest,
+    etags: &HashMap<String, String>,
+    targets: &[(String, Vec<String>)],
+    user_targets: &[(String, String, Vec<String>)],
+    invalidate_all: bool,
+    queued: bool,
+) -> serde_json::Value {
+    let timestamp = chrono::Utc::now().timestamp_millis();
+    let mut total_count = 0;
+    let mut total_paths = 0;
+    let mut total_users = 0;
+    let mut per_project = serde_json::Map::new();
+    let mut matched_paths = Vec::new();
+
+    for (project_id, target_paths) in targets {
+        let (paths, filter) = if invalidate_all {
+            (invalidate_all_routes(data, project_id), None)
+        } else {
+            (target_paths.clone(), Some(target_paths.as_slice()))
+        };
+        let sessions = if paths.is_empty() && !invalidate_all {
+            Vec::new()
+        } else {
+            target_sessions(data, project_id, req.user_id.as_ref(), req.exclude_user_ids.as_deref().unwrap_or_default(), filter, false)
+        };
+        let matched_users: std::collections::HashSet<&String> = sessions.iter().map(|(_, u, _)| u).collect();
+        let delta: serde_json::Map<String, serde_json::Value> = paths
+            .iter()
+            .map(|p| {
+                let value = match etags.get(p).filter(|_| !invalidate_all) {
+                    Some(etag) => serde_json::json!({ "timestamp": timestamp, "etag": etag }),
+                    None => serde_json::json!(timestamp),
+                };
+                (p.clone(), value)
+            })
+            .collect();
+
+        total_count += sessions.len();
+        total_paths += paths.len();
+        total_users += matched_users.len();
+        add_matched_paths(&mut matched_paths, &paths);
+        per_project.insert(project_id.clone(), serde_json::json!({
+            "broadcast_count": sessions.len(),
+            "affected_paths": paths.len(),
+            "matched_paths": paths,
+            "matched_users": matched_users.len(),
+            "delta": delta
+        }));
+    }
+
+    let mut per_user = serde_json::Map::new();
+    for (user_id, project_id, paths) in user_targets {
+        let sessions = target_sessions(data, project_id, Some(user_id), &[], Some(paths), false).len();
+        total_count += sessions;
+        total_paths += paths.len();
+        add_matched_paths(&mut matched_paths, paths);
+        add_user_counts(&mut per_user, user_id, sessions as u64, paths.len());
+    }
+
+    let mut response = serde_json::json!({
+        "status": "success",
+        "dry_run": true,
+        "broadcast_count": total_count,
+        "affected_paths": total_paths,
+        "matched_paths": matched_paths,
+        "matched_users": total_users,
+        "queued": queued,
+        "projects": per_project,
+        "timestamp": timestamp,
+        "drift_time": data.clock.drift_time()
+    });
+    if !per_user.is_empty() {
+        response["per_user"] = serde_json::Value::Object(per_user);
+    }
+    if req.user_id.is_some() {
+        response["target_online"] = serde_json::json!(total_users > 0);
+    }
+    response
+}
+
+// What an invalidate does instead for the projects whose clock went backward
+struct DriftRecovery {
+    // Future-dated projects with their drift time and reset seq
+    reset: Vec<(String, i64, u64)>,
+    // Drifted within the reset cooldown: neither invalidated nor reset
+    suppressed: Vec<String>,
+}
+
+impl DriftRecovery {
+    fn covers(&self, project_id: &str) -> bool {
+        self.reset.iter().any(|(p, _, _)| p == project_id) || self.suppressed.iter().any(|p| p == project_id)
+    }
+}
+
+// Future-dates every route of `projects` and allocates their reset seqs, except for projects
+// whose last reset was within the cooldown. Called under the clock's timestamp lock.
+fn start_drift_recovery(data: &AppState, projects: &[String]) -> DriftRecovery {
+    data.metrics.clock_drift_total.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+
+    let mut drift = DriftRecovery { reset: Vec::new(), suppressed: Vec::new() };
+    for proj in projects {
+        // A reset was just broadcast: don't future-date and re-broadcast again while the clock settles
+        let Some(drift_now) = data.clock.start_drift_reset(proj) else {
+            drift.suppressed.push(proj.clone());
+            continue;
+        };
+
+        // Far in the future (DRIFT_FUTURE_OFFSET_MS) - to be safe.
+        // This ensures ANY client of the project reconnecting will see local data as stale.
+        data.invalidations.set_all(proj, data.clock.future_timestamp(drift_now));
+        data.invalidations.clear_etags(proj); // Versions can't be trusted across a clock reset
+        drift.reset.push((proj.clone(), drift_now, data.replay.next_seq(proj)));
+    }
+    drift
+}
+
+// Broadcasts the drift event to everyone in the reset projects
+fn broadcast_drift_reset(data: &AppState, drift: &DriftRecovery) {
+    if !drift.suppressed.is_empty() {
+        log::warn!("[ClockDrift] Reset of {:?} suppressed (within {:?} cooldown)", drift.suppressed, data.clock.drift_cooldown());
+    }
+    if drift.reset.is_empty() {
+        return;
+    }
+    data.save_invalidations();
+
+    for (proj, drift_now, seq) in &drift.reset {
+        let reset_msg = OutgoingMessage::DriftReset { data: Empty {}, drift_time: *drift_now, seq: *seq }.to_json();
+
+        // Buffered deltas predate the reset; force reconnecting clients into a full sync
+        data.replay.clear_project(proj, *seq);
+
+        let mut sent = 0;
+        for (session_id, _, sender) in target_sessions(data, proj, None, &[], None, false) {
+            if data.send_or_evict(proj, session_id, &sender, reset_msg.clone()) {
+                sent += 1;
+            }
+        }
+        data.metrics.broadcasts_total.fetch_add(sent, std::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+fn drift_response(data: &AppState, drift: &DriftRecovery) -> serde_json::Value {
+    if drift.reset.is_empty() {
+        return serde_json::json!({
+            "status": "clock_reset_suppressed",
+            "message": "System clock drift detected during the reset cooldown; no broadcast issued.",
+            "projects": drift.suppressed,
+            "drift_time": data.clock.drift_time()
+        });
+    }
+    let mut response = serde_json::json!({
+        "status": "clock_reset",
+        "message": "System clock drift detected. BROADCAST: Future invalidations issued.",
+        "projects": drift.reset.iter().map(|(p, _, _)| p).collect::<Vec<_>>(),
+        "drift_time": data.clock.drift_time()
+    });
+    if !drift.suppressed.is_empty() {
+        response["suppressed_projects"] = serde_json::json!(drift.suppressed);
+    }
+    response
+}
+
+#[tracing::instrument(skip_all, fields(project_id, path_count))]
 pub async fn invalidate(
     data: web::Data<AppState>,
     req: web::Json<InvalidateRequest>,
 ) -> impl Responder {
-    let project_id = &req.project_id;
-    
+    // Target projects: project_id and/or project_ids (deduplicated)
+    let mut project_ids: Vec<String> = Vec::new();
+    for p in req.project_id.iter().chain(req.project_ids.iter().flatten()) {
+        if !project_ids.contains(p) {
+            project_ids.push(p.clone());
+        }
+    }
+
+    if project_ids.is_empty() {
+        return error_response(StatusCode::BAD_REQUEST, "missing_project", "No project provided");
+    }
+
+    // "*" targets every known project (and makes drift recovery global)
+    let global = project_ids.iter().any(|p| p == "*");
+    if global {
+        let mut all: Vec<String> = data.known_projects().into_iter().collect();
+        all.sort();
+        project_ids = all;
+    }
+
+    // Hierarchical projects are opt-in: by default "acme" is just another flat project id
+    if req.cascade && !global {
+        let mut children: Vec<String> = data.known_projects()
+            .into_iter()
+            .filter(|p| !project_ids.contains(p) && project_ids.iter().any(|parent| is_child_project(p, parent)))
+            .collect();
+        children.sort();
+        project_ids.extend(children);
+    }
+
+    // Cap the request size before doing any normalization work
+    let path_count = req.path.iter().count()
+        + req.paths.as_ref().map_or(0, |ps| ps.len())
+        + req.per_user.iter().flatten().map(|u| u.paths.len()).sum::<usize>();
+    let span = tracing::Span::current();
+    span.record("project_id", project_ids.join(",").as_str());
+    span.record("path_count", path_count);
+    if path_count > data.max_paths_per_request {
+        let mut body = error_body("too_many_paths", format!(
+            "Too many paths: {} exceeds the limit of {} per request; split into batches",
+            path_count, data.max_paths_per_request
+        ));
+        body["max_paths"] = serde_json::json!(data.max_paths_per_request);
+        return HttpResponse::PayloadTooLarge().json(body);
+    }
+
     // 0. Extract and normalize all paths
-    let mut target_paths = Vec::new();
+    let mut requested_paths = Vec::new();
     if let Some(p) = &req.path {
-        target_paths.push(normalize_path(p.clone()));
+        requested_paths.push(normalize_path(&data, p.clone()));
     }
     if let Some(ps) = &req.paths {
         for p in ps {
-            target_paths.push(normalize_path(p.clone()));
+            requested_paths.push(normalize_path(&data, p.clone()));
         }
     }
     
-    if target_paths.is_empty() {
-        return HttpResponse::BadRequest().body("No paths provided");
+    // `all` wins over any paths/tags given alongside it
+    let invalidate_all = req.all == Some(true);
+
+    let etags: HashMap<String, String> = req.etags
+        .iter()
+        .flatten()
+        .map(|(path, etag)| (canonical_path(path, data.lowercase_paths), etag.clone()))
+        .collect();
+    let if_modified_since: HashMap<String, i64> = req.if_modified_since
+        .iter()
+        .flatten()
+        .map(|(path, ts)| (canonical_path(path, data.lowercase_paths), *ts))
+        .collect();
+
+    if requested_paths.is_empty() && req.tags.is_none() && !invalidate_all && req.per_user.is_none() {
+        return error_response(StatusCode::BAD_REQUEST, "missing_paths", "No paths provided");
     }
 
-    // 1. Coordinated Timestamp Generation & Clock Drift Detection (Short-lived lock)
-    let (timestamp, drift_detected) = {
-        let mut last_ts = data.last_global_timestamp.lock();
-        let now = chrono::Utc::now().timestamp_millis();
-        let prev = *last_ts;
-
-        if prev > 0 && now < prev {
-            log::warn!("[ClockDrift] Detected backward clock jump: {} -> {}. Triggering future-dated invalidations.", prev, now);
-            *last_ts = 0; // Reset tracking
-            (now, true)
-        } else {
-            *last_ts = now;
-            (now, false)
-        }
-    };
-
-    if drift_detected {
-        let drift_now = chrono::Utc::now().timestamp_millis();
-        data.last_drift_timestamp.store(drift_now, std::sync::atomic::Ordering::SeqCst);
-        
-        // 50 years in the future (ms) - to be safe
-        let future_timestamp = drift_now + (50 * 365 * 24 * 60 * 60 * 1000);
-        
-        // Set ALL routes in ALL projects to this future timestamp
-        // This ensures ANY client reconnecting will see local data as stale.
-        for mut proj_entry in data.project_invalidation_state.iter_mut() {
-             for mut route_entry in proj_entry.value_mut().iter_mut() {
-                 *route_entry.value_mut() = future_timestamp;
-             }
-        }
-        
-        // Broadcast drift event to EVERYONE
-        let reset_msg = serde_json::json!({
-            "type": "invalidate",
-            "data": {},
-            "drift_time": drift_now
-        }).to_string();
-        
-        for proj_entry in data.active_sessions.iter() {
-            for sess_entry in proj_entry.value().iter() {
-                let _ = sess_entry.value().sender.send(reset_msg.clone());
-            }
+    // Rate limit the expanded projects once the request is known to be valid and before resolving
+    // any targets, so "*" and cascades are charged to every project they reach (applies to both
+    // the drift and normal paths; dry runs and rejected requests are free)
+    if !req.dry_run {
+        if let Err(project_id) = data.allow_invalidate(&project_ids) {
+            log::warn!("[RateLimit] Rejecting invalidate for project {}", project_id);
+            return error_response(StatusCode::TOO_MANY_REQUESTS, "rate_limited", "Rate limit exceeded");
         }
-        
-        return HttpResponse::Ok().json(serde_json::json!({
-            "status": "clock_reset",
-            "message": "System clock drift detected. BROADCAST: Future invalidations issued.",
-            "drift_time": drift_now
-        }));
     }
 
-    // 2. Register routes if new (DashMap is thread-safe, no lock needed)
-    let mut new_routes_found = false;
-    for path in &target_paths {
-        if !data.known_routes.contains_key(path) {
-            data.known_routes.insert(path.clone(), ());
-            new_routes_found = true;
+    let mut targets: Vec<(String, Vec<String>)> = if invalidate_all {
+        project_ids.iter().map(|p| (p.clone(), Vec::new())).collect()
+    } else {
+        project_ids
+            .iter()
+            .map(|p| (p.clone(), resolve_target_paths(&data, p, &requested_paths, req.tags.as_deref())))
+            .collect()
+    };
+
+    // per_user: (user, project, paths) deltas that only go to that user's sessions
+    let mut user_targets: Vec<(String, String, Vec<String>)> = if invalidate_all {
+        Vec::new()
+    } else {
+        req.per_user.iter().flatten().flat_map(|entry| {
+            let paths: Vec<String> = entry.paths.iter().map(|p| normalize_path(&data, p.clone())).collect();
+            project_ids.iter().map(move |p| (entry.user_id.clone(), p.clone(), paths.clone()))
+        })
+        .map(|(user_id, project_id, paths)| {
+            let target_paths = resolve_target_paths(&data, &project_id, &paths, None);
+            (user_id, project_id, target_paths)
+        })
+        .filter(|(_, _, paths)| !paths.is_empty())
+        .collect()
+    };
+
+    // Paths some other caller already invalidated at or after `if_modified_since` stay as they are
+    let mut skipped = Vec::new();
+    if !if_modified_since.is_empty() && !invalidate_all {
+        for (project_id, paths) in targets.iter_mut() {
+            skip_unmodified(&data, project_id, paths, &if_modified_since, &mut skipped);
         }
+        for (_, project_id, paths) in user_targets.iter_mut() {
+            skip_unmodified(&data, project_id, paths, &if_modified_since, &mut skipped);
+        }
+        user_targets.retain(|(_, _, paths)| !paths.is_empty());
     }
-    if new_routes_found {
-        data.save_routes();
-    }
-    
-    // 3. Update Invalidation State and Prepare Delta Message (DashMap is thread-safe)
-    let mut delta_data = serde_json::Map::new();
-    let current_drift = data.last_drift_timestamp.load(std::sync::atomic::Ordering::SeqCst);
-    
-    for path in &target_paths {
-        data.project_invalidation_state
-            .entry(project_id.clone())
-            .or_insert_with(dashmap::DashMap::new)
-            .insert(path.clone(), timestamp);
-        
-        delta_data.insert(path.clone(), serde_json::json!(timestamp));
-    }
-
-    let message = serde_json::json!({
-        "type": "invalidate-delta",
-        "data": delta_data,
-        "drift_time": current_drift
-    });
-    
-    let msg_str = match serde_json::to_string(&message) {
-        Ok(s) => s,
-        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
-    };
 
-    let mut count = 0;
+    // Coalescing only applies to plain broadcasts; targeted, acked or high-priority ones go out immediately
+    let high_priority = req.priority == Priority::High;
+    let coalesce_window = data.coalesce_window
+        .filter(|_| !high_priority && req.user_id.is_none() && req.exclude_user_ids.is_none() && !req.require_ack && !invalidate_all && user_targets.is_empty());
+    // Same for the per-path debounce (which dry runs don't simulate)
+    let path_min_interval = data.path_min_interval
+        .filter(|_| !high_priority && req.user_id.is_none() && req.exclude_user_ids.is_none() && !req.require_ack && coalesce_window.is_none());
 
-    // Broadcasting outside of any lock
-    if let Some(project_sessions) = data.active_sessions.get(project_id) {
-        for entry in project_sessions.iter() {
-            let session_data = entry.value();
-            
-            // Filter by user_id if provided
-            if let Some(target_user) = &req.user_id {
-                if &session_data.user_id != target_user {
-                    continue;
-                }
-            }
-            
-            // Sending message
-            let _ = session_data.sender.send(msg_str.clone());
-            count += 1;
+    if req.dry_run {
+        let mut response = dry_run_response(&data, &req, &etags, &targets, &user_targets, invalidate_all, coalesce_window.is_some());
+        if !skipped.is_empty() {
+            response["skipped"] = serde_json::json!(skipped);
         }
+        return HttpResponse::Ok().json(response);
     }
 
-    HttpResponse::Ok().json(serde_json::json!({
+    if !invalidate_all && targets.iter().all(|(_, paths)| paths.is_empty()) && user_targets.is_empty() {
+        // Only wildcards/tags that matched nothing: nothing to invalidate or broadcast
+        return HttpResponse::Ok().json(serde_json::json!({
+            "status": "success",
+            "broadcast_count": 0,
+            "affected_paths": 0,
+            "matched_paths": [],
+            "skipped": skipped
+        }));
+    }
+
+    // 1. Coordinated Timestamp Generation & Clock Drift Detection (Short-lived lock)
+    // One timestamp is shared by all targeted projects; each checks it against the last one it was
+    // given, so a backward clock jump is detected and recovered from per project. Seqs are
+    // allocated under the same lock, so within a project seq order == timestamp order.
+    let (timestamp, drift, seqs, user_seqs) = data.clock.with_timestamp(&project_ids, |now, drifted| {
+        // Drifted projects are reset instead of invalidated ("*" resets every project)
+        let drift = (!drifted.is_empty()).then(|| {
+            start_drift_recovery(&data, if global { &project_ids } else { drifted })
+        });
+        let recovering = |p: &String| drift.as_ref().is_some_and(|d| d.covers(p));
+
+        // Coalesced deltas get their seq when flushed
+        let seqs: Vec<u64> = if coalesce_window.is_some() {
+            Vec::new()
+        } else {
+            targets.iter().filter(|(p, _)| !recovering(p)).map(|(p, _)| data.replay.next_seq(p)).collect()
+        };
+        let user_seqs: Vec<u64> = user_targets
+            .iter()
+            .filter(|(_, p, _)| !recovering(p))
+            .map(|(_, p, _)| data.replay.next_seq(p))
+            .collect();
+        (now, drift, seqs, user_seqs)
+    });
+
+    data.metrics.invalidations_total.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+
+    if let Some(drift) = &drift {
+        broadcast_drift_reset(&data, drift);
+        targets.retain(|(p, _)| !drift.covers(p));
+        user_targets.retain(|(_, p, _)| !drift.covers(p));
+        if targets.is_empty() && user_targets.is_empty() {
+            return HttpResponse::Ok().json(drift_response(&data, drift));
+        }
+    }
+
+    // 2. Register routes if new (DashMap is thread-safe, no lock needed)
+    let mut new_routes_found = false;
+    let user_paths = user_targets.iter().map(|(_, project_id, paths)| (project_id, paths));
+    for (project_id, target_paths) in targets.iter().map(|(p, paths)| (p, paths)).chain(user_paths) {
+        new_routes_found |= data.routes.register(project_id, target_paths);
+    }
+    if new_routes_found {
+        data.save_routes();
+    }
+    
+    // 3. Update each project's state and broadcast its delta
+    let current_drift = data.clock.drift_time();
+    let mut total_count = 0;
+    let mut total_paths = 0;
+    let mut total_users = 0;
+    let mut per_project = serde_json::Map::new();
+    let mut matched_paths = Vec::new(); // Concrete paths, after wildcard/tag/dependency expansion
+    let mut ack_targets = std::collections::HashSet::new();
+
+    let ack_id = req.require_ack.then(|| uuid::Uuid::new_v4().to_string());
+    if let Some(ack_id) = &ack_id {
+        data.acks.start(ack_id);
+    }
+    let ctx = DeltaContext {
+        timestamp,
+        current_drift,
+        target_user: req.user_id.as_ref(),
+        exclude_users: req.exclude_user_ids.as_deref().unwrap_or_default(),
+        ack_id: ack_id.as_deref(),
+        sent_at: req.measure.then(|| data.monotonic_ms()),
+        wait_for_delivery: req.wait_for_delivery,
+        high_priority,
+    };
+
+    for (i, (project_id, target_paths)) in targets.iter().enumerate() {
+        if invalidate_all {
+            let (delivery, routes) = match apply_invalidate_all(&data, project_id, seqs[i], &ctx) {
+                Ok(result) => result,
+                Err(e) => return delivery_failed(project_id, e),
+            };
+            data.metrics.record_path_hits(project_id, &routes);
+            total_count += delivery.broadcast_count;
+            total_paths += routes.len();
+            total_users += delivery.matched_users.len();
+            ack_targets.extend(delivery.sessions.iter().copied());
+            add_matched_paths(&mut matched_paths, &routes);
+            per_project.insert(project_id.clone(), serde_json::json!({
+                "broadcast_count": delivery.broadcast_count,
+                "affected_paths": routes.len(),
+                "matched_paths": routes,
+                "matched_users": delivery.matched_users.len()
+            }));
+            continue;
+        }
+
+        if target_paths.is_empty() {
+            per_project.insert(project_id.clone(), serde_json::json!({ "broadcast_count": 0, "affected_paths": 0, "matched_paths": [], "matched_users": 0 }));
+            continue;
+        }
+
+        data.invalidations.store_etags(project_id, target_paths, &etags);
+        data.metrics.record_path_hits(project_id, target_paths);
+
+        if let Some(window) = coalesce_window {
+            // State is updated now; the broadcast goes out merged when the window closes
+            store_timestamps(&data, project_id, target_paths, timestamp);
+            stage_delta(&data, project_id, target_paths, timestamp, window);
+            total_paths += target_paths.len();
+            add_matched_paths(&mut matched_paths, target_paths);
+            per_project.insert(project_id.clone(), serde_json::json!({
+                "broadcast_count": 0,
+                "affected_paths": target_paths.len(),
+                "matched_paths": target_paths,
+                "queued": true
+            }));
+            continue;
+        }
+
+        // Paths broadcast too recently are deferred: stored now, broadcast once the interval has passed.
+        // The rest still go out (possibly as an empty delta, so the allocated seq isn't skipped).
+        let (broadcast_paths, debounced) = match path_min_interval {
+            Some(min_interval) => data.staging.debounce(project_id, target_paths, min_interval),
+            None => (target_paths.clone(), Vec::new()),
+        };
+        if let Some(min_interval) = path_min_interval.filter(|_| !debounced.is_empty()) {
+            store_timestamps(&data, project_id, &debounced, timestamp);
+            stage_delta(&data, project_id, &debounced, timestamp, min_interval);
+        }
+
+        let delivery = match apply_delta(&data, project_id, &broadcast_paths, seqs[i], &ctx) {
+            Ok(delivery) => delivery,
+            Err(e) => return delivery_failed(project_id, e),
+        };
+
+        total_count += delivery.broadcast_count;
+        total_paths += target_paths.len();
+        total_users += delivery.matched_users.len();
+        ack_targets.extend(delivery.sessions.iter().copied());
+        add_matched_paths(&mut matched_paths, target_paths);
+        let mut project_result = serde_json::json!({
+            "broadcast_count": delivery.broadcast_count,
+            "affected_paths": target_paths.len(),
+            "matched_paths": target_paths,
+            "matched_users": delivery.matched_users.len()
+        });
+        if !debounced.is_empty() {
+            project_result["debounced_paths"] = serde_json::json!(debounced);
+        }
+        per_project.insert(project_id.clone(), project_result);
+    }
+
+    // Per-user deltas share the request's timestamp but only reach that user's sessions
+    let mut per_user = serde_json::Map::new();
+    for ((user_id, project_id, target_paths), seq) in user_targets.iter().zip(user_seqs) {
+        data.invalidations.store_etags(project_id, target_paths, &etags);
+        data.metrics.record_path_hits(project_id, target_paths);
+        let user_ctx = DeltaContext { target_user: Some(user_id), ..ctx };
+        let delivery = match apply_delta(&data, project_id, target_paths, seq, &user_ctx) {
+            Ok(delivery) => delivery,
+            Err(e) => return delivery_failed(project_id, e),
+        };
+
+        total_count += delivery.broadcast_count;
+        total_paths += target_paths.len();
+        ack_targets.extend(delivery.sessions.iter().copied());
+        add_matched_paths(&mut matched_paths, target_paths);
+        add_user_counts(&mut per_user, user_id, delivery.broadcast_count, target_paths.len());
+    }
+    data.save_invalidations();
+
+    let optimistic = !req.wait_for_delivery && !high_priority;
+    let mut response = serde_json::json!({
         "status": "success",
-        "broadcast_count": count,
-        "affected_paths": target_paths.len(),
+        "broadcast_count": total_count,
+        "affected_paths": total_paths,
+        "matched_paths": matched_paths,
+        "matched_users": total_users,
+        "queued": coalesce_window.is_some(),
+        // Queued fan-outs pick their sessions on the broadcaster task, so broadcast_count and
+        // matched_users are only known (and non-zero) with wait_for_delivery
+        "optimistic": optimistic,
+        "projects": per_project,
         "timestamp": timestamp,
         "drift_time": current_drift
+    });
+    if !skipped.is_empty() {
+        response["skipped"] = serde_json::json!(skipped);
+    }
+    // Projects of the request that drifted and were reset (or suppressed) instead
+    if let Some(drift) = &drift {
+        response["clock_drift"] = drift_response(&data, drift);
+    }
+    if !per_user.is_empty() {
+        response["per_user"] = serde_json::Value::Object(per_user);
+    }
+    // Lets callers tell "user wasn't connected" apart from "delivered". A queued fan-out hasn't
+    // matched anyone yet, so there it only says whether the user has a session at all.
+    if let Some(user_id) = &req.user_id {
+        let online = if optimistic {
+            project_ids.iter().any(|p| data.sessions.has_user(p, user_id))
+        } else {
+            total_users > 0
+        };
+        response["target_online"] = serde_json::json!(online);
+    }
+    if let Some(ack_id) = ack_id {
+        data.acks.add_targets(&ack_id, ack_targets);
+        response["ack_id"] = serde_json::json!(ack_id);
+    }
+
+    HttpResponse::Ok().json(response)
+}
+
+pub async fn remove_routes(
+    data: web::Data<AppState>,
+    req: web::Json<RemoveRoutesRequest>,
+) -> impl Responder {
+    let paths: Vec<String> = req.path.iter()
+        .chain(req.paths.iter().flatten())
+        .map(|p| normalize_path(&data, p.clone()))
+        .collect();
+
+    if paths.is_empty() {
+        return error_response(StatusCode::BAD_REQUEST, "missing_paths", "No paths provided");
+    }
+
+    let removed = data.routes.remove(req.project_id.as_deref(), &paths);
+    if removed > 0 {
+        data.save_routes();
+    }
+
+    if req.purge_invalidations {
+        data.invalidations.remove_paths(req.project_id.as_deref(), &paths);
+        data.save_invalidations();
+    }
+
+    HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "removed": removed
+    }))
+}
+
+pub async fn tag_paths(
+    data: web::Data<AppState>,
+    req: web::Json<TagPathsRequest>,
+) -> impl Responder {
+    let paths = req.paths.iter().map(|p| normalize_path(&data, p.clone()));
+    let paths = data.routes.tag(&req.project_id, &req.tag, paths);
+
+    HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "tag": req.tag,
+        "paths": paths
+    }))
+}
+
+pub async fn add_dependency(
+    data: web::Data<AppState>,
+    req: web::Json<DependencyRequest>,
+) -> impl Responder {
+    let path = normalize_path(&data, req.path.clone());
+    let dependents = req.dependents.iter().map(|d| normalize_path(&data, d.clone()));
+    let dependents = data.routes.add_dependents(&path, dependents);
+
+    HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "path": path,
+        "dependents": dependents
+    }))
+}
+
+pub async fn disconnect(
+    data: web::Data<AppState>,
+    req: web::Json<DisconnectRequest>,
+) -> impl Responder {
+    // Never fall through to "everyone in the project"
+    if req.session_id.is_none() && req.user_id.is_none() {
+        return error_response(StatusCode::BAD_REQUEST, "missing_target", "session_id or user_id is required");
+    }
+
+    let disconnected = data.sessions.disconnect(&req.project_id, req.session_id, req.user_id.as_deref());
+    if disconnected > 0 {
+        log::info!("[Admin] Disconnected {} sessions in project {}", disconnected, req.project_id);
+    }
+
+    HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "disconnected": disconnected
+    }))
+}
+
+// Resets a project to the baseline (for test environments): its invalidation state, etags and
+// pending coalesced/debounced deltas are dropped, and its sessions get `{"type":"flush"}`.
+// Unlike the drift path nothing is future-dated; known routes simply sync at the baseline again.
+pub async fn flush(
+    data: web::Data<AppState>,
+    req: web::Json<FlushRequest>,
+) -> impl Responder {
+    let project_id = req.project_id.as_str();
+
+    let seq = data.clock.serialized(|| {
+        reset_project_state(&data, project_id);
+        data.replay.next_seq(project_id)
+    });
+    data.save_invalidations();
+
+    let message = OutgoingMessage::Flush {
+        drift_time: data.clock.drift_time(),
+        seq,
+    };
+    let ctx = DeltaContext {
+        timestamp: data.clock.baseline(),
+        current_drift: data.clock.drift_time(),
+        target_user: None,
+        exclude_users: &[],
+        ack_id: None,
+        sent_at: None,
+        wait_for_delivery: true, // The response reports who was actually notified
+        high_priority: false,
+    };
+    let delivery = match deliver(&data, project_id, message, seq, &ctx, None) {
+        Ok(delivery) => delivery,
+        Err(e) => return delivery_failed(project_id, e),
+    };
+    log::info!("[Admin] Flushed project {} ({} sessions notified)", project_id, delivery.broadcast_count);
+
+    HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "notified": delivery.broadcast_count
+    }))
+}
+
+// Forgets everything invalidated in the project since the baseline
+fn reset_project_state(data: &AppState, project_id: &str) {
+    data.invalidations.reset(project_id);
+    data.staging.reset(project_id);
+}
+
+pub async fn list_projects(data: web::Data<AppState>) -> impl Responder {
+    let mut project_ids: std::collections::BTreeSet<String> = data.sessions.projects().into_iter().collect();
+    project_ids.extend(data.invalidations.projects());
+    project_ids.extend(data.tokens.projects());
+
+    let projects: Vec<serde_json::Value> = project_ids.into_iter().map(|project_id| {
+        let sessions = data.sessions.project_count(&project_id);
+        let invalidated_routes = data.invalidations.route_count(&project_id);
+        serde_json::json!({
+            "project_id": project_id,
+            "sessions": sessions,
+            "invalidated_routes": invalidated_routes
+        })
+    }).collect();
+
+    HttpResponse::Ok().json(projects)
+}
+
+pub async fn list_sessions(
+    data: web::Data<AppState>,
+    project_id: web::Path<String>,
+) -> impl Responder {
+    // An unknown project simply has no sessions
+    let sessions: Vec<serde_json::Value> = data.sessions.matching(project_id.as_str(), |session_id, session| {
+        Some(serde_json::json!({
+            "session_id": session_id,
+            "user_id": session.user_id,
+            "protocol": session.protocol
+        }))
+    });
+
+    HttpResponse::Ok().json(sessions)
+}
+
+pub async fn events(
+    data: web::Data<AppState>,
+    query: web::Query<EventsQuery>,
+) -> impl Responder {
+    // Most recent `limit` events, oldest first
+    HttpResponse::Ok().json(data.events.recent(query.limit.unwrap_or(100)))
+}
+
+// The project's most invalidated paths, most first (ties by path)
+pub async fn hot_paths(
+    data: web::Data<AppState>,
+    query: web::Query<HotPathsQuery>,
+) -> impl Responder {
+    let counts = data.metrics.hot_paths(&query.project_id, query.n.unwrap_or(10));
+
+    HttpResponse::Ok().json(serde_json::json!({
+        "project_id": query.project_id,
+        "paths": counts.iter().map(|(path, count)| serde_json::json!({ "path": path, "count": count })).collect::<Vec<_>>()
+    }))
+}
+
+// Recorded clock-drift detections, oldest first
+pub async fn drift(data: web::Data<AppState>) -> impl Responder {
+    let history = data.clock.drift_history();
+    HttpResponse::Ok().json(serde_json::json!({
+        "count": history.len(),
+        "last_drift_timestamp": data.clock.drift_time(),
+        "events": history
+    }))
+}
+
+// Public liveness probe: no auth, no locks
+pub async fn health(data: web::Data<AppState>) -> impl Responder {
+    let uptime_ms = chrono::Utc::now().timestamp_millis() - data.clock.server_start_time();
+    HttpResponse::Ok().json(serde_json::json!({
+        "status": "ok",
+        "uptime_ms": uptime_ms
+    }))
+}
+
+// Which build is running: crate version plus the git SHA and build time injected by build.rs
+pub async fn version() -> impl Responder {
+    let built_at = env!("BUILD_UNIX_TIME").parse::<i64>().ok()
+        .and_then(|secs| chrono::DateTime::from_timestamp(secs, 0))
+        .map(|t| t.to_rfc3339());
+    HttpResponse::Ok().json(serde_json::json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "git_sha": env!("GIT_SHA"),
+        "built_at": built_at
+    }))
+}
+
+pub async fn route_status(
+    data: web::Data<AppState>,
+    query: web::Query<RouteQuery>,
+) -> impl Responder {
+    let path = normalize_path(&data, serde_json::Value::String(query.path.clone()));
+
+    let invalidated_at = data.invalidations.timestamp(&query.project_id, &path);
+
+    // Known but never invalidated in this project: clients last synced it at the baseline
+    let timestamp = match invalidated_at {
+        Some(ts) => ts,
+        None if data.routes.contains(&query.project_id, &path) => data.clock.baseline(),
+        None => return error_response(StatusCode::NOT_FOUND, "unknown_route", "Unknown route"),
+    };
+
+    HttpResponse::Ok().json(serde_json::json!({
+        "project_id": query.project_id,
+        "path": path,
+        "timestamp": timestamp,
+        "invalidated": invalidated_at.is_some()
     }))
 }
+
+pub async fn ack_status(
+    data: web::Data<AppState>,
+    ack_id: web::Path<String>,
+) -> impl Responder {
+    match data.acks.status(ack_id.as_str()) {
+        Some((targets, acked)) => HttpResponse::Ok().json(serde_json::json!({
+            "ack_id": ack_id.as_str(),
+            "targets": targets,
+            "acked": acked
+        })),
+        None => error_response(StatusCode::NOT_FOUND, "unknown_ack", "Unknown or expired ack_id"),
+    }
+}
+
+pub async fn stats(data: web::Data<AppState>) -> impl Responder {
+    // Counts only, so this is cheap enough to poll frequently
+    HttpResponse::Ok().json(serde_json::json!({
+        "pending_tokens": data.tokens.count(),
+        "active_sessions": data.sessions.counts(),
+        "known_routes": data.routes.count(),
+        "server_start_time": data.clock.server_start_time(),
+        "baseline_timestamp": data.clock.baseline(),
+        "last_drift_timestamp": data.clock.drift_time()
+    }))
+}
+
+pub async fn metrics(data: web::Data<AppState>) -> impl Responder {
+    use std::fmt::Write as _;
+    use std::sync::atomic::Ordering;
+
+    let active_sessions = data.sessions.total();
+
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        let _ = writeln!(out, "{} {}", name, value);
+    };
+
+    metric("pro_cache_invalidations_total", "counter", "Invalidate requests processed.", data.metrics.invalidations_total.load(Ordering::Relaxed));
+    metric("pro_cache_broadcasts_total", "counter", "Messages sent to sessions by invalidations.", data.metrics.broadcasts_total.load(Ordering::Relaxed));
+    metric("pro_cache_clock_drift_total", "counter", "Backward clock jumps detected.", data.metrics.clock_drift_total.load(Ordering::Relaxed));
+    metric("pro_cache_connections_total", "counter", "WebSocket sessions accepted.", data.metrics.connections_total.load(Ordering::Relaxed));
+    metric("pro_cache_slow_consumer_evictions_total", "counter", "Sessions dropped because their outgoing queue was full.", data.metrics.slow_consumer_evictions_total.load(Ordering::Relaxed));
+    metric("pro_cache_active_sessions", "gauge", "Currently connected WebSocket sessions.", active_sessions as u64);
+    metric("pro_cache_pending_tokens", "gauge", "Registered tokens.", data.tokens.count() as u64);
+    metric("pro_cache_known_routes", "gauge", "Known routes across all projects.", data.routes.count() as u64);
+
+    let latency = &data.metrics.invalidation_latency;
+    let _ = writeln!(out, "# HELP pro_cache_invalidation_latency_ms Time from a measured invalidate to client receipt.");
+    let _ = writeln!(out, "# TYPE pro_cache_invalidation_latency_ms histogram");
+    let mut cumulative = 0;
+    for (le, bucket) in LATENCY_BUCKETS_MS.iter().zip(&latency.buckets) {
+        cumulative += bucket.load(Ordering::Relaxed);
+        let _ = writeln!(out, "pro_cache_invalidation_latency_ms_bucket{{le=\"{}\"}} {}", le, cumulative);
+    }
+    let count = latency.count.load(Ordering::Relaxed);
+    let _ = writeln!(out, "pro_cache_invalidation_latency_ms_bucket{{le=\"+Inf\"}} {}", count);
+    let _ = writeln!(out, "pro_cache_invalidation_latency_ms_sum {}", latency.sum_ms.load(Ordering::Relaxed));
+    let _ = writeln!(out, "pro_cache_invalidation_latency_ms_count {}", count);
+
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::app_state;
+
+    // Status and JSON body of a handler's response
+    async fn respond(responder: impl Responder) -> (StatusCode, serde_json::Value) {
+        let req = actix_web::test::TestRequest::default().to_http_request();
+        let res = responder.respond_to(&req).map_into_boxed_body();
+        let status = res.status();
+        let body = actix_web::body::to_bytes(res.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    // Registers a live session for `user_id` and returns the receiving end of its channel
+    fn open_session(data: &AppState, project_id: &str, user_id: &str) -> tokio::sync::mpsc::Receiver<String> {
+        let (session, receiver) = crate::state::SessionData::for_test(user_id, 16);
+        data.sessions.insert(project_id, uuid::Uuid::new_v4(), session);
+        receiver
+    }
+
+    // Lets the broadcaster tasks run the fan-outs queued so far
+    async fn settle() {
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+
+    async fn post_invalidate(data: &web::Data<AppState>, body: serde_json::Value) -> (StatusCode, serde_json::Value) {
+        respond(invalidate(data.clone(), web::Json(serde_json::from_value(body).unwrap())).await).await
+    }
+
+    #[actix_web::test]
+    async fn priority_message_overtaking_a_queued_one_is_replayed_in_order() {
+        let data = web::Data::new(app_state());
+        let _receiver = open_session(&data, "p", "u");
+        let buffer = data.replay.buffer("p", "u");
+
+        post_invalidate(&data, serde_json::json!({ "project_id": "p", "paths": ["/queued"] })).await;
+        post_invalidate(&data, serde_json::json!({ "project_id": "p", "paths": ["/priority"], "priority": "high" })).await;
+        // The priority message (seq 2) is recorded while seq 1 is still queued on the broadcaster
+        assert_eq!(buffer.lock().since(0, 2).unwrap().len(), 1);
+        settle().await;
+
+        let replayed = buffer.lock().since(0, 2).unwrap();
+        assert!(replayed[0].contains("/queued") && replayed[1].contains("/priority"));
+        // A client that saw seq 2 first may have missed seq 1
+        let replayed = buffer.lock().since(2, 2).unwrap();
+        assert_eq!(replayed.len(), 1);
+        assert!(replayed[0].contains("/queued"));
+    }
+
+    #[actix_web::test]
+    async fn dead_broadcaster_is_restarted() {
+        let data = web::Data::new(app_state());
+        let mut receiver = open_session(&data, "p", "u1");
+        // A broadcaster whose task has died
+        data.broadcasters.queue("p", || tokio::sync::mpsc::unbounded_channel().0);
+
+        let (status, _) = post_invalidate(&data, serde_json::json!({ "project_id": "p", "paths": ["/a"] })).await;
+        assert_eq!(status, StatusCode::OK);
+        settle().await;
+        assert!(receiver.try_recv().unwrap().contains("/a"));
+
+        post_invalidate(&data, serde_json::json!({ "project_id": "p", "paths": ["/b"] })).await;
+        settle().await;
+        assert!(receiver.try_recv().unwrap().contains("/b"));
+    }
+
+    #[actix_web::test]
+    async fn queued_fanout_is_recorded_sent_and_acked_by_the_broadcaster() {
+        let data = web::Data::new(app_state());
+        let mut receiver = open_session(&data, "p", "online");
+        let offline_buffer = data.replay.buffer("p", "offline");
+
+        let (status, body) = post_invalidate(&data, serde_json::json!({ "project_id": "p", "paths": ["/a"], "require_ack": true })).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["optimistic"], true);
+        assert_eq!(body["broadcast_count"], 0);
+        let ack_id = body["ack_id"].as_str().unwrap();
+        settle().await;
+
+        let message: serde_json::Value = serde_json::from_str(&receiver.try_recv().unwrap()).unwrap();
+        assert_eq!(message["ack_id"], ack_id);
+        assert!(message["data"].get("/a").is_some());
+        assert_eq!(offline_buffer.lock().since(0, data.replay.current_seq("p")).unwrap().len(), 1);
+        assert_eq!(data.acks.status(ack_id), Some((1, 0)));
+    }
+
+    #[actix_web::test]
+    async fn queued_invalidate_reports_whether_the_target_user_is_online() {
+        let data = web::Data::new(app_state());
+        let _receiver = open_session(&data, "p", "u1");
+
+        let (_, body) = post_invalidate(&data, serde_json::json!({ "project_id": "p", "paths": ["/a"], "user_id": "u1" })).await;
+        assert_eq!(body["target_online"], true);
+        let (_, body) = post_invalidate(&data, serde_json::json!({ "project_id": "p", "paths": ["/a"], "user_id": "u2" })).await;
+        assert_eq!(body["target_online"], false);
+    }
+
+    #[actix_web::test]
+    async fn rejected_requests_do_not_use_up_the_rate_limit() {
+        let mut state = app_state();
+        state.max_paths_per_request = 1;
+        let data = web::Data::new(state);
+        data.project_configs.insert("p".to_string(), crate::state::ProjectConfig { rate_limit: Some(1.0), ..Default::default() });
+
+        let (status, _) = post_invalidate(&data, serde_json::json!({ "project_id": "p", "paths": ["/a", "/b"] })).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        let (status, _) = post_invalidate(&data, serde_json::json!({ "project_id": "p" })).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, _) = post_invalidate(&data, serde_json::json!({ "project_id": "p", "paths": ["/a"] })).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = post_invalidate(&data, serde_json::json!({ "project_id": "p", "paths": ["/a"] })).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[actix_web::test]
+    async fn global_invalidation_is_charged_to_every_project() {
+        let data = web::Data::new(app_state());
+        data.routes.register("p", &["/a".to_string()]);
+        data.project_configs.insert("p".to_string(), crate::state::ProjectConfig { rate_limit: Some(1.0), ..Default::default() });
+
+        let (status, _) = post_invalidate(&data, serde_json::json!({ "project_id": "*", "paths": ["/a"] })).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, body) = post_invalidate(&data, serde_json::json!({ "project_id": "p", "paths": ["/a"] })).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(body["code"], "rate_limited");
+    }
+
+    #[actix_web::test]
+    async fn repeated_invalidations_are_counted_per_path() {
+        let data = web::Data::new(app_state());
+        for _ in 0..3 {
+            post_invalidate(&data, serde_json::json!({ "project_id": "p", "paths": ["/hot"] })).await;
+        }
+        post_invalidate(&data, serde_json::json!({ "project_id": "p", "paths": ["/hot", "/cold"] })).await;
+        post_invalidate(&data, serde_json::json!({ "project_id": "other", "paths": ["/hot"] })).await;
+
+        let top = |n| hot_paths(data.clone(), web::Query(HotPathsQuery { project_id: "p".to_string(), n: Some(n) }));
+        let (status, body) = respond(top(10).await).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["paths"], serde_json::json!([{ "path": "/hot", "count": 4 }, { "path": "/cold", "count": 1 }]));
+        assert_eq!(respond(top(1).await).await.1["paths"], serde_json::json!([{ "path": "/hot", "count": 4 }]));
+    }
+
+    #[actix_web::test]
+    async fn unmodified_paths_are_skipped() {
+        let data = web::Data::new(app_state());
+        post_invalidate(&data, serde_json::json!({ "project_id": "p", "paths": ["/a", "/b"] })).await;
+        let stored = data.invalidations.timestamp("p", "/a").unwrap();
+
+        // Another service reports the same change, as of a moment before it was invalidated
+        let (status, body) = post_invalidate(&data, serde_json::json!({
+            "project_id": "p",
+            "paths": ["/a", "/b"],
+            "if_modified_since": { "/a": stored - 1, "/b": stored + 60_000 },
+        })).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["skipped"], serde_json::json!(["/a"]));
+        assert_eq!(body["matched_paths"], serde_json::json!(["/b"]));
+        assert_eq!(data.invalidations.timestamp("p", "/a"), Some(stored));
+    }
+
+    #[actix_web::test]
+    async fn relayed_delivery_gets_a_local_seq_and_is_replayable() {
+        let data = web::Data::new(app_state());
+        data.clock.serialized(|| (0..3).for_each(|_| { data.replay.next_seq("p"); }));
+        let buffer = data.replay.buffer("p", "u");
+
+        apply_relayed(&data, RelayedDelivery {
+            origin: uuid::Uuid::new_v4(),
+            project_id: "p".to_string(),
+            message: serde_json::json!({ "type": "invalidate-delta", "data": { "/x": 1234 }, "seq": 99 }),
+            paths: Some(vec!["/x".to_string()]),
+            target_user: None,
+            exclude_users: Vec::new(),
+            priority: false,
+        });
+        settle().await;
+
+        assert_eq!(data.replay.current_seq("p"), 4);
+        assert_eq!(data.invalidations.timestamp("p", "/x"), Some(1234));
+        let replayed = buffer.lock().since(3, data.replay.current_seq("p")).unwrap();
+        assert_eq!(replayed.len(), 1);
+        let message: serde_json::Value = serde_json::from_str(&replayed[0]).unwrap();
+        assert_eq!(message["seq"], 4);
+    }
+
+    #[actix_web::test]
+    async fn debounced_flush_restarts_the_interval() {
+        let mut state = app_state();
+        let min_interval = std::time::Duration::from_millis(50);
+        state.path_min_interval = Some(min_interval);
+        let data = web::Data::new(state);
+        let path = vec!["/x".to_string()];
+
+        let (now, debounced) = data.staging.debounce("p", &path, min_interval);
+        assert_eq!((now.len(), debounced.len()), (1, 0));
+        let (now, debounced) = data.staging.debounce("p", &path, min_interval);
+        assert_eq!((now.len(), debounced.len()), (0, 1));
+
+        // The deferred invalidation is flushed once the interval has passed
+        std::thread::sleep(std::time::Duration::from_millis(60));
+        data.staging.stage("p", &path, 1);
+        flush_staged(&data, "p");
+        assert_eq!(data.replay.current_seq("p"), 1);
+
+        // Without the flush counting as a broadcast, the interval would have elapsed here
+        let (now, debounced) = data.staging.debounce("p", &path, min_interval);
+        assert_eq!((now.len(), debounced.len()), (0, 1));
+    }
+
+    #[test]
+    fn drift_recovery_leaves_other_projects_untouched() {
+        let data = app_state();
+        data.invalidations.set_timestamps("a", [("/a".to_string(), 100)]);
+        data.invalidations.set_timestamps("b", [("/b".to_string(), 200)]);
+
+        let drift = data.clock.serialized(|| start_drift_recovery(&data, &["a".to_string()]));
+        assert!(drift.covers("a") && !drift.covers("b"));
+
+        assert!(data.invalidations.timestamp("a", "/a").unwrap() > chrono::Utc::now().timestamp_millis());
+        assert_eq!(data.invalidations.timestamp("b", "/b"), Some(200));
+        assert_eq!(data.replay.current_seq("a"), 1);
+        assert_eq!(data.replay.current_seq("b"), 0);
+    }
+
+    #[test]
+    fn second_drift_within_cooldown_is_suppressed() {
+        let data = app_state();
+        data.invalidations.set_timestamps("a", [("/a".to_string(), 100)]);
+        let projects = ["a".to_string(), "b".to_string()];
+
+        let first = data.clock.serialized(|| start_drift_recovery(&data, &projects[..1]));
+        assert_eq!(first.reset.len(), 1);
+        assert!(drift_response(&data, &first)["status"] == "clock_reset");
+        let future_dated = data.invalidations.timestamp("a", "/a");
+
+        // a is still cooling down; b has never been reset
+        let second = data.clock.serialized(|| start_drift_recovery(&data, &projects));
+        assert_eq!(second.suppressed, ["a"]);
+        assert_eq!(second.reset.iter().map(|(p, _, _)| p.as_str()).collect::<Vec<_>>(), ["b"]);
+        assert_eq!(data.invalidations.timestamp("a", "/a"), future_dated);
+        assert_eq!(data.replay.current_seq("a"), 1);
+
+        let third = data.clock.serialized(|| start_drift_recovery(&data, &projects[..1]));
+        assert!(drift_response(&data, &third)["status"] == "clock_reset_suppressed");
+    }
+
+    #[actix_web::test]
+    async fn wildcard_invalidates_only_routes_under_its_prefix() {
+        let data = web::Data::new(app_state());
+        let routes: Vec<String> = ["/api/a", "/api/b/c", "/apix", "/other"].iter().map(|r| r.to_string()).collect();
+        data.routes.register("p", &routes);
+
+        let (status, body) = post_invalidate(&data, serde_json::json!({ "project_id": "p", "path": "/api/*" })).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["affected_paths"], 2);
+        let mut matched: Vec<&str> = body["matched_paths"].as_array().unwrap().iter().filter_map(|p| p.as_str()).collect();
+        matched.sort();
+        assert_eq!(matched, ["/api/a", "/api/b/c"]);
+        assert!(data.invalidations.timestamp("p", "/api/a").is_some());
+        assert!(data.invalidations.timestamp("p", "/api/b/c").is_some());
+        assert!(data.invalidations.timestamp("p", "/apix").is_none());
+        assert!(data.invalidations.timestamp("p", "/other").is_none());
+        assert!(data.invalidations.timestamp("p", "/api/*").is_none());
+    }
+
+    #[actix_web::test]
+    async fn wildcard_matching_nothing_affects_no_paths() {
+        let data = web::Data::new(app_state());
+        data.routes.register("p", &["/other".to_string()]);
+
+        let (status, body) = post_invalidate(&data, serde_json::json!({ "project_id": "p", "path": "/api/*" })).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["affected_paths"], 0);
+        assert_eq!(body["matched_paths"], serde_json::json!([]));
+
+        // A "*" that isn't a trailing "/*" is part of the path
+        post_invalidate(&data, serde_json::json!({ "project_id": "p", "path": "/files/a*b" })).await;
+        assert!(data.invalidations.timestamp("p", "/files/a*b").is_some());
+    }
+
+    #[test]
+    fn paths_are_normalized_consistently() {
+        let mut state = app_state();
+        let path = |state: &AppState, v: serde_json::Value| normalize_path(state, v);
+        assert_eq!(path(&state, serde_json::json!("/a")), "/a");
+        assert_eq!(path(&state, serde_json::json!("/a/")), "/a");
+        assert_eq!(path(&state, serde_json::json!("/")), "/");
+        assert_eq!(path(&state, serde_json::json!(42)), "42");
+        assert_eq!(path(&state, serde_json::json!(1.5)), "1.5");
+        assert_eq!(path(&state, serde_json::json!("/Users/")), "/Users");
+
+        state.lowercase_paths = true;
+        assert_eq!(path(&state, serde_json::json!("/Users/")), "/users");
+    }
+
+    #[actix_web::test]
+    async fn over_limit_paths_are_rejected_before_any_change() {
+        let mut state = app_state();
+        state.max_paths_per_request = 3;
+        let data = web::Data::new(state);
+        let paths: Vec<String> = (0..4).map(|i| format!("/p/{}", i)).collect();
+
+        let (status, body) = post_invalidate(&data, serde_json::json!({ "project_id": "p", "paths": paths })).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(body["code"], "too_many_paths");
+        assert_eq!(body["max_paths"], 3);
+        assert_eq!(data.routes.count(), 0);
+        assert!(data.invalidations.timestamps("p").is_empty());
+
+        let (status, _) = post_invalidate(&data, serde_json::json!({ "project_id": "p", "paths": &paths[..3] })).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn invalidate_all_flushes_every_route_and_wins_over_paths() {
+        let data = web::Data::new(app_state());
+        data.routes.register("p", &["/a".to_string(), "/b".to_string()]);
+        let mut session = open_session(&data, "p", "u");
+
+        let (status, body) = post_invalidate(&data, serde_json::json!({
+            "project_id": "p",
+            "all": true,
+            "paths": ["/unlisted"],
+            "wait_for_delivery": true
+        })).await;
+        assert_eq!(status, StatusCode::OK);
+        let timestamp = body["timestamp"].as_i64().unwrap();
+        assert_eq!(data.invalidations.timestamp("p", "/a"), Some(timestamp));
+        assert_eq!(data.invalidations.timestamp("p", "/b"), Some(timestamp));
+        assert!(data.invalidations.timestamp("p", "/unlisted").is_none());
+
+        let message: serde_json::Value = serde_json::from_str(&session.try_recv().unwrap()).unwrap();
+        assert_eq!(message["type"], "invalidate-all");
+        assert_eq!(message["data"]["timestamp"], timestamp);
+        assert!(session.try_recv().is_err());
+    }
+
+    #[actix_web::test]
+    async fn invalid_batch_entry_does_not_abort_the_batch() {
+        let data = web::Data::new(app_state());
+        data.tokens.register("old", crate::state::TokenData {
+            user_id: "u1".to_string(),
+            project_id: "p".to_string(),
+            created_at: Instant::now(),
+            ttl: 0,
+            one_time: false,
+        });
+        let batch = serde_json::json!([
+            { "token": "t1", "user_id": "u1", "project_id": "p" },
+            { "token": "t2", "user_id": "", "project_id": "p" },
+            { "token": "t3", "user_id": "u3", "project_id": "p", "ttl_human": "soon" }
+        ]);
+
+        let (status, body) = respond(register_batch(data.clone(), web::Json(serde_json::from_value(batch).unwrap())).await).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!((body["registered"].as_u64(), body["failed"].as_u64()), (Some(1), Some(2)));
+        assert_eq!(body["results"][0]["status"], "success");
+        assert_eq!(body["results"][0]["previous_token"], "old");
+        assert_eq!(body["results"][1]["status"], "error");
+        assert_eq!(body["results"][2]["user_id"], "u3");
+
+        assert!(data.tokens.get("t1").is_some());
+        assert!(data.tokens.get("old").is_none());
+        assert!(data.tokens.get("t2").is_none() && data.tokens.get("t3").is_none());
+    }
+
+    #[actix_web::test]
+    async fn disconnect_removes_only_the_targeted_session() {
+        let data = web::Data::new(app_state());
+        let (target, mut target_rx) = crate::state::SessionData::for_test("u1", 4);
+        let target_id = data.sessions.insert("p", uuid::Uuid::new_v4(), target).unwrap();
+        let mut bystander_rx = open_session(&data, "p", "u2");
+
+        let req = serde_json::json!({ "project_id": "p", "session_id": target_id });
+        let (status, body) = respond(disconnect(data.clone(), web::Json(serde_json::from_value(req).unwrap())).await).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["disconnected"], 1);
+        assert_eq!(data.sessions.project_count("p"), 1);
+
+        // The session is told why, then its queue closes, which ends its task
+        let message: serde_json::Value = serde_json::from_str(&target_rx.recv().await.unwrap()).unwrap();
+        assert_eq!(message["type"], "disconnected");
+        assert!(target_rx.recv().await.is_none());
+        assert!(bystander_rx.try_recv().is_err());
+    }
+
+    #[actix_web::test]
+    async fn disconnect_without_a_target_is_rejected() {
+        let data = web::Data::new(app_state());
+        let _session = open_session(&data, "p", "u");
+
+        let req = serde_json::json!({ "project_id": "p" });
+        let (status, body) = respond(disconnect(data.clone(), web::Json(serde_json::from_value(req).unwrap())).await).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["code"], "missing_target");
+        assert_eq!(data.sessions.project_count("p"), 1);
+    }
+
+    fn assert_error_shape(body: &serde_json::Value, code: &str) {
+        assert_eq!(body["status"], "error");
+        assert_eq!(body["code"], code);
+        assert!(body["message"].as_str().is_some_and(|m| !m.is_empty()));
+    }
+
+    #[actix_web::test]
+    async fn failures_have_the_json_error_shape() {
+        let data = web::Data::new(app_state());
+
+        let (status, body) = post_invalidate(&data, serde_json::json!({ "project_id": "p" })).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_error_shape(&body, "missing_paths");
+
+        let req = serde_json::json!({ "token": "", "user_id": "u", "project_id": "p" });
+        let (status, body) = respond(register_token(data.clone(), web::Json(serde_json::from_value(req).unwrap())).await).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_error_shape(&body, "invalid_request");
+    }
+
+    #[test]
+    fn human_ttls_are_parsed_by_unit() {
+        assert_eq!(parse_human_ttl("90s"), Ok(90));
+        assert_eq!(parse_human_ttl("30m"), Ok(30 * 60));
+        assert_eq!(parse_human_ttl("24h"), Ok(24 * 60 * 60));
+        assert_eq!(parse_human_ttl(" 7d "), Ok(7 * 24 * 60 * 60));
+        for invalid in ["", "h", "10", "10w", "-5m", "1.5h", "99999999999999999999d"] {
+            assert!(parse_human_ttl(invalid).is_err(), "{:?} was accepted", invalid);
+        }
+    }
+
+    #[actix_web::test]
+    async fn numeric_ttl_wins_and_bad_human_ttl_is_rejected() {
+        let data = web::Data::new(app_state());
+        let register = |token: &str, ttl: Option<u64>, ttl_human: &str| {
+            let req = serde_json::json!({ "token": token, "user_id": token, "project_id": "p", "ttl": ttl, "ttl_human": ttl_human });
+            register_token(data.clone(), web::Json(serde_json::from_value(req).unwrap()))
+        };
+
+        assert_eq!(respond(register("a", Some(120), "7d").await).await.0, StatusCode::OK);
+        assert_eq!(data.tokens.get("a").map(|t| t.ttl), Some(120));
+        assert_eq!(respond(register("b", None, "30m").await).await.0, StatusCode::OK);
+        assert_eq!(data.tokens.get("b").map(|t| t.ttl), Some(1800));
+        assert_eq!(respond(register("c", None, "soon").await).await.0, StatusCode::BAD_REQUEST);
+        assert!(data.tokens.get("c").is_none());
+    }
+
+    #[actix_web::test]
+    async fn excluded_user_is_skipped() {
+        let data = web::Data::new(app_state());
+        let mut alice = open_session(&data, "p", "alice");
+        let mut bob = open_session(&data, "p", "bob");
+        let mut carol = open_session(&data, "p", "carol");
+
+        let (_, body) = post_invalidate(&data, serde_json::json!({
+            "project_id": "p",
+            "path": "/x",
+            "exclude_user_ids": ["bob"],
+            "wait_for_delivery": true
+        })).await;
+        assert_eq!(body["broadcast_count"], 2);
+        assert!(alice.try_recv().is_ok());
+        assert!(bob.try_recv().is_err());
+        assert!(carol.try_recv().is_ok());
+
+        // An include filter takes precedence over the exclusions
+        post_invalidate(&data, serde_json::json!({
+            "project_id": "p",
+            "path": "/y",
+            "user_id": "bob",
+            "exclude_user_ids": ["bob"],
+            "wait_for_delivery": true
+        })).await;
+        assert!(alice.try_recv().is_err());
+        assert!(bob.try_recv().is_ok());
+        assert!(carol.try_recv().is_err());
+    }
+
+    #[actix_web::test]
+    async fn flush_empties_the_projects_state_and_notifies_its_sessions() {
+        let data = web::Data::new(app_state());
+        data.routes.register("p", &["/a".to_string()]);
+        data.invalidations.set_timestamps("p", [("/a".to_string(), 100)]);
+        data.invalidations.set_timestamps("other", [("/a".to_string(), 100)]);
+        let mut session = open_session(&data, "p", "u");
+
+        let req = serde_json::json!({ "project_id": "p" });
+        let (status, body) = respond(flush(data.clone(), web::Json(serde_json::from_value(req).unwrap())).await).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["notified"], 1);
+        assert!(data.invalidations.timestamps("p").is_empty());
+        assert_eq!(data.invalidations.timestamp("other", "/a"), Some(100));
+        // Nothing is future-dated: the route syncs at the baseline again
+        assert!(data.routes.contains("p", "/a"));
+        assert_eq!(data.compute_initial_sync("p")["/a"], data.clock.baseline());
+
+        let message: serde_json::Value = serde_json::from_str(&session.try_recv().unwrap()).unwrap();
+        assert_eq!(message["type"], "flush");
+    }
+
+    #[actix_web::test]
+    async fn cascade_reaches_child_projects_only() {
+        let data = web::Data::new(app_state());
+        let mut parent = open_session(&data, "acme", "u");
+        let mut web_child = open_session(&data, "acme/web", "u");
+        let mut mobile_child = open_session(&data, "acme/mobile", "u");
+        let mut sibling = open_session(&data, "acmex", "u");
+
+        // Opt-in: without cascade only the named project is reached
+        post_invalidate(&data, serde_json::json!({ "project_id": "acme", "path": "/x", "wait_for_delivery": true })).await;
+        assert!(parent.try_recv().is_ok());
+        assert!(web_child.try_recv().is_err() && mobile_child.try_recv().is_err());
+
+        let (_, body) = post_invalidate(&data, serde_json::json!({
+            "project_id": "acme",
+            "path": "/y",
+            "cascade": true,
+            "wait_for_delivery": true
+        })).await;
+        assert_eq!(body["broadcast_count"], 3);
+        assert!(parent.try_recv().is_ok());
+        assert!(web_child.try_recv().is_ok());
+        assert!(mobile_child.try_recv().is_ok());
+        assert!(sibling.try_recv().is_err());
+        assert!(data.invalidations.timestamp("acme/web", "/y").is_some());
+    }
+
+    #[actix_web::test]
+    async fn revoking_a_project_drops_its_tokens_and_sessions() {
+        let data = web::Data::new(app_state());
+        for (token, user_id, project_id) in [("t1", "u1", "p"), ("t2", "u2", "p"), ("t3", "u1", "other")] {
+            data.tokens.register(token, crate::state::TokenData {
+                user_id: user_id.to_string(),
+                project_id: project_id.to_string(),
+                created_at: Instant::now(),
+                ttl: 0,
+                one_time: false,
+            });
+        }
+        let mut session = open_session(&data, "p", "u1");
+        let mut other_session = open_session(&data, "other", "u1");
+
+        let req = serde_json::json!({ "project_id": "p" });
+        let (status, body) = respond(revoke_project(data.clone(), web::Json(serde_json::from_value(req).unwrap())).await).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!((body["revoked"].as_u64(), body["disconnected"].as_u64()), (Some(2), Some(1)));
+        assert!(data.tokens.get("t1").is_none() && data.tokens.get("t2").is_none());
+        assert!(data.tokens.active_token_of("p", "u1").is_none());
+        assert!(data.tokens.get("t3").is_some());
+
+        let message: serde_json::Value = serde_json::from_str(&session.try_recv().unwrap()).unwrap();
+        assert_eq!(message["type"], "token-revoked");
+        assert!(other_session.try_recv().is_err());
+        assert_eq!(data.sessions.project_count("other"), 1);
+    }
+
+    #[actix_web::test]
+    async fn plain_paths_are_matched_as_normalized() {
+        let data = web::Data::new(app_state());
+        let (_, body) = post_invalidate(&data, serde_json::json!({ "project_id": "p", "paths": ["/a/", 7, "/a"] })).await;
+        assert_eq!(body["matched_paths"], serde_json::json!(["/a", "7"]));
+    }
+
+    #[test]
+    fn internal_key_must_match_exactly() {
+        assert!(internal_key_matches("s3cret", "s3cret"));
+        assert!(!internal_key_matches("s3cret", "s3cres"));
+        assert!(!internal_key_matches("s3cret", "s3cre"));
+        assert!(!internal_key_matches("s3cret", ""));
+    }
+
+    #[actix_web::test]
+    async fn peek_returns_the_active_token_or_404() {
+        let mut state = app_state();
+        state.internal_api_key = Some("secret".to_string());
+        let data = web::Data::new(state);
+        let req = serde_json::json!({ "token": "t1", "user_id": "u1", "project_id": "p", "ttl": 120 });
+        respond(register_token(data.clone(), web::Json(serde_json::from_value(req).unwrap())).await).await;
+        let peek = |user_id: &str, key: Option<&str>| {
+            let mut req = actix_web::test::TestRequest::default();
+            if let Some(key) = key {
+                req = req.insert_header(("X-Internal-Key", key));
+            }
+            let query = UserTokenQuery { project_id: "p".to_string(), user_id: user_id.to_string() };
+            peek_token(req.to_http_request(), data.clone(), web::Query(query))
+        };
+
+        let (status, body) = respond(peek("u1", Some("secret")).await).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["token"], "t1");
+        assert_eq!(body["ttl"], 120);
+        assert!(body["expires_in"].as_u64().is_some_and(|s| s <= 120));
+
+        let (status, body) = respond(peek("u2", Some("secret")).await).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_error_shape(&body, "token_not_found");
+
+        // The credential is never handed out without the key
+        assert_eq!(respond(peek("u1", None).await).await.0, StatusCode::FORBIDDEN);
+        assert_eq!(respond(peek("u1", Some("wrong")).await).await.0, StatusCode::FORBIDDEN);
+    }
+}
@@ -1,42 +1,160 @@
 mod handlers;
+mod protocol;
+mod relay;
 mod state;
+mod store;
+#[cfg(test)]
+mod test_support;
 mod ws;
 
 use actix_web::{web, App, HttpServer, middleware};
-use actix_web::dev::Service;
+use actix_web::body::MessageBody;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::http::header::{HeaderName, HeaderValue};
 use futures_util::future::{ok, Either};
 use state::AppState;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
+    // TRACING=1 swaps env_logger for tracing-subscriber, adding the invalidate/session spans to log lines
+    if std::env::var("TRACING").is_ok_and(|v| v == "1" || v == "true") {
+        init_tracing()?;
+    } else {
+        env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
+    }
 
-    let state = web::Data::new(AppState::new());
+    let state = web::Data::new(AppState::new(Box::new(store::FileStore::from_env())));
+
+    // Cross-instance relay: deliveries made here reach clients connected to other instances (off unless configured)
+    if let Some(url) = &state.redis_url {
+        relay::start(state.clone(), url)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Invalid REDIS_URL: {}", e)))?;
+    }
+
+    // Background sweeper: evict expired tokens so the token store doesn't grow forever
+    let sweeper_state = state.clone();
+    actix_rt::spawn(async move {
+        let mut interval = tokio::time::interval(sweeper_state.token_sweep_interval);
+        loop {
+            interval.tick().await;
+            let reaped = sweeper_state.sweep_expired_tokens();
+            log::info!("[Sweeper] Reaped {} expired tokens ({} remaining)", reaped, sweeper_state.tokens.count());
+            sweeper_state.acks.sweep_expired();
+        }
+    });
+
+    // Idle reaper: close sessions that stay connected but never talk to us (off unless configured)
+    if let Some(max_idle) = state.idle_session_timeout {
+        let reaper_state = state.clone();
+        actix_rt::spawn(async move {
+            let mut interval = tokio::time::interval((max_idle / 4).max(std::time::Duration::from_secs(1)));
+            loop {
+                interval.tick().await;
+                let reaped = reaper_state.sessions.reap_idle(max_idle);
+                if reaped > 0 {
+                    log::info!("[Reaper] Closed {} sessions idle for over {:?}", reaped, max_idle);
+                }
+            }
+        });
+    }
+
+    let public_bind = bind_from_env("PUBLIC_BIND", "0.0.0.0:8080")?;
+    let internal_bind = bind_from_env("INTERNAL_BIND", "127.0.0.1:8081")?;
+
+    // Optional TLS for the public listener (fails fast if configured but unloadable)
+    let tls_config = load_tls_config()?;
 
     log::info!("Starting pro_cache_backend...");
-    log::info!("Internal API listening on 127.0.0.1:8081");
-    log::info!("Public WS listening on 0.0.0.0:8080");
+    log::info!("Internal API listening on {}", internal_bind);
+    log::info!("Public WS listening on {}{}", public_bind, if tls_config.is_some() { " (TLS)" } else { "" });
+
+    // Browser origins allowed on the public listener (ALLOWED_ORIGINS, comma-separated)
+    let allowed_origins: Option<Vec<String>> = std::env::var("ALLOWED_ORIGINS").ok().map(|v| {
+        v.split(',').map(|o| o.trim().to_string()).filter(|o| !o.is_empty()).collect()
+    });
+    match &allowed_origins {
+        Some(origins) => log::info!("CORS: allowing origins {:?}", origins),
+        None => log::warn!("CORS: ALLOWED_ORIGINS not set, allowing any origin (development only)"),
+    }
 
-    HttpServer::new(move || {
+    // Public server: only the WebSocket endpoint
+    let public_state = state.clone();
+    let public_server = HttpServer::new(move || {
         App::new()
-            .app_data(state.clone())
-            .wrap(actix_cors::Cors::permissive())
+            .app_data(public_state.clone())
+            .wrap(cors(allowed_origins.as_deref()))
             .wrap(middleware::Logger::default())
             // Public WebSocket Endpoint
             .route("/ws", web::get().to(ws::ws_handler))
-            // Internal API Handlers (Protected by being on local interface in production via separate bind if desired)
-            // Ideally we separate them completely, but for "simple" project, route separation is fine.
-            // Using a scope for clarity
+            // Liveness/readiness probe for load balancers
+            .route("/health", web::get().to(handlers::health))
+    })
+    .disable_signals(); // We handle signals ourselves to notify clients and flush state first
+
+    let public_server = match tls_config {
+        Some(config) => public_server.bind_rustls_0_23(public_bind, config)?, // Public access (TLS)
+        None => public_server.bind(public_bind)?, // Public access
+    }
+    .run();
+
+    // Internal server: a separate listener so the internal routes are unreachable from the public port.
+    // The wrap_fn check stays as defense-in-depth.
+    let internal_state = state.clone();
+    let internal_server = HttpServer::new(move || {
+        App::new()
+            .app_data(internal_state.clone())
+            .app_data(web::JsonConfig::default().limit(INTERNAL_JSON_LIMIT))
+            .app_data(web::PayloadConfig::new(INTERNAL_JSON_LIMIT)) // Signature checks buffer the raw body
+            // Structured access log + X-Request-Id for correlating calls with their effects
+            .wrap_fn(|req, srv| {
+                let request_id = uuid::Uuid::new_v4().to_string();
+                let method = req.method().to_string();
+                let path = req.path().to_string();
+                let started = std::time::Instant::now();
+                let fut = srv.call(req);
+                async move {
+                    let mut res = fut.await?;
+                    if let Ok(value) = HeaderValue::from_str(&request_id) {
+                        res.headers_mut().insert(HeaderName::from_static("x-request-id"), value);
+                    }
+                    log::info!(target: "access", "{}", serde_json::json!({
+                        "request_id": request_id,
+                        "method": method,
+                        "path": path,
+                        "status": res.status().as_u16(),
+                        "duration_ms": started.elapsed().as_secs_f64() * 1000.0
+                    }));
+                    Ok(res)
+                }
+            })
             .service(
                 web::scope("/internal")
+                    // Runs after the access check below (the last wrap is the outermost)
+                    .wrap(middleware::from_fn(verify_signature))
                     .wrap_fn(|req, srv| {
-                        let is_local = req.peer_addr().map_or(false, |addr| {
+                        let is_local = req.peer_addr().is_some_and(|addr| {
                             let ip = addr.ip();
                             ip.is_loopback() || ip.to_string() == "127.0.0.1" || ip.to_string() == "::1"
                         });
 
-                        if is_local {
+                        // Non-local callers may authenticate with X-Internal-Key if INTERNAL_API_KEY is set
+                        let expected_key = req.app_data::<web::Data<AppState>>()
+                            .and_then(|s| s.internal_api_key.clone());
+                        let provided_key = req.headers().get("X-Internal-Key")
+                            .and_then(|v| v.to_str().ok());
+                        let key_valid = match (&expected_key, provided_key) {
+                            (Some(expected), Some(provided)) => handlers::internal_key_matches(expected, provided),
+                            _ => false,
+                        };
+
+                        if is_local || key_valid {
                             Either::Left(srv.call(req))
+                        } else if expected_key.is_some() && provided_key.is_some() {
+                            // Key supplied but wrong: say so, to make misconfigurations debuggable
+                            log::warn!("[Security] Invalid internal API key from: {:?}", req.peer_addr());
+                            let res = req.into_response(actix_web::HttpResponse::Unauthorized().finish());
+                            Either::Right(ok(res.map_into_boxed_body()))
                         } else {
                             // Return nothing/NotFound to pretend it doesn't exist
                             log::warn!("[Security] Blocking non-local internal access from: {:?}", req.peer_addr());
@@ -45,11 +163,260 @@ async fn main() -> std::io::Result<()> {
                         }
                     })
                     .route("/auth/register", web::post().to(handlers::register_token))
+                    .route("/auth/register_batch", web::post().to(handlers::register_batch))
+                    .route("/auth/verify", web::get().to(handlers::verify_token))
+                    .route("/auth/token", web::get().to(handlers::peek_token))
+                    .route("/auth/revoke_project", web::post().to(handlers::revoke_project))
                     .route("/invalidate", web::post().to(handlers::invalidate))
+                    .route("/route", web::get().to(handlers::route_status))
+                    .route("/routes", web::delete().to(handlers::remove_routes))
+                    .route("/tags", web::post().to(handlers::tag_paths))
+                    .route("/dependencies", web::post().to(handlers::add_dependency))
+                    .route("/projects", web::get().to(handlers::list_projects))
+                    .route("/sessions/{project_id}", web::get().to(handlers::list_sessions))
+                    .route("/disconnect", web::post().to(handlers::disconnect))
+                    .route("/flush", web::post().to(handlers::flush))
+                    .route("/acks/{ack_id}", web::get().to(handlers::ack_status))
+                    .route("/events", web::get().to(handlers::events))
+                    .route("/drift", web::get().to(handlers::drift))
+                    .route("/hotpaths", web::get().to(handlers::hot_paths))
+                    .route("/stats", web::get().to(handlers::stats))
+                    .route("/metrics", web::get().to(handlers::metrics))
+                    .route("/version", web::get().to(handlers::version))
             )
     })
-    .bind(("0.0.0.0", 8080))? // Public access
-    .bind(("127.0.0.1", 8081))? // Internal access (could be same port but separate is cleaner for firewall rules)
-    .run()
-    .await
+    .bind(internal_bind)? // Internal access only
+    .disable_signals()
+    .run();
+
+    actix_rt::spawn(shutdown_on_signal(
+        state.clone(),
+        vec![public_server.handle(), internal_server.handle()],
+    ));
+
+    futures_util::future::try_join(public_server, internal_server).await?;
+    Ok(())
+}
+
+// Permissive when no allowlist is configured, otherwise only the listed origins
+fn cors(allowed_origins: Option<&[String]>) -> actix_cors::Cors {
+    let Some(origins) = allowed_origins else {
+        return actix_cors::Cors::permissive();
+    };
+    origins
+        .iter()
+        .fold(actix_cors::Cors::default(), |cors, origin| cors.allowed_origin(origin))
+        .allowed_methods(["GET"])
+        .allow_any_header()
+        .max_age(3600)
+}
+
+// Optional request signing for the internal API. With INTERNAL_HMAC_SECRET set, callers send
+// X-Timestamp (unix seconds) and X-Signature = hex(HMAC-SHA256(secret, canonical)), where canonical is
+// "{timestamp}\n{METHOD}\n{path}\n{body}": the uppercase method, and the path as sent including
+// its query string if any (e.g. "/internal/auth/verify?token=abc"), so a signature is only valid for
+// the endpoint it was made for. Timestamps outside HMAC_MAX_SKEW_SECS are rejected so captured
+// requests can't be replayed later.
+async fn verify_signature(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse, actix_web::Error> {
+    let config = req.app_data::<web::Data<AppState>>()
+        .and_then(|s| s.hmac_secret.clone().map(|secret| (secret, s.hmac_max_skew)));
+    let Some((secret, max_skew)) = config else {
+        return next.call(req).await.map(|res| res.map_into_boxed_body());
+    };
+
+    let header = |name: &str| req.headers().get(name).and_then(|v| v.to_str().ok()).map(str::to_string);
+    let (timestamp, signature) = (header("X-Timestamp"), header("X-Signature"));
+    let method = req.method().as_str().to_string();
+    let target = req.uri().path_and_query().map_or_else(|| req.path().to_string(), |pq| pq.as_str().to_string());
+    let body = req.extract::<web::Bytes>().await?;
+    let signed = SignedRequest { method: &method, target: &target, timestamp: timestamp.as_deref() };
+
+    if let Err(message) = check_signature(&secret, max_skew, &signed, signature.as_deref(), &body) {
+        log::warn!("[Security] Rejecting internal request from {:?}: {}", req.peer_addr(), message);
+        let res = handlers::error_response(actix_web::http::StatusCode::UNAUTHORIZED, "invalid_signature", message);
+        return Ok(req.into_response(res).map_into_boxed_body());
+    }
+
+    // The body was consumed for verification; hand it back to the handler
+    req.set_payload(body.into());
+    next.call(req).await.map(|res| res.map_into_boxed_body())
+}
+
+// The parts of a request its signature covers besides the body
+struct SignedRequest<'a> {
+    method: &'a str,
+    target: &'a str, // Path and query
+    timestamp: Option<&'a str>,
+}
+
+fn check_signature(
+    secret: &str,
+    max_skew: std::time::Duration,
+    request: &SignedRequest,
+    signature: Option<&str>,
+    body: &[u8],
+) -> Result<(), &'static str> {
+    use hmac::{KeyInit, Mac};
+
+    let (Some(timestamp), Some(signature)) = (request.timestamp, signature) else {
+        return Err("X-Timestamp and X-Signature are required");
+    };
+    let sent_at: i64 = timestamp.parse().map_err(|_| "X-Timestamp must be unix seconds")?;
+    state::validate_timestamp(sent_at, max_skew).map_err(|_| "X-Timestamp is outside the allowed skew")?;
+    let signature = hex::decode(signature).map_err(|_| "X-Signature must be hex")?;
+
+    let mut mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(secret.as_bytes()).map_err(|_| "Invalid HMAC secret")?;
+    for part in [timestamp, request.method, request.target] {
+        mac.update(part.as_bytes());
+        mac.update(b"\n");
+    }
+    mac.update(body);
+    mac.verify_slice(&signature).map_err(|_| "Signature mismatch")
+}
+
+// Max JSON body size accepted by the internal API
+const INTERNAL_JSON_LIMIT: usize = 1024 * 1024;
+
+// Structured logging via tracing (filtered by RUST_LOG like env_logger); `log` records
+// are bridged in so existing log lines keep showing up, inside their spans
+fn init_tracing() -> std::io::Result<()> {
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info"));
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter).finish();
+    tracing::subscriber::set_global_default(subscriber).map_err(std::io::Error::other)?;
+    tracing_log::LogTracer::init().map_err(std::io::Error::other)
+}
+
+// Reads a host:port from `var`, falling back to `default` when unset. Hostnames are fine
+// (e.g. localhost:8080); the value is resolved here so a bad one is reported by name at startup.
+fn bind_from_env(var: &str, default: &str) -> std::io::Result<String> {
+    use std::net::ToSocketAddrs;
+
+    let value = std::env::var(var).unwrap_or_else(|_| default.to_string());
+    let invalid = |reason: String| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Invalid {} '{}' (expected host:port): {}", var, value, reason),
+        )
+    };
+    let mut addrs = value.to_socket_addrs().map_err(|e| invalid(e.to_string()))?;
+    if addrs.next().is_none() {
+        return Err(invalid("resolves to no addresses".to_string()));
+    }
+    Ok(value)
+}
+
+// Builds a rustls config from the PEM files in TLS_CERT / TLS_KEY. None when neither is set.
+fn load_tls_config() -> std::io::Result<Option<rustls::ServerConfig>> {
+    use rustls::pki_types::pem::PemObject;
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+
+    let invalid = |msg: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, msg);
+
+    let (cert_path, key_path) = match (std::env::var("TLS_CERT").ok(), std::env::var("TLS_KEY").ok()) {
+        (None, None) => return Ok(None),
+        (Some(cert), Some(key)) => (cert, key),
+        _ => return Err(invalid("TLS_CERT and TLS_KEY must be set together".to_string())),
+    };
+
+    let certs = CertificateDer::pem_file_iter(&cert_path)
+        .and_then(|iter| iter.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| invalid(format!("Failed to load TLS_CERT '{}': {}", cert_path, e)))?;
+    if certs.is_empty() {
+        return Err(invalid(format!("No certificates found in TLS_CERT '{}'", cert_path)));
+    }
+    let key = PrivateKeyDer::from_pem_file(&key_path)
+        .map_err(|e| invalid(format!("Failed to load TLS_KEY '{}': {}", key_path, e)))?;
+
+    rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map(Some)
+        .map_err(|e| invalid(format!("Invalid TLS certificate/key pair: {}", e)))
+}
+
+// How long we give the session tasks to flush the shutdown message before stopping
+const SHUTDOWN_GRACE: std::time::Duration = std::time::Duration::from_secs(2);
+
+async fn shutdown_on_signal(state: web::Data<AppState>, handles: Vec<actix_web::dev::ServerHandle>) {
+    #[cfg(unix)]
+    {
+        let mut sigterm = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(s) => s,
+            Err(e) => {
+                log::error!("[Shutdown] Failed to install SIGTERM handler: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+                return graceful_shutdown(state, handles).await;
+            }
+        };
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = sigterm.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+
+    graceful_shutdown(state, handles).await;
+}
+
+async fn graceful_shutdown(state: web::Data<AppState>, handles: Vec<actix_web::dev::ServerHandle>) {
+    log::info!("[Shutdown] Signal received, notifying clients and flushing state...");
+
+    let shutdown_msg = protocol::OutgoingMessage::Shutdown.to_json();
+    let notified = state.sessions.notify_all(&shutdown_msg);
+
+    state.save_routes();
+    state.save_invalidations();
+
+    log::info!("[Shutdown] Notified {} sessions, waiting {:?} for delivery", notified, SHUTDOWN_GRACE);
+    tokio::time::sleep(SHUTDOWN_GRACE).await;
+
+    for handle in handles {
+        handle.stop(true).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sign(secret: &str, canonical: &str) -> String {
+        use hmac::{KeyInit, Mac};
+        let mut mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(canonical.as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    #[test]
+    fn signature_is_bound_to_the_method_and_path() {
+        let skew = std::time::Duration::from_secs(300);
+        let now = chrono::Utc::now().timestamp().to_string();
+        let body = br#"{"project_id":"p"}"#;
+        let signature = sign("k", &format!("{}\nPOST\n/internal/flush\n{}", now, std::str::from_utf8(body).unwrap()));
+        let request = |method, target| SignedRequest { method, target, timestamp: Some(now.as_str()) };
+
+        assert_eq!(check_signature("k", skew, &request("POST", "/internal/flush"), Some(&signature), body), Ok(()));
+        assert!(check_signature("k", skew, &request("POST", "/internal/auth/revoke_project"), Some(&signature), body).is_err());
+        assert!(check_signature("k", skew, &request("GET", "/internal/flush"), Some(&signature), body).is_err());
+        assert!(check_signature("k", skew, &request("POST", "/internal/flush?x=1"), Some(&signature), body).is_err());
+    }
+
+    #[test]
+    fn bind_addresses_may_use_hostnames() {
+        std::env::set_var("TEST_BIND_HOSTNAME", "localhost:8080");
+        assert_eq!(bind_from_env("TEST_BIND_HOSTNAME", "0.0.0.0:1").unwrap(), "localhost:8080");
+        assert_eq!(bind_from_env("TEST_BIND_UNSET", "127.0.0.1:8081").unwrap(), "127.0.0.1:8081");
+    }
+
+    #[test]
+    fn bad_bind_address_is_reported_by_name() {
+        std::env::set_var("TEST_BIND_NO_PORT", "localhost");
+        let err = bind_from_env("TEST_BIND_NO_PORT", "0.0.0.0:1").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("TEST_BIND_NO_PORT 'localhost'"), "{}", err);
+    }
 }
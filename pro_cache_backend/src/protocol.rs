@@ -0,0 +1,141 @@
+use serde::Serialize;
+use uuid::Uuid;
+
+/// Every frame the server sends to clients; `type` is the variant's tag.
+/// Route values in `data` are a bare timestamp, or `{ "timestamp", "etag" }` for versioned routes.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum OutgoingMessage {
+    /// Initial invalidation state (on connect and `resync`). `partial`/`since_ts` mark a sync that
+    /// only lists routes changed since the client's last one; `all` marks one collapsed to a single timestamp.
+    #[serde(rename = "invalidate")]
+    Sync {
+        data: serde_json::Map<String, serde_json::Value>,
+        drift_time: i64,
+        seq: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        partial: Option<bool>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        since_ts: Option<i64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        all: Option<i64>,
+    },
+    /// Clock drift recovery: same type as a sync, with empty `data` (clients drop everything)
+    #[serde(rename = "invalidate")]
+    DriftReset {
+        data: Empty,
+        drift_time: i64,
+        seq: u64,
+    },
+    InvalidateDelta {
+        data: serde_json::Map<String, serde_json::Value>,
+        drift_time: i64,
+        seq: u64,
+    },
+    InvalidateAll {
+        data: InvalidateAllData,
+        drift_time: i64,
+        seq: u64,
+    },
+    Flush {
+        drift_time: i64,
+        seq: u64,
+    },
+    /// Answer to a client's `ping-state`
+    State {
+        seq: u64,
+        drift_time: i64,
+        server_time: i64,
+    },
+    #[serde(rename = "ws-status")]
+    Status {
+        status: &'static str,
+        session_id: Uuid,
+        server_time: i64,
+    },
+    #[serde(rename = "server-shutdown")]
+    Shutdown,
+    TokenRevoked,
+    Disconnected,
+    SessionIdle,
+    SessionEvicted,
+}
+
+/// Serializes as `{}`
+#[derive(Debug, Clone, Serialize)]
+pub struct Empty {}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct InvalidateAllData {
+    pub timestamp: i64,
+}
+
+impl OutgoingMessage {
+    pub fn to_value(&self) -> Result<serde_json::Value, serde_json::Error> {
+        serde_json::to_value(self)
+    }
+
+    /// The frame as sent on the wire (keys sorted, as `serde_json::Value` writes them).
+    /// Only for control frames, whose fields are all plain values; syncs and deltas carry
+    /// route data and go through `to_value` so a failure can be handled.
+    pub fn to_json(&self) -> String {
+        self.to_value().map(|v| v.to_string()).expect("control frames always serialize")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn wire(msg: OutgoingMessage) -> serde_json::Value {
+        msg.to_value().unwrap()
+    }
+
+    fn routes(value: serde_json::Value) -> serde_json::Map<String, serde_json::Value> {
+        value.as_object().unwrap().clone()
+    }
+
+    #[test]
+    fn sync_and_drift_reset_share_the_invalidate_shape() {
+        let sync = OutgoingMessage::Sync {
+            data: routes(json!({ "/a": 10 })),
+            drift_time: 5,
+            seq: 2,
+            partial: None,
+            since_ts: None,
+            all: None,
+        };
+        assert_eq!(wire(sync), json!({ "type": "invalidate", "data": { "/a": 10 }, "drift_time": 5, "seq": 2 }));
+
+        let reset = OutgoingMessage::DriftReset { data: Empty {}, drift_time: 5, seq: 3 };
+        assert_eq!(wire(reset), json!({ "type": "invalidate", "data": {}, "drift_time": 5, "seq": 3 }));
+    }
+
+    #[test]
+    fn live_invalidations_keep_their_shapes() {
+        let delta = OutgoingMessage::InvalidateDelta { data: routes(json!({ "/a": 10 })), drift_time: 0, seq: 4 };
+        assert_eq!(wire(delta), json!({ "type": "invalidate-delta", "data": { "/a": 10 }, "drift_time": 0, "seq": 4 }));
+
+        let all = OutgoingMessage::InvalidateAll { data: InvalidateAllData { timestamp: 99 }, drift_time: 0, seq: 5 };
+        assert_eq!(wire(all), json!({ "type": "invalidate-all", "data": { "timestamp": 99 }, "drift_time": 0, "seq": 5 }));
+
+        let flush = OutgoingMessage::Flush { drift_time: 0, seq: 6 };
+        assert_eq!(wire(flush), json!({ "type": "flush", "drift_time": 0, "seq": 6 }));
+    }
+
+    #[test]
+    fn control_frames_keep_their_type_strings() {
+        let session_id = Uuid::nil();
+        let status = OutgoingMessage::Status { status: "connected", session_id, server_time: 7 };
+        assert_eq!(
+            wire(status),
+            json!({ "type": "ws-status", "status": "connected", "session_id": session_id, "server_time": 7 })
+        );
+        assert_eq!(OutgoingMessage::Shutdown.to_json(), r#"{"type":"server-shutdown"}"#);
+        assert_eq!(OutgoingMessage::TokenRevoked.to_json(), r#"{"type":"token-revoked"}"#);
+        assert_eq!(OutgoingMessage::Disconnected.to_json(), r#"{"type":"disconnected"}"#);
+        assert_eq!(OutgoingMessage::SessionIdle.to_json(), r#"{"type":"session-idle"}"#);
+        assert_eq!(OutgoingMessage::SessionEvicted.to_json(), r#"{"type":"session-evicted"}"#);
+    }
+}
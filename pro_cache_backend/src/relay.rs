@@ -0,0 +1,90 @@
+use actix_web::web;
+use futures_util::StreamExt as _;
+use redis::AsyncCommands as _;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+use crate::state::AppState;
+
+// Pub/sub channel every instance publishes its deliveries to and listens on
+const CHANNEL: &str = "pro_cache:deliveries";
+
+// Pause before reconnecting after the Redis connection fails
+const RECONNECT_DELAY: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// One delivery made by an instance, replayed by the others to their own sessions.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RelayedDelivery {
+    pub origin: Uuid, // Instance that made the delivery (it doesn't apply its own)
+    pub project_id: String,
+    pub message: serde_json::Value,
+    pub paths: Option<Vec<String>>,
+    pub target_user: Option<String>,
+    #[serde(default)]
+    pub exclude_users: Vec<String>,
+    #[serde(default)]
+    pub priority: bool, // Sent straight to the priority lanes rather than queued
+}
+
+/// Connects this instance to the other instances behind REDIS_URL: local deliveries are
+/// published (see `AppState::relay_delivery`) and deliveries from other instances are
+/// applied to local sessions.
+pub fn start(state: web::Data<AppState>, url: &str) -> redis::RedisResult<()> {
+    let client = redis::Client::open(url)?;
+    let (tx, rx) = mpsc::unbounded_channel();
+    state.broadcasters.set_relay(tx);
+
+    actix_rt::spawn(publish_loop(client.clone(), rx));
+    actix_rt::spawn(subscribe_loop(client, state));
+    Ok(())
+}
+
+// Publishes queued deliveries, reconnecting as needed. Deliveries that fail to publish are
+// dropped: other instances' clients catch up on their next sync.
+async fn publish_loop(client: redis::Client, mut rx: mpsc::UnboundedReceiver<String>) {
+    let mut conn = None;
+    while let Some(payload) = rx.recv().await {
+        if conn.is_none() {
+            match client.get_multiplexed_async_connection().await {
+                Ok(c) => conn = Some(c),
+                Err(e) => {
+                    log::warn!("[Relay] Failed to connect to Redis, dropping a delivery: {}", e);
+                    continue;
+                }
+            }
+        }
+        if let Some(c) = conn.as_mut() {
+            if let Err(e) = c.publish::<_, _, ()>(CHANNEL, payload).await {
+                log::warn!("[Relay] Failed to publish a delivery: {}", e);
+                conn = None;
+            }
+        }
+    }
+}
+
+// Applies deliveries published by other instances, resubscribing whenever the connection drops.
+async fn subscribe_loop(client: redis::Client, state: web::Data<AppState>) {
+    loop {
+        match client.get_async_pubsub().await {
+            Ok(mut pubsub) => match pubsub.subscribe(CHANNEL).await {
+                Ok(()) => {
+                    log::info!("[Relay] Subscribed to {} as instance {}", CHANNEL, state.instance_id);
+                    let mut messages = pubsub.on_message();
+                    while let Some(msg) = messages.next().await {
+                        let relayed = msg.get_payload::<String>().ok()
+                            .and_then(|payload| serde_json::from_str::<RelayedDelivery>(&payload).ok());
+                        match relayed {
+                            Some(relayed) if relayed.origin == state.instance_id => {}
+                            Some(relayed) => crate::handlers::apply_relayed(&state, relayed),
+                            None => log::warn!("[Relay] Ignoring malformed message on {}", CHANNEL),
+                        }
+                    }
+                    log::warn!("[Relay] Lost the Redis subscription; reconnecting");
+                }
+                Err(e) => log::warn!("[Relay] Failed to subscribe to {}: {}", CHANNEL, e),
+            },
+            Err(e) => log::warn!("[Relay] Failed to connect to Redis: {}", e),
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
@@ -1,9 +1,51 @@
 use dashmap::DashMap;
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
 use tokio::sync::mpsc;
 use uuid::Uuid;
+use std::collections::HashMap;
 use std::time::Instant;
+use crate::store::StateStore;
+
+mod acks;
+mod broadcast;
+mod clock;
+mod events;
+mod invalidation;
+mod metrics;
+mod rate_limit;
+mod replay;
+mod routes;
+mod sessions;
+mod staging;
+mod tokens;
+
+pub use acks::AckTracker;
+pub use broadcast::{Broadcasters, FanoutJob};
+pub use clock::ClockTracker;
+pub use events::EventLog;
+pub use invalidation::InvalidationStore;
+pub use metrics::{Metrics, LATENCY_BUCKETS_MS};
+pub use rate_limit::RateLimiter;
+pub use replay::ReplayLog;
+pub use routes::RouteRegistry;
+pub use sessions::{SessionData, SessionRegistry};
+pub use staging::DeltaStaging;
+pub use tokens::{TokenData, TokenStore};
+
+const DEFAULT_PROJECTS_FILE: &str = "projects.json"; // Overridden by PROJECTS_FILE
+
+// Requested token TTLs are clamped to this (30 days); 0 still means "never expires"
+pub const MAX_TOKEN_TTL: u64 = 30 * 24 * 60 * 60;
+
+/// Canonical form of a route: a single trailing slash is stripped (except for root "/"),
+/// and the path is lowercased if `lowercase` is set.
+pub fn canonical_path(path: &str, lowercase: bool) -> String {
+    let path = match path.strip_suffix('/') {
+        Some(stripped) if !stripped.is_empty() => stripped,
+        _ => path,
+    };
+    if lowercase { path.to_lowercase() } else { path.to_string() }
+}
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct RegisterTokenRequest {
@@ -11,88 +53,380 @@ pub struct RegisterTokenRequest {
     pub user_id: String,
     pub project_id: String,
     pub ttl: Option<u64>, // Time to live in seconds
+    pub ttl_human: Option<String>, // e.g. "30m", "24h", "7d"; used when `ttl` is absent
+    pub nonce: Option<String>, // Single-use value guarding against replayed registrations
+    pub timestamp: Option<i64>, // Unix seconds the request was made at; required with `nonce`
+    pub one_time: Option<bool>, // Consumed by the first successful WS connect (default: reusable until it expires)
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct InvalidateRequest {
+    pub project_id: Option<String>,
+    pub project_ids: Option<Vec<String>>, // Same paths applied to several projects at once
+    #[serde(default)]
+    pub cascade: bool, // Also target child projects ("acme" -> "acme/web", "acme/mobile", ...)
+    pub path: Option<serde_json::Value>, // Accepts String or Number
+    pub paths: Option<Vec<serde_json::Value>>, // Accepts Array of Strings or Numbers
+    pub user_id: Option<String>,
+    pub exclude_user_ids: Option<Vec<String>>, // Skipped when no `user_id` is given (e.g. the user who made the change)
+    pub tags: Option<Vec<String>>, // Resolved to the paths associated with each tag
+    pub all: Option<bool>, // Invalidate every route of the project (takes precedence over paths/tags)
+    #[serde(default)]
+    pub require_ack: bool, // Stamp the broadcast with an ack_id and track client acks
+    pub per_user: Option<Vec<UserPaths>>, // Extra paths invalidated only for specific users
+    pub etags: Option<HashMap<String, String>>, // Path -> version, lets clients revalidate instead of purging
+    pub if_modified_since: Option<HashMap<String, i64>>, // Path -> ms; skipped if already invalidated at or after it
+    #[serde(default)]
+    pub wait_for_delivery: bool, // Fan out before responding so broadcast counts are exact (default: queued)
+    #[serde(default)]
+    pub measure: bool, // Stamp broadcasts with `sent_at` so clients can report delivery latency
+    #[serde(default)]
+    pub dry_run: bool, // Report what would be affected without mutating state or broadcasting
+    #[serde(default)]
+    pub priority: Priority,
+}
+
+/// `"high"` skips coalescing, the debounce and the broadcaster queue, and uses each session's
+/// priority lane. It can therefore overtake routine messages sent just before it (clients see
+/// its seq early); timestamps still order correctly, so it's only meant for must-deliver-now purges.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    #[default]
+    Normal,
+    High,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct UserPaths {
+    pub user_id: String,
+    pub paths: Vec<serde_json::Value>, // Accepts Strings or Numbers
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TagPathsRequest {
     pub project_id: String,
+    pub tag: String,
+    pub paths: Vec<serde_json::Value>, // Accepts Strings or Numbers
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DependencyRequest {
+    pub path: serde_json::Value, // Exact path, or a "/prefix/*" wildcard
+    pub dependents: Vec<serde_json::Value>, // Paths to invalidate whenever `path` is
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RemoveRoutesRequest {
+    pub project_id: Option<String>, // Only remove from this project (default: every project)
     pub path: Option<serde_json::Value>, // Accepts String or Number
     pub paths: Option<Vec<serde_json::Value>>, // Accepts Array of Strings or Numbers
+    #[serde(default)]
+    pub purge_invalidations: bool, // Also drop the routes from the affected projects' invalidation state
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DisconnectRequest {
+    pub project_id: String,
+    pub session_id: Option<Uuid>,
     pub user_id: Option<String>,
 }
 
-#[derive(Debug, Clone)]
-pub struct TokenData {
+#[derive(Debug, Deserialize)]
+pub struct FlushRequest {
+    pub project_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RevokeProjectRequest {
+    pub project_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TokenQuery {
+    pub token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UserTokenQuery {
+    pub project_id: String,
     pub user_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RouteQuery {
     pub project_id: String,
-    pub created_at: Instant,
-    pub ttl: u64,
+    pub path: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EventsQuery {
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HotPathsQuery {
+    pub project_id: String,
+    pub n: Option<usize>, // How many of the most invalidated paths to return (default 10)
+}
+
+/// Per-project overrides of the global defaults; unset fields fall back to them.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ProjectConfig {
+    pub default_ttl: Option<u64>, // Seconds, for tokens registered without a TTL (0 = never expires)
+    pub max_sessions: Option<usize>,
+    pub rate_limit: Option<f64>, // Invalidate requests per second (also the burst size)
+}
+
+// Per-project overrides from PROJECTS_FILE (default projects.json), e.g.
+// `{ "acme": { "default_ttl": 3600, "max_sessions": 500, "rate_limit": 10 } }`.
+// A missing file means no overrides; an unreadable one is logged and ignored.
+fn load_project_configs() -> DashMap<String, ProjectConfig> {
+    let file = std::env::var("PROJECTS_FILE").ok()
+        .filter(|f| !f.is_empty())
+        .unwrap_or_else(|| DEFAULT_PROJECTS_FILE.to_string());
+    let Ok(content) = std::fs::read_to_string(&file) else {
+        return DashMap::new();
+    };
+    match serde_json::from_str::<HashMap<String, ProjectConfig>>(&content) {
+        Ok(configs) => {
+            log::info!("Loaded configuration for {} projects from {}", configs.len(), file);
+            configs.into_iter().collect()
+        }
+        Err(e) => {
+            log::warn!("Ignoring {}: {}", file, e);
+            DashMap::new()
+        }
+    }
+}
+
+/// Rejects a client-supplied unix timestamp (seconds) more than `max_skew` away from server time,
+/// in either direction, so absurd values can't skew anything derived from them.
+pub fn validate_timestamp(timestamp: i64, max_skew: std::time::Duration) -> Result<(), String> {
+    let skew = chrono::Utc::now().timestamp().abs_diff(timestamp);
+    if skew > max_skew.as_secs() {
+        return Err(format!("timestamp {} is {}s away from server time (max {}s)", timestamp, skew, max_skew.as_secs()));
+    }
+    Ok(())
+}
+
+// 0 is passed through untouched (never expires); anything else is capped at MAX_TOKEN_TTL
+fn clamp_ttl(ttl: u64) -> u64 {
+    if ttl == 0 { 0 } else { ttl.min(MAX_TOKEN_TTL) }
+}
+
+// The websocket codec rejects frames over 64KB before we see them, so WS_MAX_FRAME_BYTES can't go higher
+const WS_CODEC_MAX_FRAME: usize = 64 * 1024;
+
+// Unset means the codec limit; anything above it is capped there, with a warning since it can't take effect
+fn clamp_ws_frame_size(configured: Option<usize>) -> usize {
+    match configured {
+        Some(size) if size > WS_CODEC_MAX_FRAME => {
+            log::warn!("WS_MAX_FRAME_BYTES {} exceeds the websocket codec limit; using {}", size, WS_CODEC_MAX_FRAME);
+            WS_CODEC_MAX_FRAME
+        }
+        Some(size) => size,
+        None => WS_CODEC_MAX_FRAME,
+    }
 }
 
 #[derive(Debug)]
 pub struct AppState {
-    // Token -> TokenData
-    pub pending_tokens: DashMap<String, TokenData>,
-    
-    // ProjectID -> { SessionID -> SessionData }
-    pub active_sessions: DashMap<String, DashMap<Uuid, SessionData>>,
+    pub tokens: TokenStore,
+    pub sessions: SessionRegistry,
+    pub invalidations: InvalidationStore,
+    pub clock: ClockTracker,
+    pub routes: RouteRegistry,
+    pub replay: ReplayLog,
+    pub staging: DeltaStaging,
+    pub acks: AckTracker,
+    pub rate_limiter: RateLimiter,
+    pub events: EventLog,
+    pub metrics: Metrics,
+    pub broadcasters: Broadcasters,
 
-    // (ProjectID, UserID) -> Token
-    // Used to find and invalidate old tokens when a user re-logins
-    pub user_tokens: DashMap<(String, String), String>,
+    // How far a client-supplied timestamp may be from server time (MAX_CLIENT_SKEW_SECS)
+    pub max_client_skew: std::time::Duration,
 
-    // ProjectID -> { RoutePath -> Timestamp }
-    // Stores the latest invalidation timestamp for each route in a project
-    pub project_invalidation_state: DashMap<String, DashMap<String, i64>>,
+    // Initial syncs with more routes than this are sent as a single `all` timestamp (MAX_SYNC_ROUTES); None = always enumerate
+    pub max_sync_routes: Option<usize>,
 
-    // Global set of known routes, persisted to routes.json
-    pub known_routes: DashMap<String, ()>,
-    
-    // Last global timestamp received to detect clock drift (parking_lot for better performance)
-    pub last_global_timestamp: parking_lot::Mutex<i64>,
+    // Send the `ws-status` connected frame before the initial sync instead of after it (STATUS_BEFORE_SYNC)
+    pub status_before_sync: bool,
 
-    // Last time a clock drift was detected (or server start time)
-    pub last_drift_timestamp: std::sync::atomic::AtomicI64,
+    // Answer WS auth failures with a bare 404 instead of 401 + reason (WS_STEALTH)
+    pub ws_stealth: bool,
 
-    // Stable timestamp of when the server started
-    pub server_start_time: i64,
-}
+    // TTL in seconds for tokens registered without one (DEFAULT_TOKEN_TTL, default 24 hours)
+    pub default_token_ttl: u64,
 
-#[derive(Debug, Clone)]
-pub struct SessionData {
-    pub user_id: String,
-    pub sender: mpsc::UnboundedSender<String>,
+    // ProjectID -> overrides of the TTL/limit defaults, loaded from PROJECTS_FILE at startup
+    pub project_configs: DashMap<String, ProjectConfig>,
+
+    // Lowercase paths during normalization (LOWERCASE_PATHS=1)
+    pub lowercase_paths: bool,
+
+    // Persistence for known routes, invalidation timestamps and the baseline
+    pub store: Box<dyn StateStore + Send + Sync>,
+
+    // Serializes saves to `store` (see `save_routes`)
+    save_lock: parking_lot::Mutex<()>,
+
+    // How often the background sweeper evicts expired tokens (TOKEN_SWEEP_SECS, default 60; 0 is ignored)
+    pub token_sweep_interval: std::time::Duration,
+
+    // Shared secret for the internal API (INTERNAL_API_KEY). None = loopback-only.
+    pub internal_api_key: Option<String>,
+
+    // When set (INTERNAL_HMAC_SECRET), every internal request must carry a valid X-Signature
+    pub hmac_secret: Option<String>,
+
+    // Max age/skew of a signed request's X-Timestamp (HMAC_MAX_SKEW_SECS, default 300)
+    pub hmac_max_skew: std::time::Duration,
+
+    // Sustained invalidate requests per second per project (INVALIDATE_RATE_LIMIT); also the burst size
+    pub invalidate_rate_limit: f64,
+
+    // Max concurrent sessions per (project, user) (MAX_SESSIONS_PER_USER); oldest are evicted
+    pub max_sessions_per_user: usize,
+
+    // Max concurrent sessions per project (MAX_SESSIONS_PER_PROJECT); further upgrades are refused
+    pub max_sessions_per_project: usize,
+
+    // Max number of paths accepted by a single invalidate call (MAX_PATHS_PER_REQUEST)
+    pub max_paths_per_request: usize,
+
+    // Close sessions whose client sent nothing for this long (IDLE_SESSION_TIMEOUT_SECS); None = never
+    pub idle_session_timeout: Option<std::time::Duration>,
+
+    // Per-session outgoing queue size (SESSION_CHANNEL_CAPACITY); a session that falls this far behind is dropped
+    pub session_channel_capacity: usize,
+
+    // Largest text/binary frame accepted from a client (WS_MAX_FRAME_BYTES, at most WS_CODEC_MAX_FRAME);
+    // bigger ones close the session.
+    pub ws_max_frame_size: usize,
+
+    // Time after connecting before the first ping and before heartbeat/idle timeouts start counting
+    // (HEARTBEAT_GRACE_SECS), so clients on slow links can finish setting up
+    pub heartbeat_grace: std::time::Duration,
+
+    // Origin of the monotonic `sent_at` stamps
+    pub started_at: Instant,
+
+    // Coalescing window for broadcasts (COALESCE_WINDOW_MS); None = broadcast immediately
+    pub coalesce_window: Option<std::time::Duration>,
+
+    // Minimum time between broadcasts of the same path (PATH_MIN_INTERVAL_MS); None = no debounce
+    pub path_min_interval: Option<std::time::Duration>,
+
+    // Identifies this instance on the Redis relay; Redis URL to relay deliveries through (REDIS_URL)
+    pub instance_id: Uuid,
+    pub redis_url: Option<String>,
 }
 
 impl AppState {
-    pub fn new() -> self::AppState {
-        let known_routes = DashMap::new();
-        let project_invalidation_state = DashMap::new();
-        let server_start_time = chrono::Utc::now().timestamp_millis();
-        
-        // Load routes from routes.json if exists
-        if let Ok(content) = std::fs::read_to_string("routes.json") {
-            if let Ok(routes) = serde_json::from_str::<Vec<String>>(&content) {
-                for r in routes {
-                    known_routes.insert(r.clone(), ());
-                    
-                    // The user wants these to be sent to frontend on restart with current timestamp
-                    // We don't know the projects yet, so we can't pre-populate project_invalidation_state
-                    // unless we assume a default project or just handle it in ws.rs when a project connects.
-                }
-                log::info!("Loaded {} routes from routes.json", known_routes.len());
+    pub fn new(store: Box<dyn StateStore + Send + Sync>) -> self::AppState {
+        let lowercase_paths = std::env::var("LOWERCASE_PATHS").is_ok_and(|v| v == "1" || v == "true");
+        let clock = ClockTracker::from_env(store.as_ref());
+
+        // Load persisted routes, if any
+        let routes = match store.load_routes() {
+            Some(projects) => {
+                let routes = RouteRegistry::from_snapshot(projects, lowercase_paths);
+                log::info!("Loaded routes for {} projects", routes.projects().len());
+                routes
             }
-        }
+            None => RouteRegistry::default(),
+        };
+
+        // Load per-project invalidation timestamps, if any
+        let invalidations = match store.load_invalidations() {
+            Some(projects) => {
+                let invalidations = InvalidationStore::from_snapshot(projects, lowercase_paths);
+                log::info!("Loaded invalidation state for {} projects", invalidations.projects().len());
+                invalidations
+            }
+            None => InvalidationStore::default(),
+        };
 
         let state = AppState {
-            pending_tokens: DashMap::new(),
-            active_sessions: DashMap::new(),
-            user_tokens: DashMap::new(),
-            project_invalidation_state,
-            known_routes,
-            last_global_timestamp: parking_lot::Mutex::new(0),
-            last_drift_timestamp: std::sync::atomic::AtomicI64::new(server_start_time),
-            server_start_time,
+            tokens: TokenStore::from_env(),
+            sessions: SessionRegistry::default(),
+            invalidations,
+            clock,
+            routes,
+            replay: ReplayLog::default(),
+            staging: DeltaStaging::default(),
+            acks: AckTracker::default(),
+            rate_limiter: RateLimiter::default(),
+            events: EventLog::default(),
+            metrics: Metrics::default(),
+            broadcasters: Broadcasters::default(),
+            max_client_skew: std::time::Duration::from_secs(
+                std::env::var("MAX_CLIENT_SKEW_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(300),
+            ),
+            max_sync_routes: std::env::var("MAX_SYNC_ROUTES").ok().and_then(|v| v.parse().ok()),
+            status_before_sync: std::env::var("STATUS_BEFORE_SYNC").is_ok_and(|v| v == "1" || v == "true"),
+            ws_stealth: std::env::var("WS_STEALTH").is_ok_and(|v| v == "1" || v == "true"),
+            default_token_ttl: clamp_ttl(
+                std::env::var("DEFAULT_TOKEN_TTL").ok().and_then(|v| v.parse().ok()).unwrap_or(86400),
+            ),
+            project_configs: load_project_configs(),
+            lowercase_paths,
+            store,
+            save_lock: parking_lot::Mutex::new(()),
+            token_sweep_interval: std::time::Duration::from_secs(
+                std::env::var("TOKEN_SWEEP_SECS").ok()
+                    .and_then(|v| v.parse().ok())
+                    .filter(|secs: &u64| *secs > 0)
+                    .unwrap_or(60),
+            ),
+            internal_api_key: std::env::var("INTERNAL_API_KEY").ok().filter(|k| !k.is_empty()),
+            hmac_secret: std::env::var("INTERNAL_HMAC_SECRET").ok().filter(|k| !k.is_empty()),
+            hmac_max_skew: std::time::Duration::from_secs(
+                std::env::var("HMAC_MAX_SKEW_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(300),
+            ),
+            invalidate_rate_limit: std::env::var("INVALIDATE_RATE_LIMIT").ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|r: &f64| *r > 0.0)
+                .unwrap_or(50.0),
+            max_paths_per_request: std::env::var("MAX_PATHS_PER_REQUEST").ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|n: &usize| *n > 0)
+                .unwrap_or(1000),
+            idle_session_timeout: std::env::var("IDLE_SESSION_TIMEOUT_SECS").ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|secs: &u64| *secs > 0)
+                .map(std::time::Duration::from_secs),
+            session_channel_capacity: std::env::var("SESSION_CHANNEL_CAPACITY").ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|n: &usize| *n > 0)
+                .unwrap_or(256),
+            ws_max_frame_size: clamp_ws_frame_size(
+                std::env::var("WS_MAX_FRAME_BYTES").ok().and_then(|v| v.parse().ok()).filter(|n: &usize| *n > 0),
+            ),
+            heartbeat_grace: std::time::Duration::from_secs(
+                std::env::var("HEARTBEAT_GRACE_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(5),
+            ),
+            started_at: Instant::now(),
+            coalesce_window: std::env::var("COALESCE_WINDOW_MS").ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|ms: &u64| *ms > 0)
+                .map(std::time::Duration::from_millis),
+            path_min_interval: std::env::var("PATH_MIN_INTERVAL_MS").ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|ms: &u64| *ms > 0)
+                .map(std::time::Duration::from_millis),
+            instance_id: Uuid::new_v4(),
+            redis_url: std::env::var("REDIS_URL").ok().filter(|u| !u.is_empty()),
+            max_sessions_per_project: std::env::var("MAX_SESSIONS_PER_PROJECT").ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|n: &usize| *n > 0)
+                .unwrap_or(usize::MAX),
+            max_sessions_per_user: std::env::var("MAX_SESSIONS_PER_USER").ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|n: &usize| *n > 0)
+                .unwrap_or(usize::MAX),
         };
 
         // For first project ever or on restart, we can't pre-touch projects,
@@ -101,10 +435,349 @@ impl AppState {
         state
     }
 
+    /// TTL to store for a registration: the requested one (clamped) or the project's default
+    /// (its configured one, else DEFAULT_TOKEN_TTL).
+    pub fn resolve_ttl(&self, project_id: &str, requested: Option<u64>) -> u64 {
+        requested
+            .or_else(|| self.project_configs.get(project_id).and_then(|c| c.default_ttl))
+            .map(clamp_ttl)
+            .unwrap_or(self.default_token_ttl)
+    }
+
+    /// Max concurrent sessions for `project_id`: its configured limit, else MAX_SESSIONS_PER_PROJECT.
+    pub fn max_sessions_for(&self, project_id: &str) -> usize {
+        self.project_configs.get(project_id)
+            .and_then(|c| c.max_sessions)
+            .filter(|n| *n > 0)
+            .unwrap_or(self.max_sessions_per_project)
+    }
+
+    /// Invalidate rate for `project_id`: its configured one, else INVALIDATE_RATE_LIMIT.
+    pub fn rate_limit_for(&self, project_id: &str) -> f64 {
+        self.project_configs.get(project_id)
+            .and_then(|c| c.rate_limit)
+            .filter(|r| *r > 0.0)
+            .unwrap_or(self.invalidate_rate_limit)
+    }
+
+    /// Removes expired tokens (and their replay buffers). Returns how many tokens were reaped.
+    pub fn sweep_expired_tokens(&self) -> usize {
+        let expired = self.tokens.sweep_expired();
+        for token_data in &expired {
+            self.replay.drop_buffer(&token_data.project_id, &token_data.user_id);
+        }
+        expired.len()
+    }
+
+    /// Charges one invalidate request to each of `project_ids` at its `rate_limit_for`
+    /// (see `RateLimiter::allow`). Returns the project that was rate limited.
+    pub fn allow_invalidate(&self, project_ids: &[String]) -> Result<(), String> {
+        self.rate_limiter.allow(project_ids, |project_id| self.rate_limit_for(project_id))
+    }
+
+    /// Route -> timestamp map sent to a client of `project_id` on connect/resync.
+    /// Every known route is seeded at `baseline_timestamp`, then the project's own invalidation
+    /// timestamps are overlaid (max wins). Because the baseline survives restarts, a route nobody
+    /// invalidated keeps the same value across boots and clients that synced it keep their cache;
+    /// only routes in the (persisted) invalidation overlay move forward. A reset baseline is newer
+    /// than every client's copy, so it purges everything once.
+    /// Routes with an etag are sent as `{ "timestamp", "etag" }` instead of a bare timestamp.
+    pub fn compute_initial_sync(&self, project_id: &str) -> serde_json::Map<String, serde_json::Value> {
+        let mut routes: HashMap<String, i64> = self.routes.project_routes(project_id)
+            .into_iter()
+            .map(|r| (r, self.clock.baseline()))
+            .collect();
+
+        for (path, invalidated_at) in self.invalidations.timestamps(project_id) {
+            let ts = routes.entry(path).or_insert(invalidated_at);
+            *ts = (*ts).max(invalidated_at);
+        }
+        routes
+            .into_iter()
+            .map(|(path, ts)| {
+                let value = self.route_value(project_id, &path, ts);
+                (path, value)
+            })
+            .collect()
+    }
+
+    /// Wire value for a route in `invalidate`/`invalidate-delta` data: the timestamp, or
+    /// `{ "timestamp", "etag" }` when the route has an etag.
+    pub fn route_value(&self, project_id: &str, path: &str, timestamp: i64) -> serde_json::Value {
+        match self.invalidations.etag(project_id, path) {
+            Some(etag) => serde_json::json!({ "timestamp": timestamp, "etag": etag }),
+            None => serde_json::json!(timestamp),
+        }
+    }
+
+    /// Every project we hold routes, state, sessions or replay buffers for.
+    pub fn known_projects(&self) -> std::collections::HashSet<String> {
+        let mut projects: std::collections::HashSet<String> = self.invalidations.projects().into_iter().collect();
+        projects.extend(self.routes.projects());
+        projects.extend(self.sessions.projects());
+        projects.extend(self.replay.projects());
+        projects
+    }
+
+    /// Incident response: revokes every token of `project_id` (grace-period ones included, with no
+    /// new grace) and drops the project's sessions, which all used one of them, after sending `token-revoked`.
+    /// Tokens are keyed by value, so this scans all of them: O(total tokens).
+    /// Returns (tokens revoked, sessions disconnected).
+    pub fn revoke_project_tokens(&self, project_id: &str) -> (usize, usize) {
+        let revoked = self.tokens.revoke_project(project_id);
+        // Buffered messages would let a fresh token resume where a revoked one left off; start clean
+        self.replay.drop_project(project_id);
+        (revoked, self.sessions.revoke_all(project_id))
+    }
+
+    /// Hands a local delivery to the relay so other instances send it to their sessions too.
+    /// A no-op unless the relay is running.
+    pub fn relay_delivery(
+        &self,
+        project_id: &str,
+        message: &serde_json::Value,
+        paths: Option<&[String]>,
+        target_user: Option<&String>,
+        exclude_users: &[String],
+        priority: bool,
+    ) {
+        let Some(relay) = self.broadcasters.relay() else {
+            return;
+        };
+        let relayed = crate::relay::RelayedDelivery {
+            origin: self.instance_id,
+            project_id: project_id.to_string(),
+            message: message.clone(),
+            paths: paths.map(<[String]>::to_vec),
+            target_user: target_user.cloned(),
+            exclude_users: exclude_users.to_vec(),
+            priority,
+        };
+        if let Ok(payload) = serde_json::to_string(&relayed) {
+            let _ = relay.send(payload);
+        }
+    }
+
+    /// Queues `msg` for a session. If its queue is full the client isn't keeping up, so the session
+    /// is dropped instead (closing the socket; the client reconnects and resyncs). Returns whether it was queued.
+    /// Must not be called while holding a guard on the project's session map.
+    pub fn send_or_evict(&self, project_id: &str, session_id: Uuid, sender: &mpsc::Sender<String>, msg: String) -> bool {
+        match sender.try_send(msg) {
+            Ok(()) => true,
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                log::warn!("[WS] Session {} in project {} is not keeping up; dropping it", session_id, project_id);
+                self.sessions.evict(project_id, session_id);
+                self.metrics.slow_consumer_evictions_total.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                false
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => false,
+        }
+    }
+
+    /// Milliseconds since startup: the clock behind `sent_at` (unaffected by wall-clock jumps).
+    pub fn monotonic_ms(&self) -> u64 {
+        self.started_at.elapsed().as_millis() as u64
+    }
+
+    /// Records the latency a client reported for a `sent_at` stamp. Stamps from the
+    /// future (e.g. issued before a restart) are ignored.
+    pub fn record_latency(&self, sent_at: u64) {
+        let now = self.monotonic_ms();
+        if sent_at <= now {
+            self.metrics.invalidation_latency.observe(now - sent_at);
+        }
+    }
+
+    // Both saves hold `save_lock` from snapshot to write, so concurrent requests persist one at a
+    // time and an older snapshot can't overwrite a newer one.
     pub fn save_routes(&self) {
-        let routes: Vec<String> = self.known_routes.iter().map(|r| r.key().clone()).collect();
-        if let Ok(json) = serde_json::to_string_pretty(&routes) {
-            let _ = std::fs::write("routes.json", json);
+        let _guard = self.save_lock.lock();
+        if let Err(e) = self.store.save_routes(&self.routes.snapshot()) {
+            log::warn!("Failed to persist routes: {}", e);
+        }
+    }
+
+    pub fn save_invalidations(&self) {
+        let _guard = self.save_lock.lock();
+        if let Err(e) = self.store.save_invalidations(&self.invalidations.snapshot()) {
+            log::warn!("Failed to persist invalidations: {}", e);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+    use crate::test_support::app_state;
+
+    // A new AppState over what `data` has stored, as after a restart
+    fn restarted(data: &AppState) -> AppState {
+        let store = MemoryStore::default();
+        *store.invalidations.lock() = data.store.load_invalidations();
+        *store.baseline.lock() = data.store.load_baseline();
+        AppState::new(Box::new(store))
+    }
+
+    #[test]
+    fn invalidations_are_limited_at_the_projects_configured_rate() {
+        let data = app_state();
+        data.project_configs.insert("p".to_string(), ProjectConfig { rate_limit: Some(3.0), ..Default::default() });
+        let p = vec!["p".to_string()];
+        for _ in 0..3 {
+            assert!(data.allow_invalidate(&p).is_ok());
+        }
+        assert_eq!(data.allow_invalidate(&p), Err("p".to_string()));
+    }
+
+    #[test]
+    fn invalidation_timestamps_survive_a_restart() {
+        let data = app_state();
+        let baseline = data.clock.baseline();
+        data.invalidations.set_timestamps("p", [("/a".to_string(), baseline + 500), ("/old".to_string(), baseline - 500)]);
+        data.save_invalidations();
+
+        let restarted = restarted(&data);
+
+        assert_eq!(restarted.invalidations.timestamp("p", "/a"), Some(baseline + 500));
+        // Timestamps older than the baseline are kept as-is
+        assert_eq!(restarted.invalidations.timestamp("p", "/old"), Some(baseline - 500));
+    }
+
+    #[test]
+    fn initial_sync_without_routes_is_empty() {
+        assert!(app_state().compute_initial_sync("p").is_empty());
+    }
+
+    #[test]
+    fn initial_sync_seeds_known_routes_at_the_baseline() {
+        let data = app_state();
+        data.routes.register("p", &["/a".to_string(), "/b".to_string()]);
+
+        let sync = data.compute_initial_sync("p");
+        assert_eq!(sync.len(), 2);
+        assert_eq!(sync["/a"], data.clock.baseline());
+        assert_eq!(sync["/b"], data.clock.baseline());
+    }
+
+    #[test]
+    fn initial_sync_overlays_newer_invalidations() {
+        let data = app_state();
+        let baseline = data.clock.baseline();
+        data.routes.register("p", &["/a".to_string(), "/b".to_string()]);
+        data.invalidations.set_timestamps("p", [("/a".to_string(), baseline + 100), ("/b".to_string(), baseline - 100)]);
+
+        let sync = data.compute_initial_sync("p");
+        assert_eq!(sync["/a"], baseline + 100);
+        // An invalidation older than the baseline doesn't move the route back
+        assert_eq!(sync["/b"], baseline);
+        assert!(data.compute_initial_sync("other").is_empty());
+    }
+
+    #[test]
+    fn registrations_without_ttl_get_the_default() {
+        let mut data = app_state();
+        data.default_token_ttl = 600;
+        assert_eq!(data.resolve_ttl("p", None), 600);
+        assert_eq!(data.resolve_ttl("p", Some(30)), 30);
+
+        data.project_configs.insert("custom".to_string(), ProjectConfig { default_ttl: Some(60), ..Default::default() });
+        assert_eq!(data.resolve_ttl("custom", None), 60);
+        assert_eq!(data.resolve_ttl("custom", Some(30)), 30);
+    }
+
+    #[test]
+    fn project_limits_override_the_global_defaults() {
+        let mut data = app_state();
+        data.max_sessions_per_project = 100;
+        data.invalidate_rate_limit = 5.0;
+        data.project_configs.insert("custom".to_string(), ProjectConfig {
+            max_sessions: Some(3),
+            rate_limit: Some(1.0),
+            ..Default::default()
+        });
+        // Zero means unset rather than "no sessions" or "no requests"
+        data.project_configs.insert("zeroed".to_string(), ProjectConfig {
+            max_sessions: Some(0),
+            rate_limit: Some(0.0),
+            ..Default::default()
+        });
+
+        assert_eq!(data.max_sessions_for("custom"), 3);
+        assert_eq!(data.rate_limit_for("custom"), 1.0);
+        for project_id in ["zeroed", "unconfigured"] {
+            assert_eq!(data.max_sessions_for(project_id), 100);
+            assert_eq!(data.rate_limit_for(project_id), 5.0);
+        }
+    }
+
+    #[test]
+    fn absurd_ttls_are_clamped_unless_zero() {
+        let data = app_state();
+        assert_eq!(data.resolve_ttl("p", Some(u64::MAX)), MAX_TOKEN_TTL);
+        assert_eq!(data.resolve_ttl("p", Some(MAX_TOKEN_TTL + 1)), MAX_TOKEN_TTL);
+        assert_eq!(data.resolve_ttl("p", Some(0)), 0);
+        assert_eq!(clamp_ttl(u64::MAX), MAX_TOKEN_TTL);
+    }
+
+    #[test]
+    fn initial_sync_only_carries_the_projects_own_routes() {
+        let data = app_state();
+        data.routes.register("a", &["/a-only".to_string()]);
+        data.routes.register("b", &["/b-only".to_string()]);
+        data.invalidations.set_timestamps("b", [("/b-state".to_string(), data.clock.baseline() + 1)]);
+
+        let sync = data.compute_initial_sync("a");
+        assert_eq!(sync.keys().collect::<Vec<_>>(), vec!["/a-only"]);
+        assert!(!data.compute_initial_sync("b").contains_key("/a-only"));
+    }
+
+    #[test]
+    fn session_with_a_full_queue_is_evicted() {
+        let data = app_state();
+        let (session, _receiver) = SessionData::for_test("u", 1);
+        let sender = session.sender.clone();
+        let session_id = data.sessions.insert("p", Uuid::new_v4(), session).unwrap();
+
+        assert!(data.send_or_evict("p", session_id, &sender, "first".to_string()));
+        assert!(!data.send_or_evict("p", session_id, &sender, "second".to_string()));
+        assert_eq!(data.sessions.project_count("p"), 0);
+        assert_eq!(data.metrics.slow_consumer_evictions_total.load(std::sync::atomic::Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn restarted_state_reuses_the_stored_baseline() {
+        let first = app_state();
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        let second = restarted(&first);
+        assert_eq!(second.clock.baseline(), first.clock.baseline());
+    }
+
+    #[test]
+    fn frame_sizes_above_the_codec_limit_are_clamped() {
+        assert_eq!(clamp_ws_frame_size(None), WS_CODEC_MAX_FRAME);
+        assert_eq!(clamp_ws_frame_size(Some(1024)), 1024);
+        assert_eq!(clamp_ws_frame_size(Some(WS_CODEC_MAX_FRAME)), WS_CODEC_MAX_FRAME);
+        assert_eq!(clamp_ws_frame_size(Some(1024 * 1024)), WS_CODEC_MAX_FRAME);
+    }
+
+    #[test]
+    fn timestamps_inside_the_skew_are_accepted() {
+        let skew = std::time::Duration::from_secs(300);
+        let now = chrono::Utc::now().timestamp();
+        assert!(validate_timestamp(now, skew).is_ok());
+        assert!(validate_timestamp(now - 250, skew).is_ok());
+        assert!(validate_timestamp(now + 250, skew).is_ok());
+    }
+
+    #[test]
+    fn timestamps_outside_the_skew_are_rejected() {
+        let skew = std::time::Duration::from_secs(300);
+        let now = chrono::Utc::now().timestamp();
+        assert!(validate_timestamp(now - 400, skew).is_err());
+        assert!(validate_timestamp(now + 400, skew).is_err());
+        // Milliseconds passed as seconds are far in the future
+        assert!(validate_timestamp(now * 1000, skew).is_err());
+        assert!(validate_timestamp(i64::MIN, skew).is_err());
+    }
+}
@@ -0,0 +1,93 @@
+use dashmap::DashMap;
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+// How long ack records are kept before the sweeper drops them
+pub const ACK_TIMEOUT: Duration = Duration::from_secs(300);
+
+#[derive(Debug)]
+struct AckRecord {
+    created_at: Instant,
+    targets: HashSet<Uuid>,
+    acked: HashSet<Uuid>,
+}
+
+/// Which broadcast target sessions acknowledged each `require_ack` invalidation (expired after ACK_TIMEOUT).
+#[derive(Debug, Default)]
+pub struct AckTracker {
+    // AckID -> record
+    records: DashMap<String, AckRecord>,
+}
+
+impl AckTracker {
+    /// Opens a record before broadcasting so acks that beat the response aren't lost.
+    pub fn start(&self, ack_id: &str) {
+        self.records.insert(ack_id.to_string(), AckRecord {
+            created_at: Instant::now(),
+            targets: HashSet::new(),
+            acked: HashSet::new(),
+        });
+    }
+
+    /// Adds sessions the broadcast went to; only their acks are counted. Called once per
+    /// fan-out, which may finish after the response on the project's broadcaster task.
+    pub fn add_targets(&self, ack_id: &str, targets: impl IntoIterator<Item = Uuid>) {
+        if let Some(mut record) = self.records.get_mut(ack_id) {
+            record.targets.extend(targets);
+        }
+    }
+
+    /// Records an ack from `session_id` for a known ack_id.
+    pub fn record(&self, ack_id: &str, session_id: Uuid) {
+        if let Some(mut record) = self.records.get_mut(ack_id) {
+            record.acked.insert(session_id);
+        }
+    }
+
+    /// (targets, targets that acked), or None for an unknown or expired ack_id.
+    pub fn status(&self, ack_id: &str) -> Option<(usize, usize)> {
+        self.records
+            .get(ack_id)
+            .map(|record| (record.targets.len(), record.acked.intersection(&record.targets).count()))
+    }
+
+    /// Drops records older than ACK_TIMEOUT. Returns how many were dropped.
+    pub fn sweep_expired(&self) -> usize {
+        let before = self.records.len();
+        self.records.retain(|_, record| record.created_at.elapsed() <= ACK_TIMEOUT);
+        before - self.records.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_target_acks_are_counted() {
+        let acks = AckTracker::default();
+        let (target, bystander) = (Uuid::new_v4(), Uuid::new_v4());
+        acks.start("a1");
+        // An ack can arrive before the targets are known
+        acks.record("a1", target);
+        acks.record("a1", bystander);
+        acks.add_targets("a1", [target]);
+        acks.add_targets("a1", [Uuid::new_v4()]);
+
+        assert_eq!(acks.status("a1"), Some((2, 1)));
+        assert_eq!(acks.status("unknown"), None);
+    }
+
+    #[test]
+    fn expired_records_are_swept() {
+        let acks = AckTracker::default();
+        acks.start("fresh");
+        acks.start("stale");
+        acks.records.get_mut("stale").unwrap().created_at = Instant::now() - ACK_TIMEOUT - Duration::from_secs(1);
+
+        assert_eq!(acks.sweep_expired(), 1);
+        assert!(acks.status("fresh").is_some());
+        assert!(acks.status("stale").is_none());
+    }
+}
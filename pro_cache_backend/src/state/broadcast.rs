@@ -0,0 +1,98 @@
+use dashmap::DashMap;
+use std::sync::OnceLock;
+use tokio::sync::mpsc;
+
+// One fan-out handed to a project's broadcaster task. The task records `replay_frame` for the
+// targeted users and picks the sessions to send `message` to, so neither step runs on the request path.
+pub struct FanoutJob {
+    pub seq: u64,
+    pub replay_frame: String, // Recorded for replay (without `sent_at`)
+    pub message: String,
+    pub target_user: Option<String>,
+    pub exclude_users: Vec<String>,
+    pub paths: Option<Vec<String>>, // Sessions whose subscription excludes all of them are skipped
+    pub ack_id: Option<String>, // The sessions sent to become the ack's targets
+}
+
+/// The queues deliveries leave through: each project's broadcaster task, and the relay that
+/// publishes them for other instances.
+#[derive(Debug, Default)]
+pub struct Broadcasters {
+    // ProjectID -> queue of that project's broadcaster task (started on first broadcast)
+    projects: DashMap<String, mpsc::UnboundedSender<FanoutJob>>,
+
+    // Queue of deliveries to publish for other instances; set once the relay is started
+    relay: OnceLock<mpsc::UnboundedSender<String>>,
+}
+
+impl Broadcasters {
+    /// The project's broadcaster queue, from `start` (which spawns the task) on first use.
+    pub fn queue(&self, project_id: &str, start: impl FnOnce() -> mpsc::UnboundedSender<FanoutJob>) -> mpsc::UnboundedSender<FanoutJob> {
+        self.projects.entry(project_id.to_string()).or_insert_with(start).clone()
+    }
+
+    /// Forgets the project's queue if its task has exited, so the next `queue` starts a new one.
+    pub fn remove_closed(&self, project_id: &str) {
+        self.projects.remove_if(project_id, |_, queue| queue.is_closed());
+    }
+
+    /// Installs the relay's queue. Returns false if one is already installed.
+    pub fn set_relay(&self, relay: mpsc::UnboundedSender<String>) -> bool {
+        self.relay.set(relay).is_ok()
+    }
+
+    /// The relay's queue, if the relay is running.
+    pub fn relay(&self) -> Option<&mpsc::UnboundedSender<String>> {
+        self.relay.get()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_project_keeps_the_queue_it_started_with() {
+        let broadcasters = Broadcasters::default();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut started = 0;
+        broadcasters.queue("p", || { started += 1; tx });
+        let queue = broadcasters.queue("p", || { started += 1; mpsc::unbounded_channel().0 });
+        assert_eq!(started, 1);
+
+        queue.send(FanoutJob {
+            seq: 1,
+            replay_frame: String::new(),
+            message: "m".to_string(),
+            target_user: None,
+            exclude_users: Vec::new(),
+            paths: None,
+            ack_id: None,
+        }).unwrap();
+        assert_eq!(rx.try_recv().unwrap().message, "m");
+    }
+
+    #[test]
+    fn closed_queue_is_replaced() {
+        let broadcasters = Broadcasters::default();
+        let (live, _rx) = mpsc::unbounded_channel();
+        broadcasters.queue("live", || live);
+        broadcasters.queue("dead", || mpsc::unbounded_channel().0);
+
+        broadcasters.remove_closed("live");
+        broadcasters.remove_closed("dead");
+        assert!(!broadcasters.queue("live", || mpsc::unbounded_channel().0).is_closed());
+        let mut started = false;
+        broadcasters.queue("dead", || { started = true; mpsc::unbounded_channel().0 });
+        assert!(started);
+    }
+
+    #[test]
+    fn relay_is_installed_once() {
+        let broadcasters = Broadcasters::default();
+        assert!(broadcasters.relay().is_none());
+        assert!(broadcasters.set_relay(mpsc::unbounded_channel().0));
+        assert!(!broadcasters.set_relay(mpsc::unbounded_channel().0));
+        assert!(broadcasters.relay().is_some());
+    }
+}
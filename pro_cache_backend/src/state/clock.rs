@@ -0,0 +1,268 @@
+use dashmap::DashMap;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::{Duration, Instant};
+use crate::store::StateStore;
+
+// Max number of clock-drift detections kept (oldest dropped first)
+pub const DRIFT_HISTORY_SIZE: usize = 100;
+
+// 50 years (ms): far enough ahead that no real invalidation ever catches up with it
+const DEFAULT_DRIFT_FUTURE_OFFSET_MS: i64 = 50 * 365 * 24 * 60 * 60 * 1000;
+
+/// Timestamp drift recovery stamps on every route: `offset_ms` after `now`, clamped to i64::MAX.
+pub fn future_dated(now: i64, offset_ms: i64) -> i64 {
+    now.checked_add(offset_ms).unwrap_or(i64::MAX)
+}
+
+// A backward clock jump seen by invalidate in a project: the last timestamp the project was
+// given and the clock reading below it
+#[derive(Debug, Serialize, Clone)]
+pub struct DriftEvent {
+    pub detected_at: i64,
+    pub project_id: String,
+    pub prev_ts: i64,
+    pub now_ts: i64,
+}
+
+// Reads the persisted baseline, or starts (and persists) a new one at `now` when there is none
+// or a reset was requested.
+fn load_baseline(store: &dyn StateStore, now: i64, reset: bool) -> i64 {
+    if let Some(baseline) = store.load_baseline().filter(|_| !reset) {
+        log::info!("Reusing persisted baseline timestamp {}", baseline);
+        return baseline;
+    }
+
+    log::info!("Starting new baseline timestamp {}", now);
+    if let Err(e) = store.save_baseline(now) {
+        log::warn!("Failed to persist baseline: {}", e);
+    }
+    now
+}
+
+/// Wall-clock bookkeeping: the timestamp lock invalidations are stamped (and seqs allocated)
+/// under, per-project backward-jump detection and the drift-reset cooldown.
+#[derive(Debug)]
+pub struct ClockTracker {
+    // Held while stamping invalidations and allocating seqs (parking_lot for better performance)
+    timestamp_lock: parking_lot::Mutex<()>,
+
+    // ProjectID -> last timestamp handed out to it, to detect clock drift per project
+    last_timestamps: DashMap<String, i64>,
+
+    // Last time a clock drift was detected (or the baseline)
+    last_drift: AtomicI64,
+
+    // ProjectID -> when its last drift reset was broadcast; further drifts of the project within
+    // `drift_cooldown` are suppressed
+    last_drift_handled: DashMap<String, Instant>,
+
+    // Cooldown between drift resets while the clock settles (DRIFT_COOLDOWN_SECS, default 30)
+    drift_cooldown: Duration,
+
+    // How far ahead (ms) drift recovery future-dates every route (DRIFT_FUTURE_OFFSET_MS, default 50 years).
+    // Clients must treat a future-dated timestamp as "always stale": anything cached before it is invalid.
+    drift_future_offset_ms: i64,
+
+    // Stable timestamp of when the server started
+    server_start_time: i64,
+
+    // Timestamp never-invalidated routes are seeded with in the initial sync. Persisted through the
+    // store and reused across restarts (RESET_BASELINE=1 or --reset-baseline starts a new one).
+    baseline: i64,
+
+    // Recent clock-drift detections (including suppressed ones), exposed at /internal/drift
+    drift_history: parking_lot::Mutex<VecDeque<DriftEvent>>,
+}
+
+impl ClockTracker {
+    pub fn from_env(store: &dyn StateStore) -> ClockTracker {
+        let server_start_time = chrono::Utc::now().timestamp_millis();
+        let reset = std::env::var("RESET_BASELINE").is_ok_and(|v| v == "1" || v == "true")
+            || std::env::args().any(|a| a == "--reset-baseline");
+        let baseline = load_baseline(store, server_start_time, reset);
+        ClockTracker {
+            timestamp_lock: parking_lot::Mutex::new(()),
+            last_timestamps: DashMap::new(),
+            last_drift: AtomicI64::new(baseline),
+            last_drift_handled: DashMap::new(),
+            drift_cooldown: Duration::from_secs(
+                std::env::var("DRIFT_COOLDOWN_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(30),
+            ),
+            drift_future_offset_ms: std::env::var("DRIFT_FUTURE_OFFSET_MS").ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|ms: &i64| *ms > 0)
+                .unwrap_or(DEFAULT_DRIFT_FUTURE_OFFSET_MS),
+            server_start_time,
+            baseline,
+            drift_history: parking_lot::Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Reads the clock under the timestamp lock and runs `f` with the reading and those of
+    /// `project_ids` it went backward for since their last one. A backward jump is recorded in the
+    /// drift history and resets the project's tracking; the other projects are stamped as usual.
+    /// Seqs allocated in `f` are therefore ordered like the timestamps.
+    pub fn with_timestamp<T>(&self, project_ids: &[String], f: impl FnOnce(i64, &[String]) -> T) -> T {
+        let _guard = self.timestamp_lock.lock();
+        let now = chrono::Utc::now().timestamp_millis();
+
+        let mut drifted = Vec::new();
+        for project_id in project_ids {
+            match self.last_timestamps.get(project_id).map(|ts| *ts) {
+                Some(prev) if now < prev => {
+                    log::warn!("[ClockDrift] Detected backward clock jump in project {}: {} -> {}. Triggering future-dated invalidations.", project_id, prev, now);
+                    self.last_timestamps.remove(project_id); // Reset tracking
+                    let mut history = self.drift_history.lock();
+                    history.push_back(DriftEvent { detected_at: now, project_id: project_id.clone(), prev_ts: prev, now_ts: now });
+                    if history.len() > DRIFT_HISTORY_SIZE {
+                        history.pop_front();
+                    }
+                    drifted.push(project_id.clone());
+                }
+                _ => {
+                    self.last_timestamps.insert(project_id.clone(), now);
+                }
+            }
+        }
+        f(now, &drifted)
+    }
+
+    /// Runs `f` under the timestamp lock without reading the clock (seqs allocated outside invalidate).
+    pub fn serialized<T>(&self, f: impl FnOnce() -> T) -> T {
+        let _guard = self.timestamp_lock.lock();
+        f()
+    }
+
+    /// Starts a drift reset of `project_id` unless one was started within the cooldown. Returns
+    /// the new drift time, or None if the reset is suppressed.
+    pub fn start_drift_reset(&self, project_id: &str) -> Option<i64> {
+        use dashmap::mapref::entry::Entry;
+
+        match self.last_drift_handled.entry(project_id.to_string()) {
+            Entry::Occupied(last_handled) if last_handled.get().elapsed() < self.drift_cooldown => return None,
+            Entry::Occupied(mut last_handled) => { last_handled.insert(Instant::now()); }
+            Entry::Vacant(slot) => { slot.insert(Instant::now()); }
+        }
+        let drift_now = chrono::Utc::now().timestamp_millis();
+        self.last_drift.store(drift_now, Ordering::SeqCst);
+        Some(drift_now)
+    }
+
+    /// What drift recovery at `drift_now` sets every route to.
+    pub fn future_timestamp(&self, drift_now: i64) -> i64 {
+        future_dated(drift_now, self.drift_future_offset_ms)
+    }
+
+    pub fn drift_cooldown(&self) -> Duration {
+        self.drift_cooldown
+    }
+
+    /// Last drift reset (or the baseline): the `drift_time` sent with every message.
+    pub fn drift_time(&self) -> i64 {
+        self.last_drift.load(Ordering::SeqCst)
+    }
+
+    pub fn drift_history(&self) -> Vec<DriftEvent> {
+        self.drift_history.lock().iter().cloned().collect()
+    }
+
+    pub fn server_start_time(&self) -> i64 {
+        self.server_start_time
+    }
+
+    pub fn baseline(&self) -> i64 {
+        self.baseline
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+
+    fn tracker() -> ClockTracker {
+        ClockTracker::from_env(&MemoryStore::default())
+    }
+
+    fn projects(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|p| p.to_string()).collect()
+    }
+
+    #[test]
+    fn drift_is_detected_per_project() {
+        let clock = tracker();
+        clock.with_timestamp(&projects(&["a", "b"]), |_, drifted| assert!(drifted.is_empty()));
+
+        // Project a was last given a timestamp ahead of the clock (as if it had jumped back since)
+        let ahead = chrono::Utc::now().timestamp_millis() + 60_000;
+        clock.last_timestamps.insert("a".to_string(), ahead);
+        let b_before = *clock.last_timestamps.get("b").unwrap();
+
+        clock.with_timestamp(&projects(&["a", "b"]), |now, drifted| {
+            assert_eq!(drifted, ["a"]);
+            assert!(now >= b_before);
+        });
+        let history = clock.drift_history();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].project_id, "a");
+        assert_eq!(history[0].prev_ts, ahead);
+
+        // Tracking was reset for a only, so it isn't reported again
+        clock.with_timestamp(&projects(&["a", "b"]), |_, drifted| assert!(drifted.is_empty()));
+    }
+
+    #[test]
+    fn drift_in_one_project_is_not_seen_by_another() {
+        let clock = tracker();
+        clock.last_timestamps.insert("a".to_string(), i64::MAX);
+        clock.with_timestamp(&projects(&["b"]), |_, drifted| assert!(drifted.is_empty()));
+        clock.with_timestamp(&projects(&["a"]), |_, drifted| assert_eq!(drifted, ["a"]));
+    }
+
+    #[test]
+    fn drift_reset_cooldown_is_per_project() {
+        let clock = tracker();
+        assert!(clock.start_drift_reset("a").is_some());
+        assert!(clock.start_drift_reset("a").is_none());
+        assert!(clock.start_drift_reset("b").is_some());
+    }
+
+    #[test]
+    fn new_baseline_is_persisted() {
+        let store = MemoryStore::default();
+        assert_eq!(load_baseline(&store, 1000, false), 1000);
+        assert_eq!(store.load_baseline(), Some(1000));
+    }
+
+    #[test]
+    fn persisted_baseline_is_reused_unless_reset() {
+        let store = MemoryStore::default();
+        store.save_baseline(500).unwrap();
+        assert_eq!(load_baseline(&store, 1000, false), 500);
+
+        assert_eq!(load_baseline(&store, 2000, true), 2000);
+        assert_eq!(store.load_baseline(), Some(2000));
+    }
+
+    #[test]
+    fn future_date_near_i64_max_clamps_instead_of_overflowing() {
+        assert_eq!(future_dated(i64::MAX - 1, DEFAULT_DRIFT_FUTURE_OFFSET_MS), i64::MAX);
+        assert_eq!(future_dated(i64::MAX, 1), i64::MAX);
+        assert_eq!(future_dated(1000, DEFAULT_DRIFT_FUTURE_OFFSET_MS), 1000 + DEFAULT_DRIFT_FUTURE_OFFSET_MS);
+        assert_eq!(tracker().future_timestamp(i64::MAX - 10), i64::MAX);
+    }
+
+    #[test]
+    fn drift_history_is_capped() {
+        let clock = tracker();
+        for _ in 0..DRIFT_HISTORY_SIZE + 5 {
+            clock.last_timestamps.insert("a".to_string(), i64::MAX);
+            clock.with_timestamp(&projects(&["a"]), |_, _| ());
+        }
+        let history = clock.drift_history();
+        assert_eq!(history.len(), DRIFT_HISTORY_SIZE);
+        assert!(history.iter().all(|e| e.prev_ts == i64::MAX && e.now_ts == e.detected_at));
+    }
+}
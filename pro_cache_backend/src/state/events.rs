@@ -0,0 +1,73 @@
+use serde::Serialize;
+use std::collections::VecDeque;
+use uuid::Uuid;
+
+// Max number of lifecycle events kept in memory (oldest dropped first)
+pub const EVENT_LOG_SIZE: usize = 10_000;
+
+#[derive(Debug, Serialize, Clone)]
+pub struct LifecycleEvent {
+    pub ts: i64,
+    pub kind: &'static str, // "connect" | "disconnect"
+    pub project_id: String,
+    pub user_id: String,
+    pub session_id: Uuid,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<&'static str>, // Why a session disconnected
+}
+
+/// Bounded audit trail of session lifecycle events, exposed at /internal/events.
+#[derive(Debug, Default)]
+pub struct EventLog {
+    events: parking_lot::Mutex<VecDeque<LifecycleEvent>>,
+}
+
+impl EventLog {
+    pub fn record(&self, kind: &'static str, project_id: &str, user_id: &str, session_id: Uuid, reason: Option<&'static str>) {
+        let mut events = self.events.lock();
+        events.push_back(LifecycleEvent {
+            ts: chrono::Utc::now().timestamp_millis(),
+            kind,
+            project_id: project_id.to_string(),
+            user_id: user_id.to_string(),
+            session_id,
+            reason,
+        });
+        while events.len() > EVENT_LOG_SIZE {
+            events.pop_front();
+        }
+    }
+
+    /// The most recent `limit` events, oldest first.
+    pub fn recent(&self, limit: usize) -> Vec<LifecycleEvent> {
+        let events = self.events.lock();
+        events.iter().skip(events.len().saturating_sub(limit)).cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recent_returns_the_newest_events_oldest_first() {
+        let log = EventLog::default();
+        for user_id in ["u1", "u2", "u3"] {
+            log.record("connect", "p", user_id, Uuid::new_v4(), None);
+        }
+        let users: Vec<String> = log.recent(2).into_iter().map(|e| e.user_id).collect();
+        assert_eq!(users, vec!["u2", "u3"]);
+        assert_eq!(log.recent(10).len(), 3);
+    }
+
+    #[test]
+    fn oldest_events_are_dropped_past_the_cap() {
+        let log = EventLog::default();
+        for i in 0..EVENT_LOG_SIZE + 5 {
+            log.record("connect", "p", &i.to_string(), Uuid::nil(), None);
+        }
+        let events = log.recent(usize::MAX);
+        assert_eq!(events.len(), EVENT_LOG_SIZE);
+        assert_eq!(events[0].user_id, "5");
+    }
+}
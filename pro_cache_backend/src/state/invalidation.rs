@@ -0,0 +1,207 @@
+use dashmap::DashMap;
+use std::collections::HashMap;
+use crate::store::InvalidationsSnapshot;
+use super::canonical_path;
+
+/// Latest invalidation timestamp (and etag, for versioned invalidations) per project route.
+/// Only the timestamps are persisted; etags don't survive a restart.
+#[derive(Debug, Default)]
+pub struct InvalidationStore {
+    // ProjectID -> { RoutePath -> Timestamp }
+    timestamps: DashMap<String, DashMap<String, i64>>,
+
+    // ProjectID -> { RoutePath -> ETag } from the route's latest invalidation (absent = hard purge)
+    etags: DashMap<String, DashMap<String, String>>,
+}
+
+impl InvalidationStore {
+    /// Timestamps are kept as-is (even if older than the baseline). Routes that collapse to
+    /// the same canonical path keep the latest timestamp.
+    pub fn from_snapshot(snapshot: InvalidationsSnapshot, lowercase_paths: bool) -> InvalidationStore {
+        let timestamps = DashMap::new();
+        for (project_id, routes) in snapshot {
+            let proj_map: DashMap<String, i64> = DashMap::new();
+            for (route, ts) in routes {
+                let mut entry = proj_map.entry(canonical_path(&route, lowercase_paths)).or_insert(ts);
+                *entry = (*entry).max(ts);
+            }
+            timestamps.insert(project_id, proj_map);
+        }
+        InvalidationStore { timestamps, etags: DashMap::new() }
+    }
+
+    pub fn snapshot(&self) -> InvalidationsSnapshot {
+        self.timestamps
+            .iter()
+            .map(|p| {
+                let routes = p.value().iter().map(|r| (r.key().clone(), *r.value())).collect();
+                (p.key().clone(), routes)
+            })
+            .collect()
+    }
+
+    pub fn timestamp(&self, project_id: &str, path: &str) -> Option<i64> {
+        self.timestamps.get(project_id).and_then(|proj_map| proj_map.get(path).map(|ts| *ts))
+    }
+
+    /// Every (route, timestamp) of the project.
+    pub fn timestamps(&self, project_id: &str) -> Vec<(String, i64)> {
+        self.timestamps
+            .get(project_id)
+            .map(|proj_map| proj_map.iter().map(|r| (r.key().clone(), *r.value())).collect())
+            .unwrap_or_default()
+    }
+
+    pub fn set_timestamps(&self, project_id: &str, routes: impl IntoIterator<Item = (String, i64)>) {
+        let proj_map = self.timestamps.entry(project_id.to_string()).or_default();
+        for (path, ts) in routes {
+            proj_map.insert(path, ts);
+        }
+    }
+
+    /// Moves every route the project has state for to `timestamp` (clock drift recovery).
+    pub fn set_all(&self, project_id: &str, timestamp: i64) {
+        if let Some(proj_map) = self.timestamps.get(project_id) {
+            for mut route_entry in proj_map.iter_mut() {
+                *route_entry.value_mut() = timestamp;
+            }
+        }
+    }
+
+    /// Drops `paths` from the state of `project_id`, or of every project with None.
+    pub fn remove_paths(&self, project_id: Option<&str>, paths: &[String]) {
+        for proj_entry in self.timestamps.iter().filter(|p| project_id.is_none_or(|id| id == p.key())) {
+            for path in paths {
+                proj_entry.value().remove(path);
+            }
+        }
+    }
+
+    /// Routes the project has state for.
+    pub fn route_count(&self, project_id: &str) -> usize {
+        self.timestamps.get(project_id).map_or(0, |r| r.len())
+    }
+
+    pub fn projects(&self) -> Vec<String> {
+        self.timestamps.iter().map(|p| p.key().clone()).collect()
+    }
+
+    pub fn etag(&self, project_id: &str, path: &str) -> Option<String> {
+        self.etags.get(project_id).and_then(|etags| etags.get(path).map(|e| e.clone()))
+    }
+
+    /// Records the etag each target path was invalidated with; paths without one lose any stale etag.
+    pub fn store_etags(&self, project_id: &str, target_paths: &[String], etags: &HashMap<String, String>) {
+        if etags.is_empty() && !self.etags.contains_key(project_id) {
+            return;
+        }
+        let proj_etags = self.etags.entry(project_id.to_string()).or_default();
+        for path in target_paths {
+            match etags.get(path) {
+                Some(etag) => { proj_etags.insert(path.clone(), etag.clone()); }
+                None => { proj_etags.remove(path); }
+            }
+        }
+    }
+
+    /// Forgets the project's etags, making its next invalidations hard purges.
+    pub fn clear_etags(&self, project_id: &str) {
+        self.etags.remove(project_id);
+    }
+
+    /// Back to the baseline: no timestamps or etags for the project.
+    pub fn reset(&self, project_id: &str) {
+        self.timestamps.remove(project_id);
+        self.etags.remove(project_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths(ps: &[&str]) -> Vec<String> {
+        ps.iter().map(|p| p.to_string()).collect()
+    }
+
+    fn etags(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(path, etag)| (path.to_string(), etag.to_string())).collect()
+    }
+
+    #[test]
+    fn snapshot_paths_are_canonicalized_keeping_the_latest() {
+        let snapshot = HashMap::from([("p".to_string(), HashMap::from([
+            ("/a/".to_string(), 10),
+            ("/A".to_string(), 30),
+            ("/b".to_string(), 20),
+        ]))]);
+        let store = InvalidationStore::from_snapshot(snapshot, true);
+        assert_eq!(store.timestamp("p", "/a"), Some(30));
+        assert_eq!(store.timestamp("p", "/b"), Some(20));
+        assert_eq!(store.route_count("p"), 2);
+    }
+
+    #[test]
+    fn snapshot_round_trips_timestamps_but_not_etags() {
+        let store = InvalidationStore::default();
+        store.set_timestamps("p", [("/a".to_string(), 10), ("/b".to_string(), 20)]);
+        store.store_etags("p", &paths(&["/a"]), &etags(&[("/a", "v1")]));
+
+        let restored = InvalidationStore::from_snapshot(store.snapshot(), false);
+        assert_eq!(restored.timestamp("p", "/a"), Some(10));
+        assert_eq!(restored.timestamp("p", "/b"), Some(20));
+        assert_eq!(restored.etag("p", "/a"), None);
+    }
+
+    #[test]
+    fn path_invalidated_without_an_etag_loses_its_stale_one() {
+        let store = InvalidationStore::default();
+        store.store_etags("p", &paths(&["/a", "/b"]), &etags(&[("/a", "v1"), ("/b", "v1")]));
+        store.store_etags("p", &paths(&["/a"]), &HashMap::new());
+
+        assert_eq!(store.etag("p", "/a"), None);
+        // Paths the invalidation didn't target keep theirs
+        assert_eq!(store.etag("p", "/b").as_deref(), Some("v1"));
+    }
+
+    #[test]
+    fn cleared_etags_are_gone_for_the_project_only() {
+        let store = InvalidationStore::default();
+        store.store_etags("p", &paths(&["/a"]), &etags(&[("/a", "v1")]));
+        store.store_etags("q", &paths(&["/a"]), &etags(&[("/a", "v2")]));
+        store.clear_etags("p");
+
+        assert_eq!(store.etag("p", "/a"), None);
+        assert_eq!(store.etag("q", "/a").as_deref(), Some("v2"));
+    }
+
+    #[test]
+    fn set_all_and_remove_paths_respect_the_project_scope() {
+        let store = InvalidationStore::default();
+        store.set_timestamps("p", [("/a".to_string(), 10), ("/b".to_string(), 20)]);
+        store.set_timestamps("q", [("/a".to_string(), 10)]);
+
+        store.set_all("p", 99);
+        assert_eq!(store.timestamps("p").into_iter().map(|(_, ts)| ts).collect::<Vec<_>>(), vec![99, 99]);
+        assert_eq!(store.timestamp("q", "/a"), Some(10));
+
+        store.remove_paths(Some("p"), &paths(&["/a"]));
+        assert_eq!(store.timestamp("p", "/a"), None);
+        assert_eq!(store.timestamp("q", "/a"), Some(10));
+        store.remove_paths(None, &paths(&["/a"]));
+        assert_eq!(store.timestamp("q", "/a"), None);
+    }
+
+    #[test]
+    fn reset_drops_the_projects_timestamps_and_etags() {
+        let store = InvalidationStore::default();
+        store.set_timestamps("p", [("/a".to_string(), 10)]);
+        store.store_etags("p", &paths(&["/a"]), &etags(&[("/a", "v1")]));
+        store.set_timestamps("q", [("/a".to_string(), 10)]);
+        store.reset("p");
+
+        assert!(store.timestamps("p").is_empty());
+        assert_eq!(store.etag("p", "/a"), None);
+        assert_eq!(store.projects(), vec!["q".to_string()]);
+    }
+}
@@ -0,0 +1,98 @@
+use dashmap::DashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Upper bounds (ms) of the invalidation latency histogram buckets; slower samples only land in +Inf
+pub const LATENCY_BUCKETS_MS: [u64; 10] = [5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000];
+
+// Invalidate-to-client-receipt latency samples, bucketed by LATENCY_BUCKETS_MS (not cumulative)
+#[derive(Debug, Default)]
+pub struct LatencyHistogram {
+    pub buckets: [AtomicU64; LATENCY_BUCKETS_MS.len()],
+    pub count: AtomicU64,
+    pub sum_ms: AtomicU64,
+}
+
+impl LatencyHistogram {
+    pub fn observe(&self, ms: u64) {
+        if let Some(i) = LATENCY_BUCKETS_MS.iter().position(|&le| ms <= le) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_ms.fetch_add(ms, Ordering::Relaxed);
+    }
+}
+
+/// Counters exposed at /internal/metrics, and the per-path invalidation counts behind /internal/hotpaths.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub invalidations_total: AtomicU64,
+    pub broadcasts_total: AtomicU64,
+    pub clock_drift_total: AtomicU64,
+    pub connections_total: AtomicU64,
+
+    // Sessions dropped because their outgoing queue was full
+    pub slow_consumer_evictions_total: AtomicU64,
+
+    // Latencies clients reported back for `sent_at` stamps
+    pub invalidation_latency: LatencyHistogram,
+
+    // (ProjectID, RoutePath) -> how many times invalidate has targeted it since startup
+    path_invalidation_counts: DashMap<(String, String), u64>,
+}
+
+impl Metrics {
+    /// Counts one invalidation of each of `paths`.
+    pub fn record_path_hits(&self, project_id: &str, paths: &[String]) {
+        for path in paths {
+            *self.path_invalidation_counts.entry((project_id.to_string(), path.clone())).or_insert(0) += 1;
+        }
+    }
+
+    /// The project's `n` most invalidated paths with their counts, most first (ties by path).
+    pub fn hot_paths(&self, project_id: &str, n: usize) -> Vec<(String, u64)> {
+        let mut counts: Vec<(String, u64)> = self.path_invalidation_counts
+            .iter()
+            .filter(|e| e.key().0 == project_id)
+            .map(|e| (e.key().1.clone(), *e.value()))
+            .collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        counts.truncate(n);
+        counts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths(ps: &[&str]) -> Vec<String> {
+        ps.iter().map(|p| p.to_string()).collect()
+    }
+
+    #[test]
+    fn latency_samples_land_in_their_bucket_or_only_in_the_total() {
+        let histogram = LatencyHistogram::default();
+        histogram.observe(3);
+        histogram.observe(5);
+        histogram.observe(7);
+        histogram.observe(60_000);
+
+        assert_eq!(histogram.buckets[0].load(Ordering::Relaxed), 2);
+        assert_eq!(histogram.buckets[1].load(Ordering::Relaxed), 1);
+        assert_eq!(histogram.count.load(Ordering::Relaxed), 4);
+        assert_eq!(histogram.sum_ms.load(Ordering::Relaxed), 60_015);
+    }
+
+    #[test]
+    fn hot_paths_are_ranked_per_project() {
+        let metrics = Metrics::default();
+        metrics.record_path_hits("p", &paths(&["/b", "/a"]));
+        metrics.record_path_hits("p", &paths(&["/c"]));
+        metrics.record_path_hits("p", &paths(&["/c"]));
+        metrics.record_path_hits("q", &paths(&["/z", "/z"]));
+
+        assert_eq!(metrics.hot_paths("p", 2), vec![("/c".to_string(), 2), ("/a".to_string(), 1)]);
+        assert_eq!(metrics.hot_paths("q", 10), vec![("/z".to_string(), 2)]);
+        assert!(metrics.hot_paths("other", 10).is_empty());
+    }
+}
@@ -0,0 +1,83 @@
+use dashmap::DashMap;
+use std::time::Instant;
+
+/// Per-project token buckets for the invalidate endpoint, refilled continuously at each
+/// project's rate (which is also its burst size).
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    // ProjectID -> (last refill, available tokens)
+    buckets: DashMap<String, (Instant, f64)>,
+}
+
+impl RateLimiter {
+    /// Takes one token from the bucket of each of `project_ids`, or none at all if any of them is
+    /// empty, so a rejected multi-project request doesn't use up the other projects' budget.
+    /// `rate_for` gives a project's requests per second. Returns the project that was rate limited.
+    pub fn allow(&self, project_ids: &[String], rate_for: impl Fn(&str) -> f64) -> Result<(), String> {
+        for (i, project_id) in project_ids.iter().enumerate() {
+            if !self.take(project_id, rate_for(project_id)) {
+                for taken in &project_ids[..i] {
+                    self.refund(taken, rate_for(taken));
+                }
+                return Err(project_id.clone());
+            }
+        }
+        Ok(())
+    }
+
+    // Returns false if the bucket is empty
+    fn take(&self, project_id: &str, rate_limit: f64) -> bool {
+        let capacity = rate_limit.max(1.0);
+        let now = Instant::now();
+        let mut bucket = self.buckets
+            .entry(project_id.to_string())
+            .or_insert((now, capacity));
+        let (last, tokens) = *bucket;
+
+        let refilled = (tokens + now.duration_since(last).as_secs_f64() * rate_limit).min(capacity);
+        if refilled < 1.0 {
+            *bucket = (now, refilled);
+            return false;
+        }
+        *bucket = (now, refilled - 1.0);
+        true
+    }
+
+    fn refund(&self, project_id: &str, rate_limit: f64) {
+        let capacity = rate_limit.max(1.0);
+        if let Some(mut bucket) = self.buckets.get_mut(project_id) {
+            bucket.1 = (bucket.1 + 1.0).min(capacity);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn projects(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|p| p.to_string()).collect()
+    }
+
+    #[test]
+    fn request_past_the_burst_is_rejected() {
+        let limiter = RateLimiter::default();
+        for _ in 0..3 {
+            assert!(limiter.allow(&projects(&["p"]), |_| 3.0).is_ok());
+        }
+        assert_eq!(limiter.allow(&projects(&["p"]), |_| 3.0), Err("p".to_string()));
+    }
+
+    #[test]
+    fn rejected_multi_project_request_charges_no_project() {
+        let limiter = RateLimiter::default();
+        let rate = |p: &str| if p == "a" { 10.0 } else { 1.0 };
+        assert!(limiter.allow(&projects(&["b"]), rate).is_ok());
+        assert!(limiter.allow(&projects(&["a"]), rate).is_ok());
+        let a_before = limiter.buckets.get("a").unwrap().1;
+
+        assert_eq!(limiter.allow(&projects(&["a", "b"]), rate), Err("b".to_string()));
+        let a_after = limiter.buckets.get("a").unwrap().1;
+        assert!((a_after - a_before).abs() < 0.5, "a was charged: {} -> {}", a_before, a_after);
+    }
+}
@@ -0,0 +1,242 @@
+use dashmap::DashMap;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+// Max number of recent messages kept per user for replay on reconnect
+pub const REPLAY_BUFFER_SIZE: usize = 100;
+
+/// Ring buffer of the last REPLAY_BUFFER_SIZE messages sent to a user, keyed by project seq.
+/// The user's messages are a subset of the project's, so gaps between seqs are expected;
+/// `floor` marks the point before which messages may be missing.
+/// Messages are kept in seq order even when recorded out of order (a priority message is recorded
+/// before routine ones already queued on the broadcaster).
+#[derive(Debug)]
+pub struct ReplayBuffer {
+    pub floor: u64,
+    pub messages: VecDeque<ReplayEntry>,
+}
+
+#[derive(Debug)]
+pub struct ReplayEntry {
+    pub seq: u64,
+    pub frame: String,
+    // Highest seq already buffered when this one was recorded, if that was above `seq`:
+    // a client may have seen that one first and will resume from it
+    pub overtaken_by: Option<u64>,
+}
+
+impl ReplayBuffer {
+    pub fn new(floor: u64) -> Self {
+        ReplayBuffer { floor, messages: VecDeque::new() }
+    }
+
+    pub fn push(&mut self, seq: u64, frame: String) {
+        let overtaken_by = self.messages.back().map(|last| last.seq).filter(|&last| last > seq);
+        let at = self.messages.partition_point(|entry| entry.seq <= seq);
+        self.messages.insert(at, ReplayEntry { seq, frame, overtaken_by });
+        while self.messages.len() > REPLAY_BUFFER_SIZE {
+            if let Some(evicted) = self.messages.pop_front() {
+                self.floor = self.floor.max(evicted.seq);
+            }
+        }
+    }
+
+    /// Messages with seq > `since` (plus any recorded late, after `since` itself), in seq order,
+    /// or None if some of them are no longer buffered (or `since` is ahead of `current_seq`,
+    /// e.g. from before a restart), in which case a full sync is needed.
+    pub fn since(&self, since: u64, current_seq: u64) -> Option<Vec<String>> {
+        if since < self.floor || since > current_seq {
+            return None;
+        }
+        Some(
+            self.messages
+                .iter()
+                .filter(|entry| entry.seq > since || entry.overtaken_by.is_some_and(|by| by >= since))
+                .map(|entry| entry.frame.clone())
+                .collect(),
+        )
+    }
+
+    /// Drops buffered messages, forcing any client that hasn't seen `seq` into a full sync.
+    pub fn clear(&mut self, seq: u64) {
+        self.messages.clear();
+        self.floor = seq;
+    }
+}
+
+/// Per-project broadcast seqs and the per-user replay buffers keyed by them.
+#[derive(Debug, Default)]
+pub struct ReplayLog {
+    // ProjectID -> last broadcast seq
+    // Incremented under the clock's timestamp lock, so seq order matches timestamp order within a project
+    seqs: DashMap<String, AtomicU64>,
+
+    // ProjectID -> { UserID -> ReplayBuffer }
+    // Outlives individual sessions so a reconnecting client can catch up via ?since=<seq>
+    buffers: DashMap<String, DashMap<String, Arc<parking_lot::Mutex<ReplayBuffer>>>>,
+}
+
+impl ReplayLog {
+    /// Allocates the next broadcast seq for a project. Callers hold the clock's timestamp lock.
+    pub fn next_seq(&self, project_id: &str) -> u64 {
+        self.seqs
+            .entry(project_id.to_string())
+            .or_default()
+            .fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    pub fn current_seq(&self, project_id: &str) -> u64 {
+        self.seqs.get(project_id).map_or(0, |s| s.load(Ordering::SeqCst))
+    }
+
+    /// The user's buffer, created (starting at the current seq) if it doesn't exist yet.
+    pub fn buffer(&self, project_id: &str, user_id: &str) -> Arc<parking_lot::Mutex<ReplayBuffer>> {
+        let current = self.current_seq(project_id);
+        self.buffers
+            .entry(project_id.to_string())
+            .or_default()
+            .entry(user_id.to_string())
+            .or_insert_with(|| Arc::new(parking_lot::Mutex::new(ReplayBuffer::new(current))))
+            .clone()
+    }
+
+    /// Appends `frame` to the buffer of every user of the project `targeted` accepts (connected or not).
+    pub fn record(&self, project_id: &str, seq: u64, frame: &str, mut targeted: impl FnMut(&str) -> bool) {
+        if let Some(users) = self.buffers.get(project_id) {
+            for entry in users.iter() {
+                if targeted(entry.key()) {
+                    entry.value().lock().push(seq, frame.to_string());
+                }
+            }
+        }
+    }
+
+    /// Empties every buffer of the project at `seq` (see `ReplayBuffer::clear`).
+    pub fn clear_project(&self, project_id: &str, seq: u64) {
+        if let Some(users) = self.buffers.get(project_id) {
+            for entry in users.iter() {
+                entry.value().lock().clear(seq);
+            }
+        }
+    }
+
+    pub fn drop_buffer(&self, project_id: &str, user_id: &str) {
+        if let Some(users) = self.buffers.get(project_id) {
+            users.remove(user_id);
+        }
+    }
+
+    /// Drops every buffer of the project (seqs keep counting).
+    pub fn drop_project(&self, project_id: &str) {
+        self.buffers.remove(project_id);
+    }
+
+    /// Projects with replay buffers.
+    pub fn projects(&self) -> Vec<String> {
+        self.buffers.iter().map(|p| p.key().clone()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seqs_count_per_project() {
+        let log = ReplayLog::default();
+        assert_eq!(log.next_seq("a"), 1);
+        assert_eq!(log.next_seq("a"), 2);
+        assert_eq!(log.next_seq("b"), 1);
+        assert_eq!(log.current_seq("a"), 2);
+        assert_eq!(log.current_seq("unknown"), 0);
+    }
+
+    #[test]
+    fn only_targeted_users_record_a_frame() {
+        let log = ReplayLog::default();
+        let alice = log.buffer("p", "alice");
+        let bob = log.buffer("p", "bob");
+
+        let seq = log.next_seq("p");
+        log.record("p", seq, "frame", |user| user == "alice");
+        assert_eq!(alice.lock().since(0, seq), Some(vec!["frame".to_string()]));
+        assert_eq!(bob.lock().since(0, seq), Some(Vec::new()));
+    }
+
+    #[test]
+    fn new_buffer_starts_at_the_current_seq() {
+        let log = ReplayLog::default();
+        log.next_seq("p");
+        log.next_seq("p");
+        let buffer = log.buffer("p", "u");
+        // Anything before seq 2 was never recorded for this user
+        assert_eq!(buffer.lock().since(1, 2), None);
+        assert_eq!(buffer.lock().since(2, 2), Some(Vec::new()));
+    }
+
+    #[test]
+    fn clearing_forces_a_full_sync_for_older_seqs() {
+        let log = ReplayLog::default();
+        let buffer = log.buffer("p", "u");
+        for _ in 0..3 {
+            let seq = log.next_seq("p");
+            log.record("p", seq, "frame", |_| true);
+        }
+        log.clear_project("p", 3);
+        assert_eq!(buffer.lock().since(1, 3), None);
+        assert_eq!(buffer.lock().since(3, 3), Some(Vec::new()));
+    }
+
+    #[test]
+    fn full_buffer_raises_its_floor() {
+        let mut buffer = ReplayBuffer::new(0);
+        for seq in 1..=(REPLAY_BUFFER_SIZE as u64 + 5) {
+            buffer.push(seq, seq.to_string());
+        }
+        assert_eq!(buffer.floor, 5);
+        assert_eq!(buffer.since(4, 105), None);
+        assert_eq!(buffer.since(100, 105).map(|m| m.len()), Some(5));
+    }
+
+    #[test]
+    fn out_of_order_records_are_replayed_in_seq_order() {
+        let mut buffer = ReplayBuffer::new(0);
+        buffer.push(1, "1".to_string());
+        // 3 (priority) is recorded before 2, which was still queued on the broadcaster
+        buffer.push(3, "3".to_string());
+        buffer.push(2, "2".to_string());
+        buffer.push(4, "4".to_string());
+
+        assert_eq!(buffer.since(0, 4), Some(vec!["1".to_string(), "2".to_string(), "3".to_string(), "4".to_string()]));
+        // A client that saw 3 may not have received 2 yet
+        assert_eq!(buffer.since(3, 4), Some(vec!["2".to_string(), "4".to_string()]));
+        assert_eq!(buffer.since(4, 4), Some(Vec::new()));
+    }
+
+    #[test]
+    fn floor_follows_the_lowest_evicted_seqs() {
+        let mut buffer = ReplayBuffer::new(0);
+        buffer.push(2, "2".to_string());
+        for seq in 3..=(REPLAY_BUFFER_SIZE as u64 + 1) {
+            buffer.push(seq, seq.to_string());
+        }
+        buffer.push(1, "1".to_string());
+        assert_eq!(buffer.floor, 1);
+        buffer.push(REPLAY_BUFFER_SIZE as u64 + 2, String::new());
+        assert_eq!(buffer.floor, 2);
+        assert_eq!(buffer.since(1, 102), None);
+        assert_eq!(buffer.since(2, 102).map(|m| m.len()), Some(REPLAY_BUFFER_SIZE));
+    }
+
+    #[test]
+    fn dropped_buffers_are_recreated_empty() {
+        let log = ReplayLog::default();
+        log.buffer("p", "u").lock().push(1, "frame".to_string());
+        log.drop_buffer("p", "u");
+        assert!(log.buffer("p", "u").lock().messages.is_empty());
+
+        log.drop_project("p");
+        assert!(log.projects().is_empty());
+    }
+}
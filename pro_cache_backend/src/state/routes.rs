@@ -0,0 +1,195 @@
+use dashmap::DashMap;
+use crate::store::RoutesSnapshot;
+use super::canonical_path;
+
+// A dependency key is either an exact path or a "/prefix/*" wildcard
+fn dependency_matches(key: &str, path: &str) -> bool {
+    match key.strip_suffix('*').filter(|prefix| prefix.ends_with('/')) {
+        Some(prefix) => path.starts_with(prefix),
+        None => key == path,
+    }
+}
+
+/// Known routes per project (persisted), plus the tags and dependencies invalidations are
+/// resolved through (in memory only).
+#[derive(Debug, Default)]
+pub struct RouteRegistry {
+    // ProjectID -> set of known routes
+    known: DashMap<String, DashMap<String, ()>>,
+
+    // ProjectID -> { Tag -> [RoutePath] }
+    tags: DashMap<String, DashMap<String, Vec<String>>>,
+
+    // RoutePath (or "/prefix/*") -> [Dependent RoutePath]; invalidations cascade to dependents
+    dependencies: DashMap<String, Vec<String>>,
+}
+
+impl RouteRegistry {
+    pub fn from_snapshot(snapshot: RoutesSnapshot, lowercase_paths: bool) -> RouteRegistry {
+        let known: DashMap<String, DashMap<String, ()>> = DashMap::new();
+        for (project_id, routes) in snapshot {
+            let proj_routes = known.entry(project_id).or_default();
+            for r in routes {
+                proj_routes.insert(canonical_path(&r, lowercase_paths), ());
+            }
+        }
+        RouteRegistry { known, ..Default::default() }
+    }
+
+    pub fn snapshot(&self) -> RoutesSnapshot {
+        self.known
+            .iter()
+            .map(|p| (p.key().clone(), p.value().iter().map(|r| r.key().clone()).collect()))
+            .collect()
+    }
+
+    /// Known routes of `project_id`.
+    pub fn project_routes(&self, project_id: &str) -> Vec<String> {
+        self.known
+            .get(project_id)
+            .map(|routes| routes.iter().map(|r| r.key().clone()).collect())
+            .unwrap_or_default()
+    }
+
+    pub fn contains(&self, project_id: &str, path: &str) -> bool {
+        self.known.get(project_id).is_some_and(|routes| routes.contains_key(path))
+    }
+
+    /// Adds `paths` to the project's known routes. Returns whether any of them was new.
+    pub fn register(&self, project_id: &str, paths: &[String]) -> bool {
+        let proj_routes = self.known.entry(project_id.to_string()).or_default();
+        let mut new_routes_found = false;
+        for path in paths {
+            if proj_routes.insert(path.clone(), ()).is_none() {
+                new_routes_found = true;
+            }
+        }
+        new_routes_found
+    }
+
+    /// Forgets `paths` in `project_id`, or in every project with None. Returns how many were known.
+    pub fn remove(&self, project_id: Option<&str>, paths: &[String]) -> usize {
+        let mut removed = 0;
+        for proj_entry in self.known.iter().filter(|p| project_id.is_none_or(|id| id == p.key())) {
+            removed += paths.iter().filter(|p| proj_entry.value().remove(*p).is_some()).count();
+        }
+        removed
+    }
+
+    /// Total known routes across all projects.
+    pub fn count(&self) -> usize {
+        self.known.iter().map(|p| p.value().len()).sum()
+    }
+
+    pub fn projects(&self) -> Vec<String> {
+        self.known.iter().map(|p| p.key().clone()).collect()
+    }
+
+    /// Adds `paths` to the project's tag. Returns all of the tag's paths.
+    pub fn tag(&self, project_id: &str, tag: &str, paths: impl IntoIterator<Item = String>) -> Vec<String> {
+        let project_tags = self.tags.entry(project_id.to_string()).or_default();
+        let mut tag_paths = project_tags.entry(tag.to_string()).or_default();
+        for path in paths {
+            if !tag_paths.contains(&path) {
+                tag_paths.push(path);
+            }
+        }
+        tag_paths.clone()
+    }
+
+    /// Current paths of each of `tags` in the project (unknown tags contribute nothing).
+    pub fn tagged_paths(&self, project_id: &str, tags: &[String]) -> Vec<String> {
+        let Some(project_tags) = self.tags.get(project_id) else {
+            return Vec::new();
+        };
+        tags.iter()
+            .filter_map(|tag| project_tags.get(tag).map(|paths| paths.clone()))
+            .flatten()
+            .collect()
+    }
+
+    /// Declares `dependents` of `path` (exact, or a "/prefix/*" wildcard). Returns all of its dependents.
+    pub fn add_dependents(&self, path: &str, dependents: impl IntoIterator<Item = String>) -> Vec<String> {
+        let mut declared = self.dependencies.entry(path.to_string()).or_default();
+        for dependent in dependents {
+            if !declared.contains(&dependent) {
+                declared.push(dependent);
+            }
+        }
+        declared.clone()
+    }
+
+    pub fn has_dependencies(&self) -> bool {
+        !self.dependencies.is_empty()
+    }
+
+    /// Direct dependents of `path`, from every dependency key matching it.
+    pub fn dependents_of(&self, path: &str) -> Vec<String> {
+        self.dependencies
+            .iter()
+            .filter(|dep| dependency_matches(dep.key(), path))
+            .flat_map(|dep| dep.value().clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn paths(ps: &[&str]) -> Vec<String> {
+        ps.iter().map(|p| p.to_string()).collect()
+    }
+
+    #[test]
+    fn snapshot_routes_are_canonicalized() {
+        let snapshot = HashMap::from([("p".to_string(), paths(&["/a/", "/B"]))]);
+        let routes = RouteRegistry::from_snapshot(snapshot, true);
+        assert!(routes.contains("p", "/a"));
+        assert!(routes.contains("p", "/b"));
+        assert_eq!(routes.count(), 2);
+    }
+
+    #[test]
+    fn register_reports_new_routes_only() {
+        let routes = RouteRegistry::default();
+        assert!(routes.register("p", &paths(&["/a", "/b"])));
+        assert!(!routes.register("p", &paths(&["/a"])));
+        assert!(!routes.contains("other", "/a"));
+    }
+
+    #[test]
+    fn remove_is_scoped_to_a_project_unless_none() {
+        let routes = RouteRegistry::default();
+        routes.register("p", &paths(&["/a", "/b"]));
+        routes.register("q", &paths(&["/a"]));
+
+        assert_eq!(routes.remove(Some("p"), &paths(&["/a", "/missing"])), 1);
+        assert!(routes.contains("q", "/a"));
+        assert_eq!(routes.remove(None, &paths(&["/a", "/b"])), 2);
+        assert_eq!(routes.count(), 0);
+    }
+
+    #[test]
+    fn tags_accumulate_without_duplicates() {
+        let routes = RouteRegistry::default();
+        routes.tag("p", "orders", paths(&["/o/1"]));
+        assert_eq!(routes.tag("p", "orders", paths(&["/o/1", "/o/2"])), paths(&["/o/1", "/o/2"]));
+        assert_eq!(routes.tagged_paths("p", &paths(&["orders", "unknown"])), paths(&["/o/1", "/o/2"]));
+        assert!(routes.tagged_paths("q", &paths(&["orders"])).is_empty());
+    }
+
+    #[test]
+    fn wildcard_dependencies_match_by_prefix() {
+        let routes = RouteRegistry::default();
+        assert!(!routes.has_dependencies());
+        routes.add_dependents("/users/*", paths(&["/dashboard"]));
+        routes.add_dependents("/users/1", paths(&["/profile/1"]));
+
+        let mut dependents = routes.dependents_of("/users/1");
+        dependents.sort();
+        assert_eq!(dependents, paths(&["/dashboard", "/profile/1"]));
+        assert!(routes.dependents_of("/users").is_empty());
+    }
+}
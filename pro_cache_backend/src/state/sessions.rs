@@ -0,0 +1,385 @@
+use dashmap::DashMap;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use crate::protocol::OutgoingMessage;
+
+// Fresh ids tried when a new session's id is already taken (v4 collisions: never, in practice)
+const MAX_SESSION_ID_ATTEMPTS: usize = 8;
+
+#[derive(Debug, Clone)]
+pub struct SessionData {
+    pub user_id: String,
+    pub token: String,
+    pub sender: mpsc::Sender<String>,
+    pub priority_sender: mpsc::Sender<String>, // Drained before `sender` (high-priority invalidations)
+    pub connected_at: Instant,
+    pub protocol: &'static str, // Negotiated Sec-WebSocket-Protocol (legacy clients get the oldest version)
+    // Path prefixes this session subscribed to; empty = receive everything
+    pub filters: Arc<parking_lot::Mutex<Vec<String>>>,
+    // Last time the client sent us a message (used by the idle reaper)
+    pub last_active: Arc<parking_lot::Mutex<Instant>>,
+}
+
+impl SessionData {
+    pub fn wants_any(&self, paths: &[String]) -> bool {
+        let filters = self.filters.lock();
+        filters.is_empty() || paths.iter().any(|p| filters.iter().any(|f| p.starts_with(f.as_str())))
+    }
+}
+
+#[cfg(test)]
+impl SessionData {
+    /// A session of `user_id` whose queue holds `capacity` messages, and the receiving end of that queue.
+    pub fn for_test(user_id: &str, capacity: usize) -> (SessionData, mpsc::Receiver<String>) {
+        let (sender, receiver) = mpsc::channel(capacity);
+        let (priority_sender, _) = mpsc::channel(capacity);
+        let session = SessionData {
+            user_id: user_id.to_string(),
+            token: format!("token-{}", user_id),
+            sender,
+            priority_sender,
+            connected_at: Instant::now(),
+            protocol: "procache.v1",
+            filters: Default::default(),
+            last_active: Arc::new(parking_lot::Mutex::new(Instant::now())),
+        };
+        (session, receiver)
+    }
+}
+
+/// A place under a project's connection cap, reserved before the upgrade and released when
+/// dropped (when the session task ends, or when the connect is refused after all).
+#[derive(Debug)]
+pub struct SessionSlot {
+    slots: Arc<DashMap<String, Arc<AtomicUsize>>>,
+    project_id: String,
+    count: Arc<AtomicUsize>,
+}
+
+impl Drop for SessionSlot {
+    fn drop(&mut self) {
+        // The last one out removes the project's counter; a reservation taken in between (made
+        // under the entry's lock) keeps it
+        if self.count.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.slots.remove_if(&self.project_id, |_, count| count.load(Ordering::Acquire) == 0);
+        }
+    }
+}
+
+// Sends `msg` to each listed session and removes it. Dropping the sender ends the session task,
+// which closes the socket. Returns how many were removed.
+fn remove_sessions(project_sessions: &DashMap<Uuid, SessionData>, session_ids: &[Uuid], msg: &str) -> usize {
+    let mut removed = 0;
+    for session_id in session_ids {
+        if let Some((_, session)) = project_sessions.remove(session_id) {
+            let _ = session.sender.try_send(msg.to_string());
+            removed += 1;
+        }
+    }
+    removed
+}
+
+/// Live WebSocket sessions by project. Methods hold a project's map only while they run, so
+/// none of them may be called from inside a `matching` callback.
+#[derive(Debug, Default)]
+pub struct SessionRegistry {
+    // ProjectID -> { SessionID -> SessionData }
+    projects: DashMap<String, DashMap<Uuid, SessionData>>,
+
+    // ProjectID -> sessions connected or connecting, counted against the project's connection cap
+    // (shared with the SessionSlots, which remove a project's entry when its count drops to 0)
+    slots: Arc<DashMap<String, Arc<AtomicUsize>>>,
+}
+
+impl SessionRegistry {
+    /// Reserves a place for a new session of `project_id` if fewer than `max` are connected or
+    /// connecting. Checking and taking it is a single atomic step, so concurrent connects can't
+    /// overshoot the cap.
+    pub fn reserve_slot(&self, project_id: &str, max: usize) -> Option<SessionSlot> {
+        // Counted while holding the entry, so a slot dropped concurrently can't remove it meanwhile
+        let entry = self.slots.entry(project_id.to_string()).or_default();
+        if entry.fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| (n < max).then_some(n + 1)).is_err() {
+            // A cap of 0 refuses even the first connect; drop the counter this call created
+            drop(entry);
+            self.slots.remove_if(project_id, |_, count| count.load(Ordering::Acquire) == 0);
+            return None;
+        }
+        Some(SessionSlot { slots: self.slots.clone(), project_id: project_id.to_string(), count: entry.clone() })
+    }
+
+    /// Registers `session` under `session_id`, or under a fresh id if that one is already taken
+    /// (overwriting would orphan the other session's task). Returns the id it was registered
+    /// under, or None if MAX_SESSION_ID_ATTEMPTS ids in a row collided.
+    pub fn insert(&self, project_id: &str, mut session_id: Uuid, session: SessionData) -> Option<Uuid> {
+        use dashmap::mapref::entry::Entry;
+
+        let project_sessions = self.projects.entry(project_id.to_string()).or_default();
+        for _ in 0..MAX_SESSION_ID_ATTEMPTS {
+            match project_sessions.entry(session_id) {
+                Entry::Vacant(slot) => {
+                    slot.insert(session);
+                    return Some(session_id);
+                }
+                Entry::Occupied(_) => {
+                    let fresh = Uuid::new_v4();
+                    log::warn!("[WS] Session id {} is already in use in project {}; using {}", session_id, project_id, fresh);
+                    session_id = fresh;
+                }
+            }
+        }
+        None
+    }
+
+    /// Unregisters a session, dropping its project's map once it's empty so churny projects don't
+    /// leave keys behind. The emptiness check happens under the entry's shard lock, which
+    /// registration also takes (`entry().or_default()`), so a session joining concurrently is never lost.
+    pub fn remove(&self, project_id: &str, session_id: Uuid) {
+        use dashmap::mapref::entry::Entry;
+
+        if let Entry::Occupied(project_sessions) = self.projects.entry(project_id.to_string()) {
+            project_sessions.get().remove(&session_id);
+            if project_sessions.get().is_empty() {
+                project_sessions.remove();
+            }
+        }
+    }
+
+    /// Drops a session without notifying it (its queue is full anyway); its task closes the socket.
+    pub fn evict(&self, project_id: &str, session_id: Uuid) {
+        if let Some(project_sessions) = self.projects.get(project_id) {
+            project_sessions.remove(&session_id);
+        }
+    }
+
+    /// `f` applied to each session of `project_id`, keeping the Some results.
+    pub fn matching<T>(&self, project_id: &str, mut f: impl FnMut(Uuid, &SessionData) -> Option<T>) -> Vec<T> {
+        let Some(project_sessions) = self.projects.get(project_id) else {
+            return Vec::new();
+        };
+        project_sessions.iter().filter_map(|entry| f(*entry.key(), entry.value())).collect()
+    }
+
+    /// Whether `user_id` has a session in `project_id` (stops at the first one).
+    pub fn has_user(&self, project_id: &str, user_id: &str) -> bool {
+        self.projects.get(project_id).is_some_and(|s| s.iter().any(|e| e.value().user_id == user_id))
+    }
+
+    /// Sends `token-revoked` to every live session of `project_id` using `token` and drops them.
+    /// Returns how many were revoked.
+    pub fn revoke_token(&self, project_id: &str, token: &str) -> usize {
+        self.drop_where(project_id, OutgoingMessage::TokenRevoked, |_, session| session.token == token)
+    }
+
+    /// Sends `token-revoked` to every session of `project_id` and drops them. Returns how many there were.
+    pub fn revoke_all(&self, project_id: &str) -> usize {
+        self.drop_where(project_id, OutgoingMessage::TokenRevoked, |_, _| true)
+    }
+
+    /// Admin disconnect: drops one session, or every session of a user, in `project_id`
+    /// after sending them `disconnected`. Returns how many were dropped.
+    pub fn disconnect(&self, project_id: &str, session_id: Option<Uuid>, user_id: Option<&str>) -> usize {
+        self.drop_where(project_id, OutgoingMessage::Disconnected, |id, session| {
+            session_id.is_none_or(|s| id == s) && user_id.is_none_or(|u| session.user_id == u)
+        })
+    }
+
+    fn drop_where(&self, project_id: &str, msg: OutgoingMessage, mut f: impl FnMut(Uuid, &SessionData) -> bool) -> usize {
+        let Some(project_sessions) = self.projects.get(project_id) else {
+            return 0;
+        };
+        let matched: Vec<Uuid> = project_sessions
+            .iter()
+            .filter(|e| f(*e.key(), e.value()))
+            .map(|e| *e.key())
+            .collect();
+        remove_sessions(&project_sessions, &matched, &msg.to_json())
+    }
+
+    /// Closes sessions whose client has been silent for longer than `max_idle`, after sending
+    /// them `session-idle`. Returns how many were closed.
+    pub fn reap_idle(&self, max_idle: std::time::Duration) -> usize {
+        let idle_msg = OutgoingMessage::SessionIdle.to_json();
+        let mut reaped = 0;
+        for project_sessions in self.projects.iter() {
+            let idle: Vec<Uuid> = project_sessions
+                .iter()
+                .filter(|e| e.value().last_active.lock().elapsed() > max_idle)
+                .map(|e| *e.key())
+                .collect();
+            reaped += remove_sessions(project_sessions.value(), &idle, &idle_msg);
+        }
+        reaped
+    }
+
+    /// Evicts the oldest sessions of `user_id` in `project_id` beyond `max_per_user`.
+    /// Returns how many were evicted.
+    pub fn enforce_user_limit(&self, project_id: &str, user_id: &str, max_per_user: usize) -> usize {
+        let Some(project_sessions) = self.projects.get(project_id) else {
+            return 0;
+        };
+        let mut user_sessions: Vec<(Uuid, Instant)> = project_sessions
+            .iter()
+            .filter(|e| e.value().user_id == user_id)
+            .map(|e| (*e.key(), e.value().connected_at))
+            .collect();
+        if user_sessions.len() <= max_per_user {
+            return 0;
+        }
+
+        user_sessions.sort_by_key(|(_, connected_at)| *connected_at);
+        let excess = user_sessions.len() - max_per_user;
+
+        let evict_msg = OutgoingMessage::SessionEvicted.to_json();
+        let evicted: Vec<Uuid> = user_sessions.into_iter().take(excess).map(|(id, _)| id).collect();
+        remove_sessions(&project_sessions, &evicted, &evict_msg)
+    }
+
+    /// Queues `msg` for every session without dropping any (e.g. `server-shutdown`). Returns how many there were.
+    pub fn notify_all(&self, msg: &str) -> usize {
+        let mut notified = 0;
+        for project_sessions in self.projects.iter() {
+            for session in project_sessions.value().iter() {
+                let _ = session.value().sender.try_send(msg.to_string());
+                notified += 1;
+            }
+        }
+        notified
+    }
+
+    pub fn project_count(&self, project_id: &str) -> usize {
+        self.projects.get(project_id).map_or(0, |s| s.len())
+    }
+
+    /// Sessions per project with at least one.
+    pub fn counts(&self) -> HashMap<String, usize> {
+        self.projects.iter().map(|p| (p.key().clone(), p.value().len())).collect()
+    }
+
+    pub fn total(&self) -> usize {
+        self.projects.iter().map(|p| p.value().len()).sum()
+    }
+
+    pub fn projects(&self) -> Vec<String> {
+        self.projects.iter().map(|p| p.key().clone()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connection_past_the_project_cap_is_refused() {
+        let sessions = SessionRegistry::default();
+        let first = sessions.reserve_slot("p", 2);
+        let second = sessions.reserve_slot("p", 2);
+        assert!(first.is_some() && second.is_some());
+        assert!(sessions.reserve_slot("p", 2).is_none());
+        assert!(sessions.reserve_slot("other", 2).is_some());
+
+        // A disconnect frees its place
+        drop(first);
+        assert!(sessions.reserve_slot("p", 2).is_some());
+    }
+
+    #[test]
+    fn released_slots_leave_no_counters_behind() {
+        let sessions = Arc::new(SessionRegistry::default());
+        let handles: Vec<_> = (0..8)
+            .map(|worker| {
+                let sessions = sessions.clone();
+                std::thread::spawn(move || {
+                    for i in 0..500 {
+                        let project_id = format!("p{}", (worker + i) % 4);
+                        let slot = sessions.reserve_slot(&project_id, 3);
+                        drop(slot);
+                        assert!(sessions.reserve_slot("closed", 0).is_none());
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert!(sessions.slots.is_empty());
+    }
+
+    #[test]
+    fn concurrent_reservations_never_exceed_the_cap() {
+        let sessions = Arc::new(SessionRegistry::default());
+        let handles: Vec<_> = (0..16)
+            .map(|_| {
+                let sessions = sessions.clone();
+                std::thread::spawn(move || sessions.reserve_slot("p", 5))
+            })
+            .collect();
+        let slots: Vec<SessionSlot> = handles.into_iter().filter_map(|h| h.join().unwrap()).collect();
+        assert_eq!(slots.len(), 5);
+    }
+
+    #[test]
+    fn churning_sessions_leave_no_empty_project_maps() {
+        let sessions = Arc::new(SessionRegistry::default());
+        let handles: Vec<_> = (0..8)
+            .map(|worker| {
+                let sessions = sessions.clone();
+                std::thread::spawn(move || {
+                    for i in 0..500 {
+                        let project_id = format!("p{}", (worker + i) % 4);
+                        let (session, _receiver) = SessionData::for_test("u", 1);
+                        let session_id = sessions.insert(&project_id, Uuid::new_v4(), session).unwrap();
+                        sessions.remove(&project_id, session_id);
+                    }
+                })
+            })
+            .collect();
+        handles.into_iter().for_each(|h| h.join().unwrap());
+
+        assert_eq!(sessions.total(), 0);
+        assert!(sessions.projects.is_empty(), "{} project maps left behind", sessions.projects.len());
+    }
+
+    #[test]
+    fn session_joining_while_another_leaves_is_kept() {
+        let sessions = Arc::new(SessionRegistry::default());
+        let handles: Vec<_> = (0..8)
+            .map(|worker| {
+                let sessions = sessions.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..200 {
+                        let (session, _receiver) = SessionData::for_test("u", 1);
+                        let session_id = sessions.insert("p", Uuid::new_v4(), session).unwrap();
+                        sessions.remove("p", session_id);
+                    }
+                    // Every other worker stays connected
+                    let (session, _receiver) = SessionData::for_test("u", 1);
+                    worker % 2 == 0 && sessions.insert("p", Uuid::new_v4(), session).is_some()
+                })
+            })
+            .collect();
+        let stayed = handles.into_iter().map(|h| h.join().unwrap()).filter(|s| *s).count();
+
+        assert_eq!(sessions.project_count("p"), stayed);
+    }
+
+    #[test]
+    fn colliding_session_id_is_regenerated() {
+        let sessions = SessionRegistry::default();
+        let id = Uuid::new_v4();
+        let (first, _first_rx) = SessionData::for_test("first", 1);
+        let (second, _second_rx) = SessionData::for_test("second", 1);
+
+        assert_eq!(sessions.insert("p", id, first), Some(id));
+        // Forced collision: the same id again
+        let regenerated = sessions.insert("p", id, second).unwrap();
+        assert_ne!(regenerated, id);
+
+        let mut users = sessions.matching("p", |session_id, s| Some((session_id, s.user_id.clone())));
+        users.sort_by_key(|(_, user_id)| user_id.clone());
+        assert_eq!(users, vec![(id, "first".to_string()), (regenerated, "second".to_string())]);
+    }
+}
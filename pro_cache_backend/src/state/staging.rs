@@ -0,0 +1,125 @@
+use dashmap::DashMap;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Broadcast pacing: deltas staged by the coalescing window or the per-path debounce, and when
+/// each path was last broadcast.
+#[derive(Debug, Default)]
+pub struct DeltaStaging {
+    // ProjectID -> { RoutePath -> max Timestamp } staged until the coalescing window closes
+    staged: DashMap<String, HashMap<String, i64>>,
+
+    // ProjectID -> { RoutePath -> last time it was broadcast }, for the per-path debounce
+    last_broadcast: DashMap<String, DashMap<String, Instant>>,
+}
+
+impl DeltaStaging {
+    /// Merges `paths` at `timestamp` into the project's staged delta, keeping the max timestamp
+    /// per path. Returns true for the first delta of a window: the caller schedules its flush.
+    pub fn stage(&self, project_id: &str, paths: &[String], timestamp: i64) -> bool {
+        use dashmap::mapref::entry::Entry;
+
+        match self.staged.entry(project_id.to_string()) {
+            Entry::Occupied(mut staged) => {
+                for path in paths {
+                    let ts = staged.get_mut().entry(path.clone()).or_insert(timestamp);
+                    *ts = (*ts).max(timestamp);
+                }
+                false
+            }
+            Entry::Vacant(slot) => {
+                slot.insert(paths.iter().map(|p| (p.clone(), timestamp)).collect());
+                true
+            }
+        }
+    }
+
+    /// Takes the project's staged delta, if any, closing its window.
+    pub fn take(&self, project_id: &str) -> Option<HashMap<String, i64>> {
+        self.staged.remove(project_id).map(|(_, merged)| merged)
+    }
+
+    /// Splits `paths` into those to broadcast now and those already broadcast less than
+    /// `min_interval` ago (debounced). The former are recorded as broadcast now.
+    pub fn debounce(&self, project_id: &str, paths: &[String], min_interval: Duration) -> (Vec<String>, Vec<String>) {
+        let last_broadcast = self.last_broadcast.entry(project_id.to_string()).or_default();
+        let now = Instant::now();
+        paths.iter().cloned().partition(|path| {
+            let mut last = last_broadcast.entry(path.clone()).or_insert(now);
+            // A fresh entry holds `now`: never broadcast before
+            if *last != now && now.duration_since(*last) < min_interval {
+                return false;
+            }
+            *last = now;
+            true
+        })
+    }
+
+    /// Records `paths` as broadcast now, for the per-path debounce.
+    pub fn record_broadcast(&self, project_id: &str, paths: impl IntoIterator<Item = String>) {
+        let last_broadcast = self.last_broadcast.entry(project_id.to_string()).or_default();
+        let now = Instant::now();
+        for path in paths {
+            last_broadcast.insert(path, now);
+        }
+    }
+
+    /// Drops the project's staged delta and broadcast history.
+    pub fn reset(&self, project_id: &str) {
+        self.staged.remove(project_id);
+        self.last_broadcast.remove(project_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths(ps: &[&str]) -> Vec<String> {
+        ps.iter().map(|p| p.to_string()).collect()
+    }
+
+    #[test]
+    fn staged_deltas_merge_to_the_max_timestamp() {
+        let staging = DeltaStaging::default();
+        assert!(staging.stage("p", &paths(&["/a", "/b"]), 20));
+        assert!(!staging.stage("p", &paths(&["/a"]), 10));
+        assert!(!staging.stage("p", &paths(&["/b"]), 30));
+
+        let merged = staging.take("p").unwrap();
+        assert_eq!(merged, HashMap::from([("/a".to_string(), 20), ("/b".to_string(), 30)]));
+        assert!(staging.take("p").is_none());
+        // The next delta opens a new window
+        assert!(staging.stage("p", &paths(&["/a"]), 40));
+    }
+
+    #[test]
+    fn second_invalidation_within_the_interval_is_debounced() {
+        let staging = DeltaStaging::default();
+        let interval = Duration::from_secs(60);
+        assert_eq!(staging.debounce("p", &paths(&["/a"]), interval), (paths(&["/a"]), Vec::new()));
+        assert_eq!(staging.debounce("p", &paths(&["/a", "/b"]), interval), (paths(&["/b"]), paths(&["/a"])));
+        assert_eq!(staging.debounce("q", &paths(&["/a"]), interval), (paths(&["/a"]), Vec::new()));
+    }
+
+    #[test]
+    fn paths_are_broadcast_again_once_the_interval_has_passed() {
+        let staging = DeltaStaging::default();
+        let interval = Duration::from_millis(5);
+        staging.debounce("p", &paths(&["/a"]), interval);
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(staging.debounce("p", &paths(&["/a"]), interval), (paths(&["/a"]), Vec::new()));
+    }
+
+    #[test]
+    fn reset_forgets_staged_deltas_and_broadcast_history() {
+        let staging = DeltaStaging::default();
+        let interval = Duration::from_secs(60);
+        staging.stage("p", &paths(&["/a"]), 10);
+        staging.record_broadcast("p", paths(&["/a"]));
+        staging.reset("p");
+
+        assert!(staging.take("p").is_none());
+        assert_eq!(staging.debounce("p", &paths(&["/a"]), interval), (paths(&["/a"]), Vec::new()));
+    }
+}
@@ -0,0 +1,268 @@
+use dashmap::DashMap;
+use std::time::{Duration, Instant};
+use super::validate_timestamp;
+
+#[derive(Debug, Clone)]
+pub struct TokenData {
+    pub user_id: String,
+    pub project_id: String,
+    pub created_at: Instant,
+    pub ttl: u64,
+    pub one_time: bool,
+}
+
+impl TokenData {
+    // A ttl of 0 means the token never expires
+    pub fn is_expired(&self) -> bool {
+        self.ttl != 0 && self.created_at.elapsed().as_secs() > self.ttl
+    }
+
+    // Seconds until expiry; None for tokens that never expire
+    pub fn expires_in(&self) -> Option<u64> {
+        (self.ttl != 0).then(|| self.ttl.saturating_sub(self.created_at.elapsed().as_secs()))
+    }
+}
+
+#[derive(Debug, Clone)]
+struct RevokedToken {
+    token_data: TokenData,
+    revoked_at: Instant,
+}
+
+/// Registered tokens, the user -> token index, replaced tokens in their grace period and
+/// register nonces. Each map is locked on its own: a token and its user entry are not updated atomically.
+#[derive(Debug)]
+pub struct TokenStore {
+    // Token -> TokenData
+    tokens: DashMap<String, TokenData>,
+
+    // (ProjectID, UserID) -> Token
+    // Used to find and invalidate old tokens when a user re-logins
+    by_user: DashMap<(String, String), String>,
+
+    // Token -> RevokedToken
+    // Tokens replaced by a re-login stay usable for `grace_period` so in-flight reconnects don't fail
+    revoked: DashMap<String, RevokedToken>,
+
+    // How long a replaced token is still accepted (TOKEN_GRACE_SECS, default 0: opt-in, so a
+    // re-login revokes the old token immediately unless configured otherwise)
+    grace_period: Duration,
+
+    // Nonce -> when it was used, for registrations sent with a nonce
+    register_nonces: DashMap<String, Instant>,
+
+    // How long a register nonce is remembered, and how far its timestamp may be off (NONCE_WINDOW_SECS)
+    nonce_window: Duration,
+}
+
+impl TokenStore {
+    pub fn from_env() -> TokenStore {
+        TokenStore {
+            tokens: DashMap::new(),
+            by_user: DashMap::new(),
+            revoked: DashMap::new(),
+            grace_period: Duration::from_secs(
+                std::env::var("TOKEN_GRACE_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(0),
+            ),
+            register_nonces: DashMap::new(),
+            nonce_window: Duration::from_secs(
+                std::env::var("NONCE_WINDOW_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(300),
+            ),
+        }
+    }
+
+    /// A registered token, expired or not.
+    pub fn get(&self, token: &str) -> Option<TokenData> {
+        self.tokens.get(token).map(|t| t.clone())
+    }
+
+    /// The user's current token for the project, unless it has expired.
+    pub fn active_token_of(&self, project_id: &str, user_id: &str) -> Option<(String, TokenData)> {
+        let token = self.by_user.get(&(project_id.to_string(), user_id.to_string()))?.clone();
+        let token_data = self.get(&token).filter(|t| !t.is_expired())?;
+        Some((token, token_data))
+    }
+
+    /// Looks up a replaced token that is still within its grace period.
+    pub fn in_grace(&self, token: &str) -> Option<TokenData> {
+        self.revoked
+            .get(token)
+            .filter(|r| r.revoked_at.elapsed() <= self.grace_period)
+            .map(|r| r.token_data.clone())
+    }
+
+    /// Registers `token` as the user's token for the project. A different token the user had
+    /// is moved to the grace period and returned; its sessions are the caller's to revoke.
+    pub fn register(&self, token: &str, token_data: TokenData) -> Option<String> {
+        let user_key = (token_data.project_id.clone(), token_data.user_id.clone());
+        let previous = self.by_user
+            .get(&user_key)
+            .map(|t| t.value().clone())
+            .filter(|t| t != token);
+
+        if let Some(old_token) = &previous {
+            // It stays in `revoked` for the grace period so a reconnect racing the rotation still succeeds
+            if let Some((_, old_data)) = self.tokens.remove(old_token).filter(|_| !self.grace_period.is_zero()) {
+                self.revoked.insert(old_token.clone(), RevokedToken {
+                    token_data: old_data,
+                    revoked_at: Instant::now(),
+                });
+            }
+        }
+
+        self.tokens.insert(token.to_string(), token_data);
+        self.by_user.insert(user_key, token.to_string());
+        previous
+    }
+
+    /// Drops a token (e.g. found expired), and its user entry if it still points at it.
+    pub fn discard(&self, token: &str, token_data: &TokenData) {
+        self.tokens.remove(token);
+        self.unlink_user(token, token_data);
+    }
+
+    /// Consumes a one-time token, from the grace-period map if that's where it was found.
+    /// Removal is atomic, so of two callers racing on the same token only one gets true.
+    pub fn consume(&self, token: &str, token_data: &TokenData, in_grace: bool) -> bool {
+        let consumed = if in_grace {
+            self.revoked.remove(token).is_some()
+        } else {
+            self.tokens.remove(token).is_some()
+        };
+        if consumed {
+            self.unlink_user(token, token_data);
+        }
+        consumed
+    }
+
+    fn unlink_user(&self, token: &str, token_data: &TokenData) {
+        let user_key = (token_data.project_id.clone(), token_data.user_id.clone());
+        self.by_user.remove_if(&user_key, |_, t| t == token);
+    }
+
+    /// Removes expired tokens (and their user entry), replaced tokens past their grace period and
+    /// forgotten nonces. Returns the expired tokens' data.
+    pub fn sweep_expired(&self) -> Vec<TokenData> {
+        let expired: Vec<(String, TokenData)> = self.tokens
+            .iter()
+            .filter(|e| e.value().is_expired())
+            .map(|e| (e.key().clone(), e.value().clone()))
+            .collect();
+
+        for (token, token_data) in &expired {
+            self.discard(token, token_data);
+        }
+
+        self.revoked.retain(|_, r| r.revoked_at.elapsed() <= self.grace_period);
+        self.register_nonces.retain(|_, used_at| used_at.elapsed() <= self.nonce_window);
+
+        expired.into_iter().map(|(_, token_data)| token_data).collect()
+    }
+
+    /// Revokes every token of `project_id`, grace-period ones included (with no new grace).
+    /// Tokens are keyed by value, so this scans all of them: O(total tokens). Returns how many were revoked.
+    pub fn revoke_project(&self, project_id: &str) -> usize {
+        let tokens: Vec<String> = self.tokens
+            .iter()
+            .filter(|e| e.value().project_id == project_id)
+            .map(|e| e.key().clone())
+            .collect();
+        for token in &tokens {
+            self.tokens.remove(token);
+        }
+        let in_grace = self.revoked.len();
+        self.revoked.retain(|_, r| r.token_data.project_id != project_id);
+        self.by_user.retain(|(p, _), _| p != project_id);
+        tokens.len() + (in_grace - self.revoked.len())
+    }
+
+    /// Accepts a register nonce once: it must come with a timestamp inside the window
+    /// and not have been used within it. Timestamps outside the window are rejected
+    /// because their nonce may already have been forgotten.
+    pub fn use_register_nonce(&self, nonce: &str, timestamp: Option<i64>) -> Result<(), String> {
+        use dashmap::mapref::entry::Entry;
+
+        let timestamp = timestamp.ok_or("timestamp is required with nonce")?;
+        validate_timestamp(timestamp, self.nonce_window)?;
+        match self.register_nonces.entry(nonce.to_string()) {
+            Entry::Occupied(used) if used.get().elapsed() <= self.nonce_window => {
+                Err("nonce has already been used".to_string())
+            }
+            Entry::Occupied(mut used) => {
+                used.insert(Instant::now());
+                Ok(())
+            }
+            Entry::Vacant(slot) => {
+                slot.insert(Instant::now());
+                Ok(())
+            }
+        }
+    }
+
+    /// Registered tokens (expired ones included until swept).
+    pub fn count(&self) -> usize {
+        self.tokens.len()
+    }
+
+    /// Projects some user holds a token for.
+    pub fn projects(&self) -> Vec<String> {
+        self.by_user.iter().map(|e| e.key().0.clone()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token_data(user_id: &str) -> TokenData {
+        TokenData {
+            user_id: user_id.to_string(),
+            project_id: "p".to_string(),
+            created_at: Instant::now(),
+            ttl: 0,
+            one_time: false,
+        }
+    }
+
+    #[test]
+    fn replaced_token_is_rejected_without_grace_period() {
+        let mut store = TokenStore::from_env();
+        store.grace_period = Duration::ZERO;
+        store.register("t1", token_data("u"));
+        assert_eq!(store.register("t2", token_data("u")).as_deref(), Some("t1"));
+
+        assert!(store.get("t1").is_none());
+        assert!(store.in_grace("t1").is_none());
+        assert_eq!(store.active_token_of("p", "u").map(|(t, _)| t).as_deref(), Some("t2"));
+    }
+
+    #[test]
+    fn replaced_token_is_accepted_during_grace_period() {
+        let mut store = TokenStore::from_env();
+        store.grace_period = Duration::from_secs(30);
+        store.register("t1", token_data("u"));
+        store.register("t2", token_data("u"));
+
+        assert!(store.get("t1").is_none());
+        assert_eq!(store.in_grace("t1").map(|t| t.user_id).as_deref(), Some("u"));
+    }
+
+    #[test]
+    fn register_nonce_is_accepted_once() {
+        let store = TokenStore::from_env();
+        let now = chrono::Utc::now().timestamp();
+        assert!(store.use_register_nonce("n1", Some(now)).is_ok());
+        assert_eq!(store.use_register_nonce("n1", Some(now)), Err("nonce has already been used".to_string()));
+        assert!(store.use_register_nonce("n2", Some(now)).is_ok());
+    }
+
+    #[test]
+    fn register_nonce_needs_a_timestamp_inside_the_window() {
+        let store = TokenStore::from_env();
+        let stale = chrono::Utc::now().timestamp() - store.nonce_window.as_secs() as i64 - 60;
+        assert!(store.use_register_nonce("n", None).is_err());
+        assert!(store.use_register_nonce("n", Some(stale)).is_err());
+        // Rejected attempts don't burn the nonce
+        assert!(store.use_register_nonce("n", Some(chrono::Utc::now().timestamp())).is_ok());
+    }
+}
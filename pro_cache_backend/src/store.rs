@@ -0,0 +1,224 @@
+use std::collections::HashMap;
+
+// File names inside DATA_DIR (default: the working directory); each can be moved with its own env var
+const DEFAULT_ROUTES_FILE: &str = "routes.json"; // Overridden by ROUTES_FILE
+const INVALIDATIONS_FILE: &str = "invalidations.json"; // Overridden by INVALIDATIONS_FILE
+const BASELINE_FILE: &str = "baseline.json"; // Overridden by BASELINE_FILE
+
+// Project that routes from the old flat routes.json format are migrated into
+const LEGACY_ROUTES_PROJECT: &str = "default";
+
+// ProjectID -> known routes
+pub type RoutesSnapshot = HashMap<String, Vec<String>>;
+
+// ProjectID -> { RoutePath -> Timestamp }
+pub type InvalidationsSnapshot = HashMap<String, HashMap<String, i64>>;
+
+/// Where known routes, invalidation timestamps and the sync baseline are kept between restarts.
+/// Loads return None when nothing (readable) has been stored yet.
+pub trait StateStore: std::fmt::Debug {
+    fn load_routes(&self) -> Option<RoutesSnapshot>;
+    fn save_routes(&self, routes: &RoutesSnapshot) -> Result<(), String>;
+    fn load_invalidations(&self) -> Option<InvalidationsSnapshot>;
+    fn save_invalidations(&self, invalidations: &InvalidationsSnapshot) -> Result<(), String>;
+    fn load_baseline(&self) -> Option<i64>;
+    fn save_baseline(&self, baseline: i64) -> Result<(), String>;
+}
+
+/// Default store: pretty-printed JSON files in DATA_DIR (default: the working directory),
+/// each of which can be moved with ROUTES_FILE, INVALIDATIONS_FILE or BASELINE_FILE.
+#[derive(Debug)]
+pub struct FileStore {
+    routes_file: String,
+    invalidations_file: String,
+    baseline_file: String,
+}
+
+impl FileStore {
+    pub fn from_env() -> Self {
+        let data_dir = std::env::var("DATA_DIR").ok().filter(|d| !d.is_empty());
+        let file = |var: &str, default: &str| {
+            resolve_file(std::env::var(var).ok(), data_dir.as_deref(), default)
+        };
+        FileStore {
+            routes_file: file("ROUTES_FILE", DEFAULT_ROUTES_FILE),
+            invalidations_file: file("INVALIDATIONS_FILE", INVALIDATIONS_FILE),
+            baseline_file: file("BASELINE_FILE", BASELINE_FILE),
+        }
+    }
+}
+
+// An explicit (non-empty) path wins; otherwise `default` inside `data_dir`, if any
+fn resolve_file(explicit: Option<String>, data_dir: Option<&str>, default: &str) -> String {
+    match (explicit.filter(|f| !f.is_empty()), data_dir) {
+        (Some(file), _) => file,
+        (None, Some(dir)) => std::path::Path::new(dir).join(default).to_string_lossy().into_owned(),
+        (None, None) => default.to_string(),
+    }
+}
+
+impl StateStore for FileStore {
+    // The old format was a flat list shared by every project; it's migrated into the
+    // "default" project (and rewritten on the next save).
+    fn load_routes(&self) -> Option<RoutesSnapshot> {
+        let content = std::fs::read_to_string(&self.routes_file).ok()?;
+        serde_json::from_str::<RoutesSnapshot>(&content).ok().or_else(|| {
+            let routes = serde_json::from_str::<Vec<String>>(&content).ok()?;
+            log::info!("Migrating flat {} into project '{}'", self.routes_file, LEGACY_ROUTES_PROJECT);
+            Some(HashMap::from([(LEGACY_ROUTES_PROJECT.to_string(), routes)]))
+        })
+    }
+
+    fn save_routes(&self, routes: &RoutesSnapshot) -> Result<(), String> {
+        write_json(&self.routes_file, routes)
+    }
+
+    fn load_invalidations(&self) -> Option<InvalidationsSnapshot> {
+        let content = std::fs::read_to_string(&self.invalidations_file).ok()?;
+        serde_json::from_str(&content).ok()
+    }
+
+    fn save_invalidations(&self, invalidations: &InvalidationsSnapshot) -> Result<(), String> {
+        write_json(&self.invalidations_file, invalidations)
+    }
+
+    fn load_baseline(&self) -> Option<i64> {
+        let content = std::fs::read_to_string(&self.baseline_file).ok()?;
+        serde_json::from_str(&content).ok()
+    }
+
+    fn save_baseline(&self, baseline: i64) -> Result<(), String> {
+        write_json(&self.baseline_file, &baseline)
+    }
+}
+
+/// Store that keeps everything in memory, for tests.
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    pub routes: parking_lot::Mutex<Option<RoutesSnapshot>>,
+    pub invalidations: parking_lot::Mutex<Option<InvalidationsSnapshot>>,
+    pub baseline: parking_lot::Mutex<Option<i64>>,
+}
+
+#[cfg(test)]
+impl StateStore for MemoryStore {
+    fn load_routes(&self) -> Option<RoutesSnapshot> {
+        self.routes.lock().clone()
+    }
+
+    fn save_routes(&self, routes: &RoutesSnapshot) -> Result<(), String> {
+        *self.routes.lock() = Some(routes.clone());
+        Ok(())
+    }
+
+    fn load_invalidations(&self) -> Option<InvalidationsSnapshot> {
+        self.invalidations.lock().clone()
+    }
+
+    fn save_invalidations(&self, invalidations: &InvalidationsSnapshot) -> Result<(), String> {
+        *self.invalidations.lock() = Some(invalidations.clone());
+        Ok(())
+    }
+
+    fn load_baseline(&self) -> Option<i64> {
+        *self.baseline.lock()
+    }
+
+    fn save_baseline(&self, baseline: i64) -> Result<(), String> {
+        *self.baseline.lock() = Some(baseline);
+        Ok(())
+    }
+}
+
+fn write_json(path: &str, value: &impl serde::Serialize) -> Result<(), String> {
+    let json = serde_json::to_string_pretty(value).map_err(|e| e.to_string())?;
+    write_atomic(path, &json).map_err(|e| format!("{}: {}", path, e))
+}
+
+// Distinguishes the temp files of concurrent writes within this process
+static TMP_COUNTER: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+// Writes `contents` to a temp file next to `path` and renames it over `path`,
+// so a crash mid-write never leaves a truncated file behind. Each write gets its own
+// temp file, so concurrent writers can't truncate each other's before the rename.
+fn write_atomic(path: &str, contents: &str) -> std::io::Result<()> {
+    let n = TMP_COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    let tmp = format!("{}.{}.{}.tmp", path, std::process::id(), n);
+    let result = std::fs::write(&tmp, contents).and_then(|()| std::fs::rename(&tmp, path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_store(name: &str) -> (std::path::PathBuf, FileStore) {
+        let dir = std::env::temp_dir().join(format!("pro_cache_store_{}_{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = |f: &str| dir.join(f).to_string_lossy().into_owned();
+        let store = FileStore {
+            routes_file: file(DEFAULT_ROUTES_FILE),
+            invalidations_file: file(INVALIDATIONS_FILE),
+            baseline_file: file(BASELINE_FILE),
+        };
+        (dir, store)
+    }
+
+    #[test]
+    fn files_default_to_data_dir_unless_overridden() {
+        assert_eq!(resolve_file(None, None, BASELINE_FILE), "baseline.json");
+        assert_eq!(resolve_file(None, Some("/data"), BASELINE_FILE), "/data/baseline.json");
+        assert_eq!(resolve_file(Some("/etc/b.json".into()), Some("/data"), BASELINE_FILE), "/etc/b.json");
+        assert_eq!(resolve_file(Some(String::new()), Some("/data"), BASELINE_FILE), "/data/baseline.json");
+    }
+
+    #[test]
+    fn baseline_round_trips_through_file_store() {
+        let (dir, store) = temp_store("baseline");
+        assert_eq!(store.load_baseline(), None);
+
+        store.save_baseline(1_700_000_000_000).unwrap();
+        assert_eq!(store.load_baseline(), Some(1_700_000_000_000));
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1, "temp file left behind");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn concurrent_saves_leave_a_complete_file() {
+        let (dir, store) = temp_store("concurrent");
+        let store = std::sync::Arc::new(store);
+        let writers: Vec<_> = (0..8).map(|i| {
+            let store = store.clone();
+            std::thread::spawn(move || {
+                let paths: HashMap<String, i64> = (0..200).map(|p| (format!("/{}", p), i)).collect();
+                for _ in 0..20 {
+                    store.save_invalidations(&HashMap::from([("p".to_string(), paths.clone())])).unwrap();
+                }
+            })
+        }).collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        assert_eq!(store.load_invalidations().unwrap()["p"].len(), 200);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1, "temp files left behind");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn flat_routes_file_is_migrated_into_default_project() {
+        let (dir, store) = temp_store("legacy_routes");
+        std::fs::write(&store.routes_file, r#"["/a", "/b"]"#).unwrap();
+
+        let routes = store.load_routes().unwrap();
+        assert_eq!(routes.get(LEGACY_ROUTES_PROJECT), Some(&vec!["/a".to_string(), "/b".to_string()]));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,8 @@
+// Fixtures shared by the unit tests of several modules
+use crate::state::AppState;
+use crate::store::MemoryStore;
+
+/// A fresh AppState over an empty in-memory store.
+pub fn app_state() -> AppState {
+    AppState::new(Box::new(MemoryStore::default()))
+}
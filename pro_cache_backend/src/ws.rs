@@ -1,96 +1,311 @@
-use actix_web::{web, Error, HttpRequest, HttpResponse};
+use actix_web::{http::StatusCode, web, Error, HttpRequest, HttpResponse};
 use futures_util::StreamExt as _;
+use tracing::Instrument as _;
 use tokio::sync::mpsc;
 use uuid::Uuid;
+use crate::handlers::error_response;
+use crate::protocol::OutgoingMessage;
 use crate::state::{AppState, SessionData};
+use std::time::{Duration, Instant};
 
+// How often we ping the client
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+// How long without a pong before we consider the connection dead
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(30);
+// Sec-WebSocket-Protocol values we speak, oldest first
+const SUPPORTED_PROTOCOLS: &[&str] = &["procache.v1"];
+// Sec-WebSocket-Protocol entries carrying the token ("procache.token.<token>"). Browsers only accept
+// a reply naming an offered protocol, so clients must offer a version alongside it.
+const TOKEN_PROTOCOL_PREFIX: &str = "procache.token.";
+// How long a session opened with a replaced (grace-period) token lives before it's revoked
+const REVOKED_SESSION_LIFETIME: Duration = Duration::from_secs(5);
+// How long a server-initiated close may spend flushing still-queued messages before the close frame
+const CLOSE_DRAIN_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[tracing::instrument(name = "ws", skip_all, fields(project_id, user_id, session_id))]
 pub async fn ws_handler(
     req: HttpRequest,
     stream: web::Payload,
     data: web::Data<AppState>,
 ) -> Result<HttpResponse, Error> {
-    // 1. Extract Token from Query Params
+    // 1. Extract Token, then the optional replay cursors and encoding from Query Params
+    let Some(token) = extract_token(&req) else {
+        return Ok(auth_failure(&data, "missing_token", "Missing token"));
+    };
     let query_str = req.query_string();
-    let token = match form_urlencoded::parse(query_str.as_bytes())
-        .find(|(k, _)| k == "token") 
-    {
-        Some((_, v)) => v.to_string(),
-        None => return Ok(HttpResponse::Unauthorized().body("Missing token")),
+    let since: Option<u64> = form_urlencoded::parse(query_str.as_bytes())
+        .find(|(k, _)| k == "since")
+        .and_then(|(_, v)| v.parse().ok());
+    // Timestamp (ms) of the client's last sync; `since` is already the seq cursor
+    let since_ts: Option<i64> = form_urlencoded::parse(query_str.as_bytes())
+        .find(|(k, _)| k == "since_ts")
+        .and_then(|(_, v)| v.parse().ok());
+    let gzip_sync = form_urlencoded::parse(query_str.as_bytes())
+        .any(|(k, v)| k == "compress" && v == "gzip");
+    let encoding = if form_urlencoded::parse(query_str.as_bytes()).any(|(k, v)| k == "encoding" && v == "msgpack") {
+        Encoding::MsgPack
+    } else {
+        Encoding::Json
+    };
+    let wire = Wire {
+        encoding,
+        compact: form_urlencoded::parse(query_str.as_bytes()).any(|(k, v)| k == "compact" && (v == "1" || v == "true")),
     };
 
-    // 2. Validate Token
-    let token_data = match data.pending_tokens.get(&token) {
-        Some(entry) => entry.clone(),
-        None => return Ok(HttpResponse::Unauthorized().body("Invalid or expired token")),
+    // 2. Validate Token (a just-replaced token is still accepted during its grace period)
+    let (token_data, using_revoked_token) = match data.tokens.get(&token) {
+        Some(token_data) => (token_data, false),
+        None => match data.tokens.in_grace(&token) {
+            Some(token_data) => (token_data, true),
+            None => return Ok(auth_failure(&data, "invalid_token", "Invalid or expired token")),
+        },
     };
 
+    // 2b. Enforce TTL. Expired tokens are dropped so they can't be reused.
+    if token_data.is_expired() {
+        data.tokens.discard(&token, &token_data);
+        data.replay.drop_buffer(&token_data.project_id, &token_data.user_id);
+        return Ok(auth_failure(&data, "token_expired", "Token expired"));
+    }
+
+    // 2c. Per-project connection cap (checked before the upgrade so the client gets a real status).
+    // The place is held until the session ends, and given back if the connect fails below.
+    let max_sessions = data.max_sessions_for(&token_data.project_id);
+    let Some(slot) = data.sessions.reserve_slot(&token_data.project_id, max_sessions) else {
+        log::warn!("[WS] Project {} is at its connection limit ({})", token_data.project_id, max_sessions);
+        return Ok(error_response(StatusCode::SERVICE_UNAVAILABLE, "project_connection_limit", "Project connection limit reached"));
+    };
+
+    // 2d. Protocol version negotiation: the first client-offered version we support wins.
+    // Clients that offer none are treated as the oldest version; offering only unknown ones is refused.
+    let offered: Vec<String> = req.headers()
+        .get_all(actix_web::http::header::SEC_WEBSOCKET_PROTOCOL)
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|p| p.trim().to_string())
+        .filter(|p| !p.is_empty() && !p.starts_with(TOKEN_PROTOCOL_PREFIX))
+        .collect();
+    let protocol = match offered.iter().find_map(|p| SUPPORTED_PROTOCOLS.iter().find(|s| **s == p.as_str())) {
+        Some(protocol) => Some(*protocol),
+        None if offered.is_empty() => None,
+        None => {
+            let mut res = error_response(StatusCode::UPGRADE_REQUIRED, "unsupported_protocol", "Unsupported protocol version");
+            if let Ok(supported) = actix_web::http::header::HeaderValue::from_str(&SUPPORTED_PROTOCOLS.join(", ")) {
+                res.headers_mut().insert(actix_web::http::header::SEC_WEBSOCKET_PROTOCOL, supported);
+            }
+            return Ok(res);
+        }
+    };
+
+    // 2e. One-time tokens are consumed now that every check has passed. Removal is atomic,
+    // so of two connects racing on the same token only one gets through.
+    if token_data.one_time && !data.tokens.consume(&token, &token_data, using_revoked_token) {
+        return Ok(auth_failure(&data, "invalid_token", "Invalid or expired token"));
+    }
+
     // 3. Upgrade to WebSocket
-    let (res, mut session, mut stream) = actix_ws::handle(&req, stream)?;
+    let (mut res, mut session, mut stream) = actix_ws::handle(&req, stream)?;
+    if let Some(protocol) = protocol {
+        res.headers_mut().insert(
+            actix_web::http::header::SEC_WEBSOCKET_PROTOCOL,
+            actix_web::http::header::HeaderValue::from_static(protocol),
+        );
+    }
 
     let project_id = token_data.project_id.clone();
     let user_id = token_data.user_id.clone();
     let session_id = Uuid::new_v4();
+    let span = tracing::Span::current();
+    span.record("project_id", project_id.as_str());
+    span.record("user_id", user_id.as_str());
+    span.record("session_id", tracing::field::display(session_id));
 
-    // 4. Send Initial Invalidation State
-    let timestamp_now = chrono::Utc::now().timestamp_millis();
-    let proj_map = data.project_invalidation_state.entry(project_id.clone())
-        .or_insert_with(dashmap::DashMap::new);
+    // 4. Create Channels for this session (bounded, so a stuck client can't grow them forever):
+    // routine messages, and a lane for high-priority ones that is always drained first
+    let (tx, rx) = mpsc::channel::<String>(data.session_channel_capacity);
+    let (priority_tx, priority_rx) = mpsc::channel::<String>(data.session_channel_capacity);
 
-    // If this project has no invalidation state yet, but we have globally known routes 
-    // (e.g. from routes.json after a restart), populate the project state with "now" timestamps.
-    // This forces the frontend to invalidate its local cache for these routes once.
-    if proj_map.is_empty() && !data.known_routes.is_empty() {
-        log::info!("[WS] Populating initial state for project {} with {} known routes", project_id, data.known_routes.len());
-        for entry in data.known_routes.iter() {
-            proj_map.insert(entry.key().clone(), timestamp_now);
-        }
+    // 5. Register Session before anything is sent, so the client is only ever told the id it was
+    // registered under (`insert` picks a fresh one on a collision). Broadcasts made from here on
+    // wait in the channel and go out after the sync.
+    let filters = std::sync::Arc::new(parking_lot::Mutex::new(Vec::new()));
+    // Activity is dated to the end of the grace period, so the idle reaper doesn't count it either
+    let last_active = std::sync::Arc::new(parking_lot::Mutex::new(Instant::now() + data.heartbeat_grace));
+    let registered = data.sessions.insert(&project_id, session_id, SessionData {
+            user_id: user_id.clone(),
+            token: token.clone(),
+            sender: tx,
+            priority_sender: priority_tx,
+            connected_at: Instant::now(),
+            protocol: protocol.unwrap_or(SUPPORTED_PROTOCOLS[0]),
+            filters: filters.clone(),
+            last_active: last_active.clone(),
+        });
+    let Some(session_id) = registered else {
+        log::error!("[WS] Could not allocate a session id in project {}; closing", project_id);
+        let _ = session.close(None).await;
+        return Ok(res);
+    };
+    span.record("session_id", tracing::field::display(session_id));
+    data.metrics.connections_total.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    data.events.record("connect", &project_id, &user_id, session_id, None);
+
+    let evicted = data.sessions.enforce_user_limit(&project_id, &user_id, data.max_sessions_per_user);
+    if evicted > 0 {
+        log::info!("[WS] Evicted {} old sessions of user {} in project {}", evicted, user_id, project_id);
+    }
+
+    // Tells the client the socket is live; sent after the sync unless STATUS_BEFORE_SYNC
+    let status_msg = OutgoingMessage::Status {
+        status: "connected",
+        session_id,
+        server_time: chrono::Utc::now().timestamp_millis(),
+    }.to_json();
+    if data.status_before_sync {
+        let _ = send_encoded(&mut session, wire, status_msg.clone()).await;
     }
 
-    let initial_routes: std::collections::HashMap<String, i64> = 
-        proj_map.iter().map(|r| (r.key().clone(), *r.value())).collect();
+    // 6. Send missed messages if the client is resuming, otherwise the Initial Invalidation State:
+    // only routes changed after `since_ts` if it's no older than the baseline, the full map otherwise
+    // (the buffer is created here so broadcasts are recorded for this user from now on)
+    let replay_buffer = data.replay.buffer(&project_id, &user_id);
+    let current_seq = data.replay.current_seq(&project_id);
+    let replay = since.and_then(|seq| replay_buffer.lock().since(seq, current_seq));
 
-    let all_sync = serde_json::json!({
-        "type": "invalidate",
-        "data": initial_routes,
-        "drift_time": data.last_drift_timestamp.load(std::sync::atomic::Ordering::SeqCst)
-    });
-    let _ = session.text(all_sync.to_string()).await;
+    if let Some(missed) = replay {
+        log::info!("[WS] Replaying {} missed messages for user {} in project {}", missed.len(), user_id, project_id);
+        for msg in missed {
+            let _ = send_encoded(&mut session, wire, msg).await;
+        }
+    } else {
+        let newer_than = since_ts.filter(|ts| *ts >= data.clock.baseline());
+        let all_sync = initial_sync_message(&data, &project_id, &[], newer_than);
+        let sync_routes = match &all_sync {
+            OutgoingMessage::Sync { data, .. } => data.len(),
+            _ => 0,
+        };
 
-    // 5. Create Channel for this session
-    let (tx, rx) = mpsc::unbounded_channel::<String>();
+        let frame = sync_frame(&project_id, all_sync, |sync| encode_sync(sync, wire, gzip_sync));
+        let sync_bytes = match &frame {
+            SyncFrame::Text(text) => text.len(),
+            SyncFrame::Binary(bytes) => bytes.len(),
+        };
+        log::debug!("[WS] Initial sync for session {} in project {}: {} routes, {} bytes", session_id, project_id, sync_routes, sync_bytes);
+        let _ = match frame {
+            SyncFrame::Text(text) => send_encoded(&mut session, wire, text).await,
+            SyncFrame::Binary(bytes) => session.binary(bytes).await,
+        };
+    }
+    if !data.status_before_sync {
+        let _ = send_encoded(&mut session, wire, status_msg).await;
+    }
 
-    // 6. Register Session
-    data.active_sessions
-        .entry(project_id.clone())
-        .or_insert_with(dashmap::DashMap::new)
-        .insert(session_id, SessionData {
-            user_id: user_id.clone(),
-            sender: tx
+    // Sessions on a replaced token only get a short window, then receive token-revoked
+    if using_revoked_token {
+        log::info!("[WS] Session {} connected with a replaced token; revoking in {:?}", session_id, REVOKED_SESSION_LIFETIME);
+        let state = data.clone();
+        let project_id = project_id.clone();
+        let token = token.clone();
+        actix_rt::spawn(async move {
+            tokio::time::sleep(REVOKED_SESSION_LIFETIME).await;
+            state.sessions.revoke_token(&project_id, &token);
         });
+    }
 
-    let active_sessions = data.active_sessions.clone();
+    // web::Data is an Arc, so the task shares the real session map (not a copy)
+    let state = data.clone();
     let project_id_clone = project_id.clone();
 
     // 7. Spawn WebSocket Task
     actix_rt::spawn(async move {
-        let mut rx_stream = tokio_stream::wrappers::UnboundedReceiverStream::new(rx);
+        let mut rx_stream = tokio_stream::wrappers::ReceiverStream::new(rx);
+        let mut priority_stream = tokio_stream::wrappers::ReceiverStream::new(priority_rx);
+        let mut priority_open = true;
         
-        // We keep track of the close reason if the client sends one
+        // We keep track of the close reason if the client sends one (echoed back when closing)
         let mut close_reason = None;
+        let disconnect_reason;
+
+        // Heartbeat: ping periodically and drop the connection if pongs stop arriving.
+        // Both only start once the grace period is over.
+        let grace_end = Instant::now() + state.heartbeat_grace;
+        let mut heartbeat = tokio::time::interval_at(grace_end.into(), HEARTBEAT_INTERVAL);
+        let mut last_pong = grace_end;
 
         loop {
             tokio::select! {
+                // Branches are polled in order, so the priority lane is never starved by the rest
+                biased;
+
+                msg_from_priority = priority_stream.next(), if priority_open => {
+                    match msg_from_priority {
+                        Some(msg) => {
+                            if send_encoded(&mut session, wire, msg).await.is_err() {
+                                disconnect_reason = DisconnectReason::SendError;
+                                break;
+                            }
+                        }
+                        // Closed together with the routine lane, which may still hold messages
+                        // (e.g. token-revoked) and ends the loop once drained
+                        None => priority_open = false,
+                    }
+                }
+
+                _ = heartbeat.tick() => {
+                    if last_pong.elapsed() > CLIENT_TIMEOUT {
+                        disconnect_reason = DisconnectReason::Heartbeat;
+                        break;
+                    }
+                    if session.ping(b"").await.is_err() {
+                        disconnect_reason = DisconnectReason::SendError;
+                        break;
+                    }
+                }
+
                 // Incoming messages from the Client
                 msg_opt = stream.next() => {
+                    // Only application frames count as activity: browsers answer pings on their own
+                    if let Some(Ok(actix_ws::Message::Text(_) | actix_ws::Message::Binary(_))) = &msg_opt {
+                        *last_active.lock() = Instant::now();
+                    }
+                    let oversized = match &msg_opt {
+                        Some(Ok(actix_ws::Message::Text(text))) => text.len() > state.ws_max_frame_size,
+                        Some(Ok(actix_ws::Message::Binary(bytes))) => bytes.len() > state.ws_max_frame_size,
+                        Some(Err(actix_ws::ProtocolError::Overflow)) => true,
+                        _ => false,
+                    };
+                    if oversized {
+                        disconnect_reason = DisconnectReason::FrameTooLarge;
+                        break;
+                    }
                     match msg_opt {
                         Some(Ok(actix_ws::Message::Ping(bytes))) => {
-                            if session.pong(&bytes).await.is_err() { break; }
+                            last_pong = Instant::now();
+                            if session.pong(&bytes).await.is_err() {
+                                disconnect_reason = DisconnectReason::SendError;
+                                break;
+                            }
+                        }
+                        Some(Ok(actix_ws::Message::Pong(_))) => {
+                            last_pong = Instant::now();
+                        }
+                        Some(Ok(actix_ws::Message::Text(text))) => {
+                            if let Some(reply) = handle_client_message(&text, &state, &project_id_clone, session_id, &filters) {
+                                if send_encoded(&mut session, wire, reply).await.is_err() {
+                                    disconnect_reason = DisconnectReason::SendError;
+                                    break;
+                                }
+                            }
                         }
                         Some(Ok(actix_ws::Message::Close(reason))) => {
                             close_reason = reason;
+                            disconnect_reason = DisconnectReason::ClientClose;
                             break; // Exit loop to handle session.close() once
                         }
-                        Some(Err(_)) | None => break,
+                        Some(Err(_)) | None => {
+                            disconnect_reason = DisconnectReason::StreamError;
+                            break;
+                        }
                         _ => {}
                     }
                 }
@@ -99,26 +314,981 @@ pub async fn ws_handler(
                 msg_from_chan = rx_stream.next() => {
                     match msg_from_chan {
                         Some(msg) => {
-                            if session.text(msg).await.is_err() {
+                            let terminal = terminal_reason(&msg);
+                            if send_encoded(&mut session, wire, msg).await.is_err() {
+                                disconnect_reason = DisconnectReason::SendError;
+                                break;
+                            }
+                            // The server is ending this session; close now with a code that says why
+                            if let Some(reason) = terminal {
+                                disconnect_reason = reason;
                                 break;
                             }
                         }
-                        None => break,
+                        // The server dropped our sender (revoked, evicted, too slow...)
+                        None => {
+                            disconnect_reason = DisconnectReason::Evicted;
+                            break;
+                        }
                     }
                 }
             }
         }
 
         // --- CLEANUP PHASE ---
-        
-        // This consumes `session`. Since we are outside the loop, 
+
+        // This consumes `session`. Since we are outside the loop,
         // it only happens once.
-        let _ = session.close(close_reason).await;
+        close_session(session, wire, rx_stream.as_mut(), priority_stream.as_mut(), disconnect_reason, close_reason, session_id).await;
 
-        if let Some(project_map) = active_sessions.get(&project_id_clone) {
-            project_map.remove(&session_id);
-        }
-    });
+        state.sessions.remove(&project_id_clone, session_id);
+        drop(slot);
+        log::info!(
+            "[WS] Session {} of user {} in project {} disconnected: {}",
+            session_id, user_id, project_id_clone, disconnect_reason.as_str()
+        );
+        state.events.record("disconnect", &project_id_clone, &user_id, session_id, Some(disconnect_reason.as_str()));
+    }.instrument(span));
 
     Ok(res)
-}
\ No newline at end of file
+}
+
+// Why a session task left its loop, logged and recorded with the disconnect event
+#[derive(Debug, Clone, Copy)]
+enum DisconnectReason {
+    ClientClose,
+    StreamError,
+    SendError,
+    Heartbeat,
+    Evicted,
+    FrameTooLarge,
+    TokenRevoked,
+    Idle,
+    AdminDisconnect,
+    Shutdown,
+}
+
+impl DisconnectReason {
+    fn as_str(self) -> &'static str {
+        match self {
+            DisconnectReason::ClientClose => "client_close",
+            DisconnectReason::StreamError => "stream_error",
+            DisconnectReason::SendError => "send_error",
+            DisconnectReason::Heartbeat => "heartbeat_timeout",
+            DisconnectReason::Evicted => "evicted",
+            DisconnectReason::FrameTooLarge => "frame_too_large",
+            DisconnectReason::TokenRevoked => "token_revoked",
+            DisconnectReason::Idle => "idle",
+            DisconnectReason::AdminDisconnect => "admin_disconnect",
+            DisconnectReason::Shutdown => "shutdown",
+        }
+    }
+
+    // Whether the socket is still usable and the client hasn't closed, so queued messages can go out first
+    fn drains_queue(self) -> bool {
+        matches!(
+            self,
+            DisconnectReason::Evicted
+                | DisconnectReason::TokenRevoked
+                | DisconnectReason::Idle
+                | DisconnectReason::AdminDisconnect
+                | DisconnectReason::Shutdown
+        )
+    }
+
+    // Close frame for a server-initiated close. 4xxx codes are ours: 4001 means re-authenticate
+    // before reconnecting, the others that reconnecting as-is is fine. Client closes are echoed instead.
+    fn close_reason(self) -> Option<actix_ws::CloseReason> {
+        let (code, description) = match self {
+            DisconnectReason::ClientClose => return None,
+            DisconnectReason::StreamError | DisconnectReason::SendError => (actix_ws::CloseCode::Error, "connection error"),
+            DisconnectReason::FrameTooLarge => (actix_ws::CloseCode::Protocol, "frame too large"),
+            DisconnectReason::Shutdown => (actix_ws::CloseCode::Away, "server shutting down"),
+            DisconnectReason::TokenRevoked => (actix_ws::CloseCode::Other(4001), "token revoked"),
+            DisconnectReason::Evicted => (actix_ws::CloseCode::Other(4002), "evicted"),
+            DisconnectReason::Idle => (actix_ws::CloseCode::Other(4003), "idle"),
+            DisconnectReason::AdminDisconnect => (actix_ws::CloseCode::Other(4004), "disconnected"),
+            DisconnectReason::Heartbeat => (actix_ws::CloseCode::Other(4005), "heartbeat timeout"),
+        };
+        Some(actix_ws::CloseReason { code, description: Some(description.to_string()) })
+    }
+}
+
+// Control messages after which the server ends the session (the sender is dropped right
+// after them, except on shutdown), mapped to the reason the session is closed with
+fn terminal_reason(msg: &str) -> Option<DisconnectReason> {
+    static TERMINAL: std::sync::LazyLock<[(String, DisconnectReason); 5]> = std::sync::LazyLock::new(|| [
+        (OutgoingMessage::TokenRevoked.to_json(), DisconnectReason::TokenRevoked),
+        (OutgoingMessage::SessionEvicted.to_json(), DisconnectReason::Evicted),
+        (OutgoingMessage::SessionIdle.to_json(), DisconnectReason::Idle),
+        (OutgoingMessage::Disconnected.to_json(), DisconnectReason::AdminDisconnect),
+        (OutgoingMessage::Shutdown.to_json(), DisconnectReason::Shutdown),
+    ]);
+    TERMINAL.iter().find(|(terminal, _)| terminal == msg).map(|(_, reason)| *reason)
+}
+
+// Sends the messages already queued on both lanes (priority first) without waiting for more.
+// Repeated terminal messages are dropped since the session is closing anyway. Returns how many were sent.
+async fn drain_queued(
+    session: &mut actix_ws::Session,
+    wire: Wire,
+    rx: &mut mpsc::Receiver<String>,
+    priority_rx: &mut mpsc::Receiver<String>,
+) -> usize {
+    let mut sent = 0;
+    while let Ok(msg) = priority_rx.try_recv().or_else(|_| rx.try_recv()) {
+        if terminal_reason(&msg).is_some() {
+            continue;
+        }
+        if send_encoded(session, wire, msg).await.is_err() {
+            break;
+        }
+        sent += 1;
+    }
+    sent
+}
+
+// Ends a session. On a graceful server-side close, what's still queued is delivered first rather
+// than dropped (a stuck socket only holds this up for CLOSE_DRAIN_TIMEOUT). A client's close is echoed.
+async fn close_session(
+    mut session: actix_ws::Session,
+    wire: Wire,
+    rx: &mut mpsc::Receiver<String>,
+    priority_rx: &mut mpsc::Receiver<String>,
+    disconnect_reason: DisconnectReason,
+    client_reason: Option<actix_ws::CloseReason>,
+    session_id: Uuid,
+) {
+    if disconnect_reason.drains_queue() {
+        match tokio::time::timeout(CLOSE_DRAIN_TIMEOUT, drain_queued(&mut session, wire, rx, priority_rx)).await {
+            Ok(0) => {}
+            Ok(sent) => log::debug!("[WS] Flushed {} queued messages to session {} before closing", sent, session_id),
+            Err(_) => log::warn!("[WS] Session {} didn't take its queued messages within {:?}, closing anyway", session_id, CLOSE_DRAIN_TIMEOUT),
+        }
+    }
+    let _ = session.close(client_reason.or_else(|| disconnect_reason.close_reason())).await;
+}
+
+// The session token, from the first source that has one: `?token=`, `Authorization: Bearer`,
+// then a `procache.token.<token>` Sec-WebSocket-Protocol entry (for proxies that strip query strings)
+fn extract_token(req: &HttpRequest) -> Option<String> {
+    let from_query = form_urlencoded::parse(req.query_string().as_bytes())
+        .find(|(k, _)| k == "token")
+        .map(|(_, v)| v.to_string())
+        .filter(|t| !t.is_empty());
+    let from_header = || {
+        req.headers()
+            .get(actix_web::http::header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .map(|t| t.trim().to_string())
+            .filter(|t| !t.is_empty())
+    };
+    let from_protocol = || {
+        req.headers()
+            .get_all(actix_web::http::header::SEC_WEBSOCKET_PROTOCOL)
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .find_map(|p| p.trim().strip_prefix(TOKEN_PROTOCOL_PREFIX).filter(|t| !t.is_empty()).map(String::from))
+    };
+    from_query.or_else(from_header).or_else(from_protocol)
+}
+
+// 401 with a JSON error by default; with WS_STEALTH a bare 404, like the internal scope
+fn auth_failure(data: &AppState, code: &str, message: &str) -> HttpResponse {
+    if data.ws_stealth {
+        HttpResponse::NotFound().finish()
+    } else {
+        error_response(StatusCode::UNAUTHORIZED, code, message)
+    }
+}
+
+// Wire encoding negotiated per connection via ?encoding=msgpack (JSON text by default)
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Encoding {
+    Json,
+    MsgPack,
+}
+
+// Everything negotiated about a connection's frames: the encoding, and with ?compact=1 numeric
+// paths sent as `"numeric": [[id, ts], ...]` instead of string keys in `data`
+#[derive(Debug, Clone, Copy)]
+struct Wire {
+    encoding: Encoding,
+    compact: bool,
+}
+
+// Messages are produced as JSON strings; they're rewritten for compact sessions, and
+// MessagePack sessions re-encode them as binary frames.
+async fn send_encoded(session: &mut actix_ws::Session, wire: Wire, msg: String) -> Result<(), actix_ws::Closed> {
+    let msg = if wire.compact { compact_message(msg) } else { msg };
+    match wire.encoding {
+        Encoding::Json => session.text(msg).await,
+        Encoding::MsgPack => match json_to_msgpack(&msg) {
+            Ok(bytes) => session.binary(bytes).await,
+            Err(e) => {
+                log::warn!("[WS] Failed to encode message as MessagePack, sending JSON: {}", e);
+                session.text(msg).await
+            }
+        },
+    }
+}
+
+// An encoded initial sync: text is still subject to the session encoding, binary is sent as-is
+enum SyncFrame {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+// If the sync can't be encoded the client still gets a (coarse) sync rather than nothing
+fn sync_frame(
+    project_id: &str,
+    sync: OutgoingMessage,
+    encode: impl FnOnce(&OutgoingMessage) -> Result<SyncFrame, String>,
+) -> SyncFrame {
+    match encode(&sync) {
+        Ok(frame) => frame,
+        Err(e) => {
+            log::error!("[WS] Failed to encode initial sync for project {}, sending the all fallback: {}", project_id, e);
+            SyncFrame::Text(sync_fallback(sync).to_json())
+        }
+    }
+}
+
+// Large syncs can be requested as a gzip-compressed binary frame (?compress=gzip)
+fn encode_sync(sync: &impl serde::Serialize, wire: Wire, gzip_sync: bool) -> Result<SyncFrame, String> {
+    let mut sync = serde_json::to_value(sync).map_err(|e| e.to_string())?;
+    if wire.compact {
+        compact_numeric_paths(&mut sync);
+    }
+    let bytes = match wire.encoding {
+        Encoding::Json if !gzip_sync => return serde_json::to_string(&sync).map(SyncFrame::Text).map_err(|e| e.to_string()),
+        Encoding::Json => serde_json::to_vec(&sync).map_err(|e| e.to_string())?,
+        Encoding::MsgPack => rmp_serde::to_vec_named(&sync).map_err(|e| e.to_string())?,
+    };
+    if gzip_sync {
+        gzip(&bytes).map(SyncFrame::Binary).map_err(|e| e.to_string())
+    } else {
+        Ok(SyncFrame::Binary(bytes))
+    }
+}
+
+// Compact form of a message (unchanged if it has nothing to compact or isn't JSON)
+fn compact_message(msg: String) -> String {
+    let Ok(mut value) = serde_json::from_str::<serde_json::Value>(&msg) else {
+        return msg;
+    };
+    if compact_numeric_paths(&mut value) { value.to_string() } else { msg }
+}
+
+// Moves the bare-timestamp entries of a sync/delta `data` whose path is a plain number into
+// `"numeric": [[id, ts], ...]`; string paths (and versioned entries) stay in `data`.
+// Returns whether anything moved.
+fn compact_numeric_paths(msg: &mut serde_json::Value) -> bool {
+    if msg["type"] != "invalidate" && msg["type"] != "invalidate-delta" {
+        return false;
+    }
+    let Some(data) = msg.get_mut("data").and_then(|d| d.as_object_mut()) else {
+        return false;
+    };
+    // Only keys that survive the round trip: "007" stays a string path
+    let numeric_keys: Vec<String> = data
+        .iter()
+        .filter(|(path, ts)| ts.is_i64() && path.parse::<u64>().is_ok_and(|id| id.to_string() == **path))
+        .map(|(path, _)| path.clone())
+        .collect();
+    if numeric_keys.is_empty() {
+        return false;
+    }
+    let numeric: Vec<serde_json::Value> = numeric_keys
+        .iter()
+        .filter_map(|path| {
+            let ts = data.remove(path)?;
+            Some(serde_json::json!([path.parse::<u64>().ok()?, ts]))
+        })
+        .collect();
+    msg["numeric"] = serde_json::Value::Array(numeric);
+    true
+}
+
+fn json_to_msgpack(json: &str) -> Result<Vec<u8>, String> {
+    let value: serde_json::Value = serde_json::from_str(json).map_err(|e| e.to_string())?;
+    rmp_serde::to_vec_named(&value).map_err(|e| e.to_string())
+}
+
+// Full-state `invalidate` message for a project, restricted to `filters` prefixes (empty = everything).
+// With `newer_than` it only carries routes invalidated after that timestamp and is marked `partial`
+// (routes missing from it are unchanged, not gone).
+// Past MAX_SYNC_ROUTES routes it's collapsed to `"all": <newest timestamp>` with empty `data`:
+// much smaller, but the client can no longer tell which routes changed and has to drop
+// everything cached before that timestamp (clients that ignore `all` just clear their cache).
+fn initial_sync_message(data: &AppState, project_id: &str, filters: &[String], newer_than: Option<i64>) -> OutgoingMessage {
+    let current_seq = data.replay.current_seq(project_id);
+    let mut initial_routes = data.compute_initial_sync(project_id);
+
+    if !filters.is_empty() {
+        initial_routes.retain(|path, _| filters.iter().any(|f| path.starts_with(f.as_str())));
+    }
+    if let Some(ts) = newer_than {
+        initial_routes.retain(|_, v| route_timestamp(v).is_some_and(|t| t > ts));
+    }
+
+    let too_large = data.max_sync_routes.is_some_and(|max| initial_routes.len() > max);
+    let message = OutgoingMessage::Sync {
+        data: initial_routes,
+        drift_time: data.clock.drift_time(),
+        // Baseline for gap detection: the next live message will have seq > this
+        seq: current_seq,
+        partial: newer_than.map(|_| true),
+        since_ts: newer_than,
+        all: None,
+    };
+    if too_large { sync_fallback(message) } else { message }
+}
+
+// A sync collapsed to `"all": <newest route timestamp>` with empty `data`.
+// Routes are seeded at the baseline, so the timestamp is never older than it.
+// A collapsed partial sync is no longer partial: `all` applies to everything cached.
+fn sync_fallback(sync: OutgoingMessage) -> OutgoingMessage {
+    match sync {
+        OutgoingMessage::Sync { data, drift_time, seq, .. } => OutgoingMessage::Sync {
+            all: data.values().filter_map(route_timestamp).max(),
+            data: serde_json::Map::new(),
+            drift_time,
+            seq,
+            partial: None,
+            since_ts: None,
+        },
+        other => other,
+    }
+}
+
+// Sync entries are a bare timestamp or `{ "timestamp", ... }` for versioned routes
+fn route_timestamp(v: &serde_json::Value) -> Option<i64> {
+    v.as_i64().or_else(|| v["timestamp"].as_i64())
+}
+
+// Client -> server control messages. Returns a frame to send back, if any.
+fn handle_client_message(
+    text: &str,
+    state: &AppState,
+    project_id: &str,
+    session_id: Uuid,
+    filters: &parking_lot::Mutex<Vec<String>>,
+) -> Option<String> {
+    let msg = serde_json::from_str::<serde_json::Value>(text).ok()?;
+
+    // { "type": "resync" }: full state again without reconnecting
+    if msg["type"] == "resync" {
+        let filters = filters.lock().clone();
+        let sync = initial_sync_message(state, project_id, &filters, None);
+        return Some(match sync.to_value() {
+            Ok(value) => value.to_string(),
+            Err(e) => {
+                log::error!("[WS] Failed to encode resync for project {}, sending the all fallback: {}", project_id, e);
+                sync_fallback(sync).to_json()
+            }
+        });
+    }
+
+    // { "type": "ping-state" }: cheap "am I up to date?" check (compare seq with the last one seen)
+    if msg["type"] == "ping-state" {
+        return Some(OutgoingMessage::State {
+            seq: state.replay.current_seq(project_id),
+            drift_time: state.clock.drift_time(),
+            server_time: chrono::Utc::now().timestamp_millis(),
+        }.to_json());
+    }
+
+    // { "type": "subscribe", "prefixes": ["/orders", "/cart"] } (empty list = everything)
+    if msg["type"] == "subscribe" {
+        let prefixes: Vec<String> = msg["prefixes"]
+            .as_array()
+            .map(|a| a.iter().filter_map(|p| p.as_str().map(String::from)).collect())
+            .unwrap_or_default();
+        *filters.lock() = prefixes;
+    }
+
+    // { "type": "latency", "sent_at": ... }: echo of a measured broadcast's stamp
+    if msg["type"] == "latency" {
+        if let Some(sent_at) = msg["sent_at"].as_u64() {
+            state.record_latency(sent_at);
+        }
+    }
+
+    // { "type": "ack", "ack_id": "..." }
+    if msg["type"] == "ack" {
+        if let Some(ack_id) = msg["ack_id"].as_str() {
+            state.acks.record(ack_id, session_id);
+        }
+    }
+
+    None
+}
+
+fn gzip(bytes: &[u8]) -> std::io::Result<Vec<u8>> {
+    use std::io::Write as _;
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(bytes)?;
+    encoder.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::app_state;
+
+    // Fails like a map with non-string keys would
+    struct Unserializable;
+
+    impl serde::Serialize for Unserializable {
+        fn serialize<S: serde::Serializer>(&self, _: S) -> Result<S::Ok, S::Error> {
+            Err(serde::ser::Error::custom("unserializable"))
+        }
+    }
+
+    const JSON: Wire = Wire { encoding: Encoding::Json, compact: false };
+
+    fn sync(routes: &[(&str, i64)]) -> OutgoingMessage {
+        OutgoingMessage::Sync {
+            data: routes.iter().map(|(path, ts)| (path.to_string(), serde_json::json!(ts))).collect(),
+            drift_time: 0,
+            seq: 7,
+            partial: None,
+            since_ts: None,
+            all: None,
+        }
+    }
+
+    #[test]
+    fn encode_failure_is_reported() {
+        assert!(encode_sync(&Unserializable, JSON, false).is_err());
+        assert!(encode_sync(&Unserializable, JSON, true).is_err());
+    }
+
+    #[test]
+    fn failed_sync_falls_back_to_all() {
+        let frame = sync_frame("p", sync(&[("/a", 10), ("/b", 30)]), |_| encode_sync(&Unserializable, JSON, false));
+        let SyncFrame::Text(text) = frame else {
+            panic!("fallback is a text frame");
+        };
+        let value: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(value["all"], 30);
+        assert_eq!(value["seq"], 7);
+        assert_eq!(value["data"], serde_json::json!({}));
+    }
+
+    #[test]
+    fn encodable_sync_is_sent_as_is() {
+        let frame = sync_frame("p", sync(&[("/a", 10)]), |s| encode_sync(s, JSON, false));
+        let SyncFrame::Text(text) = frame else {
+            panic!("uncompressed JSON sync is a text frame");
+        };
+        let value: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(value["data"]["/a"], 10);
+        assert!(value.get("all").is_none());
+    }
+
+    #[test]
+    fn gzip_sync_decompresses_to_the_json_sync() {
+        use std::io::Read as _;
+
+        let message = sync(&[("/a", 10), ("/b", 30)]);
+        let SyncFrame::Binary(compressed) = encode_sync(&message, JSON, true).unwrap() else {
+            panic!("gzip sync is a binary frame");
+        };
+        let mut json = String::new();
+        flate2::read::GzDecoder::new(compressed.as_slice()).read_to_string(&mut json).unwrap();
+
+        let decompressed: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(decompressed, serde_json::to_value(&message).unwrap());
+    }
+
+    #[test]
+    fn msgpack_sync_round_trips() {
+        let message = sync(&[("/a", 10), ("/b", 30)]);
+        let wire = Wire { encoding: Encoding::MsgPack, compact: false };
+        let SyncFrame::Binary(bytes) = encode_sync(&message, wire, false).unwrap() else {
+            panic!("msgpack sync is a binary frame");
+        };
+        let decoded: serde_json::Value = rmp_serde::from_slice(&bytes).unwrap();
+        assert_eq!(decoded, serde_json::to_value(&message).unwrap());
+    }
+
+    #[test]
+    fn msgpack_delta_round_trips() {
+        let delta = serde_json::json!({ "type": "invalidate-delta", "data": { "/a": 10 }, "drift_time": 0, "seq": 3 });
+        let bytes = json_to_msgpack(&delta.to_string()).unwrap();
+        let decoded: serde_json::Value = rmp_serde::from_slice(&bytes).unwrap();
+        assert_eq!(decoded, delta);
+    }
+
+    // What a compact client does: moves `numeric` back into `data` under string keys
+    fn expand_numeric_paths(mut msg: serde_json::Value) -> serde_json::Value {
+        if let Some(serde_json::Value::Array(numeric)) = msg.as_object_mut().unwrap().remove("numeric") {
+            for entry in numeric {
+                msg["data"][entry[0].to_string()] = entry[1].clone();
+            }
+        }
+        msg
+    }
+
+    #[test]
+    fn compact_mixed_paths_round_trip() {
+        let delta = serde_json::json!({
+            "type": "invalidate-delta",
+            "data": { "42": 10, "7": 20, "007": 30, "/a": 40, "99": { "timestamp": 50, "etag": "v2" } },
+            "drift_time": 0,
+            "seq": 3,
+        });
+        let compacted: serde_json::Value = serde_json::from_str(&compact_message(delta.to_string())).unwrap();
+        // Only bare timestamps of canonical numbers move; everything else stays string-keyed
+        assert_eq!(compacted["data"], serde_json::json!({ "007": 30, "/a": 40, "99": { "timestamp": 50, "etag": "v2" } }));
+        let mut numeric = compacted["numeric"].as_array().unwrap().clone();
+        numeric.sort_by_key(|entry| entry[0].as_u64());
+        assert_eq!(numeric, vec![serde_json::json!([7, 20]), serde_json::json!([42, 10])]);
+        // A numeric path carrying an etag keeps its object value instead of an [id, ts] pair
+        assert!(numeric.iter().all(|entry| entry[0] != 99));
+        assert_eq!(expand_numeric_paths(compacted), delta);
+
+        let compact = Wire { encoding: Encoding::Json, compact: true };
+        let SyncFrame::Text(text) = encode_sync(&sync(&[("12", 10), ("/b", 30)]), compact, false).unwrap() else {
+            panic!("uncompressed JSON sync is a text frame");
+        };
+        let compacted: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(compacted["numeric"], serde_json::json!([[12, 10]]));
+        assert_eq!(expand_numeric_paths(compacted), serde_json::to_value(sync(&[("12", 10), ("/b", 30)])).unwrap());
+    }
+
+    #[test]
+    fn messages_without_numeric_paths_are_not_compacted() {
+        let delta = serde_json::json!({ "type": "invalidate-delta", "data": { "/a": 10 }, "drift_time": 0, "seq": 3 }).to_string();
+        assert_eq!(compact_message(delta.clone()), delta);
+        let flush = serde_json::json!({ "type": "flush", "data": { "1": 10 } }).to_string();
+        assert_eq!(compact_message(flush.clone()), flush);
+        assert_eq!(compact_message("not json".to_string()), "not json");
+    }
+
+    fn token_of(req: actix_web::test::TestRequest) -> Option<String> {
+        extract_token(&req.to_http_request())
+    }
+
+    #[test]
+    fn token_is_read_from_query_header_or_subprotocol() {
+        use actix_web::test::TestRequest;
+
+        assert_eq!(token_of(TestRequest::get().uri("/ws?token=q")).as_deref(), Some("q"));
+        assert_eq!(token_of(TestRequest::get().insert_header(("authorization", "Bearer h"))).as_deref(), Some("h"));
+        let protocols = ("sec-websocket-protocol", "procache.v1, procache.token.s");
+        assert_eq!(token_of(TestRequest::get().insert_header(protocols)).as_deref(), Some("s"));
+        assert_eq!(token_of(TestRequest::get()), None);
+        assert_eq!(token_of(TestRequest::get().uri("/ws?token=").insert_header(("authorization", "Basic x"))), None);
+    }
+
+    #[test]
+    fn query_token_wins_over_header_and_header_over_subprotocol() {
+        use actix_web::test::TestRequest;
+
+        let all = TestRequest::get()
+            .uri("/ws?token=q")
+            .insert_header(("authorization", "Bearer h"))
+            .insert_header(("sec-websocket-protocol", "procache.token.s"));
+        assert_eq!(token_of(all).as_deref(), Some("q"));
+        let no_query = TestRequest::get()
+            .insert_header(("authorization", "Bearer h"))
+            .insert_header(("sec-websocket-protocol", "procache.token.s"));
+        assert_eq!(token_of(no_query).as_deref(), Some("h"));
+    }
+
+    // A GET carrying the WebSocket upgrade handshake headers
+    fn handshake_request() -> actix_web::test::TestRequest {
+        actix_web::test::TestRequest::get()
+            .insert_header(("connection", "upgrade"))
+            .insert_header(("upgrade", "websocket"))
+            .insert_header(("sec-websocket-version", "13"))
+            .insert_header(("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ=="))
+    }
+
+    async fn upgrade(data: web::Data<AppState>, token: &str, protocols: Option<&str>) -> actix_web::dev::ServiceResponse {
+        let app = actix_web::test::init_service(
+            actix_web::App::new().app_data(data).route("/ws", web::get().to(ws_handler)),
+        ).await;
+        let mut req = handshake_request().uri(&format!("/ws?token={}", token));
+        if let Some(protocols) = protocols {
+            req = req.insert_header(("sec-websocket-protocol", protocols));
+        }
+        actix_web::test::call_service(&app, req.to_request()).await
+    }
+
+    #[test]
+    fn sync_past_max_sync_routes_collapses_to_all() {
+        let mut state = app_state();
+        state.max_sync_routes = Some(2);
+        let baseline = state.clock.baseline();
+        state.routes.register("p", &["/a".to_string(), "/b".to_string()]);
+        state.invalidations.set_timestamps("p", [("/b".to_string(), baseline + 50)]);
+
+        // At the threshold every route is still listed
+        let value = serde_json::to_value(initial_sync_message(&state, "p", &[], None)).unwrap();
+        assert_eq!(value["data"].as_object().map(|d| d.len()), Some(2));
+        assert!(value.get("all").is_none());
+
+        // One past it, the newest timestamp stands in for all of them
+        state.routes.register("p", &["/c".to_string()]);
+        let value = serde_json::to_value(initial_sync_message(&state, "p", &[], None)).unwrap();
+        assert_eq!(value["data"], serde_json::json!({}));
+        assert_eq!(value["all"], baseline + 50);
+    }
+
+    async fn connect(data: web::Data<AppState>, token: &str) -> StatusCode {
+        upgrade(data, token, None).await.status()
+    }
+
+    fn protocol_header(res: &actix_web::dev::ServiceResponse) -> Option<&str> {
+        res.headers().get(actix_web::http::header::SEC_WEBSOCKET_PROTOCOL).and_then(|v| v.to_str().ok())
+    }
+
+    fn token_data(ttl: u64, age: Duration) -> crate::state::TokenData {
+        crate::state::TokenData {
+            user_id: "u".to_string(),
+            project_id: "p".to_string(),
+            created_at: Instant::now() - age,
+            ttl,
+            one_time: false,
+        }
+    }
+
+    fn register(data: &AppState, token: &str, ttl: u64, age: Duration) {
+        data.tokens.register(token, token_data(ttl, age));
+    }
+
+    #[actix_web::test]
+    async fn expired_token_is_rejected_and_dropped() {
+        let data = web::Data::new(app_state());
+        // A 1s TTL token, connecting 2s after it was registered
+        register(&data, "short-lived", 1, Duration::from_secs(2));
+
+        assert_eq!(connect(data.clone(), "short-lived").await, StatusCode::UNAUTHORIZED);
+        assert!(data.tokens.get("short-lived").is_none());
+        assert!(data.tokens.active_token_of("p", "u").is_none());
+    }
+
+    #[actix_web::test]
+    async fn zero_ttl_token_never_expires() {
+        let data = web::Data::new(app_state());
+        register(&data, "forever", 0, Duration::from_secs(2));
+
+        assert_eq!(connect(data.clone(), "forever").await, StatusCode::SWITCHING_PROTOCOLS);
+        assert!(data.tokens.get("forever").is_some());
+    }
+
+    #[actix_web::test]
+    async fn supported_subprotocol_is_echoed() {
+        let data = web::Data::new(app_state());
+        register(&data, "t", 0, Duration::ZERO);
+
+        let res = upgrade(data.clone(), "t", Some("procache.v9, procache.v1")).await;
+        assert_eq!(res.status(), StatusCode::SWITCHING_PROTOCOLS);
+        assert_eq!(protocol_header(&res), Some("procache.v1"));
+    }
+
+    #[actix_web::test]
+    async fn unknown_only_subprotocols_are_refused() {
+        let data = web::Data::new(app_state());
+        register(&data, "t", 0, Duration::ZERO);
+
+        let res = upgrade(data.clone(), "t", Some("procache.v9")).await;
+        assert_eq!(res.status(), StatusCode::UPGRADE_REQUIRED);
+        assert_eq!(protocol_header(&res), Some("procache.v1"));
+    }
+
+    #[actix_web::test]
+    async fn auth_failure_is_a_json_error() {
+        let data = web::Data::new(app_state());
+        let res = upgrade(data, "unknown", None).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let body: serde_json::Value = serde_json::from_slice(&actix_web::test::read_body(res).await).unwrap();
+        assert_eq!(body["status"], "error");
+        assert_eq!(body["code"], "invalid_token");
+    }
+
+    #[actix_web::test]
+    async fn one_time_token_is_rejected_on_the_second_connect() {
+        let data = web::Data::new(app_state());
+        data.tokens.register("once", crate::state::TokenData { one_time: true, ..token_data(0, Duration::ZERO) });
+
+        assert_eq!(connect(data.clone(), "once").await, StatusCode::SWITCHING_PROTOCOLS);
+        assert!(data.tokens.get("once").is_none());
+        assert_eq!(connect(data.clone(), "once").await, StatusCode::UNAUTHORIZED);
+
+        // A regular token for the same user stays valid across reconnects
+        register(&data, "reusable", 0, Duration::ZERO);
+        for _ in 0..2 {
+            assert_eq!(connect(data.clone(), "reusable").await, StatusCode::SWITCHING_PROTOCOLS);
+        }
+    }
+
+    // Closes a session with `reason` after queueing `routine` and `priority` messages, and returns
+    // the frames (opcode, payload) it wrote. Server frames are unmasked; payloads here stay under 126 bytes.
+    async fn close_with_queued(reason: DisconnectReason, routine: &[&str], priority: &[&str]) -> Vec<(u8, Vec<u8>)> {
+        let (req, mut payload) = handshake_request().to_http_parts();
+        let payload = <web::Payload as actix_web::FromRequest>::from_request(&req, &mut payload).await.unwrap();
+        let (res, session, _stream) = actix_ws::handle(&req, payload).unwrap();
+        let (tx, mut rx) = mpsc::channel(8);
+        let (priority_tx, mut priority_rx) = mpsc::channel(8);
+        routine.iter().for_each(|msg| tx.try_send(msg.to_string()).unwrap());
+        priority.iter().for_each(|msg| priority_tx.try_send(msg.to_string()).unwrap());
+
+        close_session(session, JSON, &mut rx, &mut priority_rx, reason, None, Uuid::nil()).await;
+
+        let bytes = actix_web::body::to_bytes(res.into_body()).await.unwrap();
+        let mut frames = Vec::new();
+        let mut rest = &bytes[..];
+        while let [first, len, ..] = *rest {
+            let len = usize::from(len & 0x7f);
+            frames.push((first & 0x0f, rest[2..2 + len].to_vec()));
+            rest = &rest[2 + len..];
+        }
+        frames
+    }
+
+    fn close_code(frame: &(u8, Vec<u8>)) -> u16 {
+        assert_eq!(frame.0, 0x8, "expected a close frame");
+        u16::from_be_bytes([frame.1[0], frame.1[1]])
+    }
+
+    #[actix_web::test]
+    async fn queued_messages_are_delivered_before_an_intentional_close() {
+        let disconnected = OutgoingMessage::Disconnected.to_json();
+        let frames = close_with_queued(DisconnectReason::AdminDisconnect, &["routine", &disconnected], &["urgent"]).await;
+
+        // Priority lane first; the repeated terminal message is dropped
+        assert_eq!(frames[..2], [(0x1, b"urgent".to_vec()), (0x1, b"routine".to_vec())]);
+        assert_eq!(frames.len(), 3);
+        assert_eq!(close_code(&frames[2]), 4004);
+    }
+
+    #[actix_web::test]
+    async fn broken_connections_close_without_draining() {
+        let frames = close_with_queued(DisconnectReason::Heartbeat, &["routine"], &[]).await;
+        assert_eq!(frames.len(), 1);
+        assert_eq!(close_code(&frames[0]), 4005);
+    }
+
+    // Minimal client for exercising a live session over a real socket
+    struct WsClient {
+        stream: tokio::net::TcpStream,
+        buf: Vec<u8>,
+    }
+
+    impl WsClient {
+        async fn open(addr: std::net::SocketAddr, token: &str) -> WsClient {
+            use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+            let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let handshake = format!(
+                "GET /ws?token={} HTTP/1.1\r\nHost: {}\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\
+                 Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+                token, addr
+            );
+            stream.write_all(handshake.as_bytes()).await.unwrap();
+
+            let mut buf = Vec::new();
+            let header_end = loop {
+                if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                    break end + 4;
+                }
+                let mut chunk = [0; 1024];
+                let n = stream.read(&mut chunk).await.unwrap();
+                assert!(n > 0, "connection closed during the handshake");
+                buf.extend_from_slice(&chunk[..n]);
+            };
+            assert!(buf.starts_with(b"HTTP/1.1 101"), "{}", String::from_utf8_lossy(&buf));
+            buf.drain(..header_end);
+            WsClient { stream, buf }
+        }
+
+        // (opcode, payload) of the next server frame (server frames are never masked)
+        async fn frame(&mut self) -> (u8, Vec<u8>) {
+            use tokio::io::AsyncReadExt as _;
+
+            loop {
+                if self.buf.len() >= 2 {
+                    let (len, offset) = match self.buf[1] & 0x7f {
+                        126 if self.buf.len() >= 4 => (u16::from_be_bytes([self.buf[2], self.buf[3]]) as usize, 4),
+                        127 if self.buf.len() >= 10 => (u64::from_be_bytes(self.buf[2..10].try_into().unwrap()) as usize, 10),
+                        126 | 127 => (usize::MAX, 0),
+                        len => (len as usize, 2),
+                    };
+                    if len != usize::MAX && self.buf.len() >= offset + len {
+                        let opcode = self.buf[0] & 0x0f;
+                        let payload = self.buf[offset..offset + len].to_vec();
+                        self.buf.drain(..offset + len);
+                        return (opcode, payload);
+                    }
+                }
+                let mut chunk = [0; 4096];
+                let read = tokio::time::timeout(Duration::from_secs(5), self.stream.read(&mut chunk)).await;
+                let n = read.expect("no frame within 5s").unwrap();
+                assert!(n > 0, "connection closed before the next frame");
+                self.buf.extend_from_slice(&chunk[..n]);
+            }
+        }
+
+        async fn text(&mut self) -> serde_json::Value {
+            let (opcode, payload) = self.frame().await;
+            assert_eq!(opcode, 0x1, "expected a text frame");
+            serde_json::from_slice(&payload).unwrap()
+        }
+
+        async fn send_text(&mut self, payload: &[u8]) {
+            use tokio::io::AsyncWriteExt as _;
+
+            let mask = [0x12, 0x34, 0x56, 0x78];
+            let mut frame = vec![0x81];
+            match payload.len() {
+                len if len < 126 => frame.push(0x80 | len as u8),
+                len if len <= u16::MAX as usize => {
+                    frame.push(0x80 | 126);
+                    frame.extend_from_slice(&(len as u16).to_be_bytes());
+                }
+                len => {
+                    frame.push(0x80 | 127);
+                    frame.extend_from_slice(&(len as u64).to_be_bytes());
+                }
+            }
+            frame.extend_from_slice(&mask);
+            frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+            self.stream.write_all(&frame).await.unwrap();
+        }
+    }
+
+    // Serves ws_handler on an ephemeral local port
+    fn serve(data: web::Data<AppState>) -> std::net::SocketAddr {
+        let server = actix_web::HttpServer::new(move || {
+            actix_web::App::new().app_data(data.clone()).route("/ws", web::get().to(ws_handler))
+        })
+        .workers(1)
+        .disable_signals()
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let addr = server.addrs()[0];
+        actix_rt::spawn(server.run());
+        addr
+    }
+
+    // A client on a fresh server, connected with a regular token for user "u" of project "p"
+    async fn connected_client(data: &web::Data<AppState>) -> WsClient {
+        register(data, "t", 0, Duration::ZERO);
+        WsClient::open(serve(data.clone()), "t").await
+    }
+
+    #[actix_web::test]
+    async fn oversized_frame_closes_with_a_protocol_error() {
+        let mut state = app_state();
+        state.ws_max_frame_size = 64;
+        let data = web::Data::new(state);
+        let mut client = connected_client(&data).await;
+
+        client.send_text(&[b'x'; 65]).await;
+        let close = loop {
+            match client.frame().await {
+                frame @ (0x8, _) => break frame,
+                _ => continue, // The sync and status frames
+            }
+        };
+        assert_eq!(close_code(&close), 1002);
+        assert_eq!(&close.1[2..], b"frame too large");
+    }
+
+    #[actix_web::test]
+    async fn revoked_token_closes_with_its_own_code() {
+        let data = web::Data::new(app_state());
+        let mut client = connected_client(&data).await;
+        // Sync and status: the session is registered
+        client.text().await;
+        client.text().await;
+
+        assert_eq!(data.revoke_project_tokens("p"), (1, 1));
+        assert_eq!(client.text().await["type"], "token-revoked");
+        let close = client.frame().await;
+        assert_eq!(close_code(&close), 4001);
+        assert_eq!(&close.1[2..], b"token revoked");
+    }
+
+    #[actix_web::test]
+    async fn connected_status_follows_the_sync() {
+        let data = web::Data::new(app_state());
+        let mut client = connected_client(&data).await;
+
+        assert_eq!(client.text().await["type"], "invalidate");
+        let status = client.text().await;
+        assert_eq!(status["type"], "ws-status");
+        assert_eq!(status["status"], "connected");
+        assert!(status["session_id"].as_str().is_some_and(|id| Uuid::parse_str(id).is_ok()));
+        assert!(status["server_time"].as_i64().is_some());
+    }
+
+    #[actix_web::test]
+    async fn connected_status_can_precede_the_sync() {
+        let mut state = app_state();
+        state.status_before_sync = true;
+        let data = web::Data::new(state);
+        let mut client = connected_client(&data).await;
+
+        let status = client.text().await;
+        assert_eq!(status["type"], "ws-status");
+        // Registered before the status went out, under the id it reports
+        let registered = data.sessions.matching("p", |id, _| Some(id.to_string()));
+        assert_eq!(registered, vec![status["session_id"].as_str().unwrap().to_string()]);
+        assert_eq!(client.text().await["type"], "invalidate");
+    }
+
+    #[test]
+    fn sync_since_a_timestamp_only_carries_newer_routes() {
+        let state = app_state();
+        let baseline = state.clock.baseline();
+        state.routes.register("p", &["/old".to_string(), "/new".to_string(), "/untouched".to_string()]);
+        state.invalidations.set_timestamps("p", [("/old".to_string(), baseline + 10), ("/new".to_string(), baseline + 30)]);
+
+        let value = serde_json::to_value(initial_sync_message(&state, "p", &[], Some(baseline + 20))).unwrap();
+        assert_eq!(value["data"], serde_json::json!({ "/new": baseline + 30 }));
+        assert_eq!(value["partial"], true);
+        assert_eq!(value["since_ts"], baseline + 20);
+    }
+
+    #[actix_web::test]
+    async fn since_older_than_the_baseline_gets_the_full_sync() {
+        let data = web::Data::new(app_state());
+        let baseline = data.clock.baseline();
+        data.routes.register("p", &["/a".to_string(), "/b".to_string()]);
+        data.invalidations.set_timestamps("p", [("/b".to_string(), baseline + 30)]);
+        register(&data, "t", 0, Duration::ZERO);
+        let addr = serve(data.clone());
+
+        let sync = WsClient::open(addr, &format!("t&since_ts={}", baseline - 1)).await.text().await;
+        assert_eq!(sync["data"], serde_json::json!({ "/a": baseline, "/b": baseline + 30 }));
+        assert!(sync.get("partial").is_none());
+
+        let sync = WsClient::open(addr, &format!("t&since_ts={}", baseline)).await.text().await;
+        assert_eq!(sync["data"], serde_json::json!({ "/b": baseline + 30 }));
+        assert_eq!(sync["partial"], true);
+    }
+
+    #[actix_web::test]
+    async fn first_ping_waits_for_the_grace_window() {
+        let mut state = app_state();
+        state.heartbeat_grace = Duration::from_millis(400);
+        let data = web::Data::new(state);
+        let connected_at = Instant::now();
+        let mut client = connected_client(&data).await;
+
+        // Sync and status, then nothing until the first ping at the end of the grace window
+        client.text().await;
+        client.text().await;
+        let (opcode, _) = client.frame().await;
+        assert_eq!(opcode, 0x9);
+        assert!(connected_at.elapsed() >= Duration::from_millis(400), "pinged after {:?}", connected_at.elapsed());
+    }
+}
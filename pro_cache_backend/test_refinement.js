@@ -56,7 +56,7 @@ async function run() {
     // 3. Register Token 2 (Should invalidate Token 1 in storage)
     await registerToken(TOKEN_2);
 
-    // 4. Try Connect with Token 1 (Should Fail because it was replaced)
+    // 4. Try Connect with Token 1 (Should Fail because it was replaced; assumes TOKEN_GRACE_SECS is unset or 0)
     console.log("[Client] Connecting with TOKEN_1 (Expect Failure)...");
     try {
         await new Promise((resolve, reject) => {